@@ -8,7 +8,9 @@ use bevy::{input::common_conditions::input_just_pressed, prelude::*};
 use binds::{Binding, InputBindingSystem};
 use view::{Gimbal, GimbalPos};
 
-use crate::{editor::update_editor_context, game::GameRules, id::IdGen};
+use crate::{
+    core::map::properties::MapProperties, editor::update_editor_context, game::GameRules, id::IdGen,
+};
 
 #[derive(States, Default, Debug, Clone, PartialEq, Eq, Hash)]
 pub enum AppState {
@@ -64,18 +66,20 @@ fn init(mut commands: Commands) {
 
 fn playtest(
     app_state: Res<State<AppState>>,
+    properties: Res<MapProperties>,
     q_existing_cam: Query<(&GlobalTransform, &Gimbal), With<Camera>>,
     mut next_app_state: ResMut<NextState<AppState>>,
     mut commands: Commands,
 ) {
+    // Playtesting starts from the editor camera rather than the map's spawn.
+    let mut rules = GameRules::from_properties(&properties);
     if let Ok((transform, gimbal)) = q_existing_cam.single() {
-        commands.insert_resource(GameRules {
-            spawn: GimbalPos {
-                pos: transform.translation(),
-                rot: *gimbal,
-            },
-        });
+        rules.spawn = GimbalPos {
+            pos: transform.translation(),
+            rot: *gimbal,
+        };
     }
+    commands.insert_resource(rules);
 
     next_app_state.set(if app_state.get() == &AppState::InEditor {
         AppState::InGame
@@ -107,8 +111,6 @@ fn toggle_studio_light(
 pub fn plugin(app: &mut App) {
     app.add_plugins((media::plugin, binds::plugin, view::plugin, map::plugin))
         .init_resource::<IdGen>()
-        .insert_resource(ClearColor(Color::BLACK))
-        .insert_resource(AmbientLight::NONE)
         .init_state::<AppState>()
         .enable_state_scoped_entities::<AppState>()
         .add_systems(Startup, init)
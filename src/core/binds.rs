@@ -15,6 +15,11 @@ pub enum Binding {
     Playtest,
     Undo,
     Redo,
+    CycleRedoBranch,
+    NewMap,
+    SaveAs,
+    CloseMap,
+    CheckMap,
 
     // Movement
     MoveLeft,
@@ -48,6 +53,21 @@ pub enum Binding {
     HoldSnap,
     SelNext,
     SelPrev,
+
+    // Generators
+    Generate,
+    CycleGenerator,
+
+    // Surfaces
+    FitSurface,
+    ToggleUvAlign,
+
+    // Brushes
+    CycleBrushShape,
+    RotateBrush,
+    RotateBrushBack,
+    EditVertices,
+    Carve,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
@@ -196,6 +216,26 @@ impl Default for InputBindingMap {
             Binding::Redo,
             BoundInput::key(KeyCode::KeyZ).with_control().with_shift(),
         );
+        map.insert(
+            Binding::CycleRedoBranch,
+            BoundInput::key(KeyCode::KeyB).with_control(),
+        );
+        map.insert(
+            Binding::NewMap,
+            BoundInput::key(KeyCode::KeyN).with_control(),
+        );
+        map.insert(
+            Binding::SaveAs,
+            BoundInput::key(KeyCode::KeyS).with_control().with_shift(),
+        );
+        map.insert(
+            Binding::CloseMap,
+            BoundInput::key(KeyCode::KeyW).with_control(),
+        );
+        map.insert(
+            Binding::CheckMap,
+            BoundInput::key(KeyCode::KeyF).with_control().with_shift(),
+        );
         map.insert(Binding::MoveLeft, BoundInput::key(KeyCode::KeyA));
         map.insert(Binding::MoveRight, BoundInput::key(KeyCode::KeyD));
         map.insert(Binding::MoveBackwards, BoundInput::key(KeyCode::KeyS));
@@ -235,6 +275,24 @@ impl Default for InputBindingMap {
         map.insert(Binding::HoldSnap, BoundInput::key(KeyCode::AltLeft));
         map.insert(Binding::SelNext, BoundInput::scroll_down().with_shift());
         map.insert(Binding::SelPrev, BoundInput::scroll_up().with_shift());
+        map.insert(Binding::Generate, BoundInput::key(KeyCode::KeyG));
+        map.insert(
+            Binding::CycleGenerator,
+            BoundInput::key(KeyCode::KeyG).with_shift(),
+        );
+        map.insert(Binding::FitSurface, BoundInput::key(KeyCode::KeyU));
+        map.insert(
+            Binding::ToggleUvAlign,
+            BoundInput::key(KeyCode::KeyU).with_shift(),
+        );
+        map.insert(Binding::CycleBrushShape, BoundInput::key(KeyCode::KeyB));
+        map.insert(Binding::RotateBrush, BoundInput::key(KeyCode::KeyR));
+        map.insert(
+            Binding::RotateBrushBack,
+            BoundInput::key(KeyCode::KeyR).with_shift(),
+        );
+        map.insert(Binding::EditVertices, BoundInput::key(KeyCode::KeyH));
+        map.insert(Binding::Carve, BoundInput::key(KeyCode::KeyK));
 
         InputBindingMap(map)
     }
@@ -1,7 +1,7 @@
-use std::{marker::PhantomData, sync::Arc};
+use std::{marker::PhantomData, path::Path, sync::Arc};
 
 use bevy::prelude::*;
-use redb::{Database, TableDefinition, TypeName};
+use redb::{Database, ReadableTable, TableDefinition, TypeName};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use thiserror::Error;
 use ulid::Ulid;
@@ -14,9 +14,35 @@ pub struct Db {
 }
 
 impl Db {
-    pub fn new(path: &str) -> Db {
-        Db {
-            backing: Arc::new(Database::builder().create(path).unwrap()),
+    pub fn new(path: impl AsRef<Path>) -> Result<Db, DbError> {
+        Ok(Db {
+            backing: Arc::new(Database::builder().create(path)?),
+        })
+    }
+
+    /// Opens an existing database without creating it.
+    pub fn open(path: impl AsRef<Path>) -> Result<Db, DbError> {
+        Ok(Db {
+            backing: Arc::new(Database::open(path)?),
+        })
+    }
+
+    /// A database that only lives in memory, for tests.
+    #[cfg(test)]
+    pub fn in_memory() -> Result<Db, DbError> {
+        Ok(Db {
+            backing: Arc::new(
+                Database::builder().create_with_backend(redb::backends::InMemoryBackend::new())?,
+            ),
+        })
+    }
+
+    /// Reclaims space left behind by deleted rows.
+    /// Returns false if the database is shared elsewhere and can't be compacted.
+    pub fn compact(&mut self) -> Result<bool> {
+        match Arc::get_mut(&mut self.backing) {
+            Some(database) => Ok(database.compact()?),
+            None => Ok(false),
         }
     }
 }
@@ -26,11 +52,14 @@ pub const TBL_OBJECTS: TableDefinition<Checksum, Object> = TableDefinition::new(
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Meta {
-    pub name: String,
     pub hist_node_id: Id,
     pub editor_context: EditorContext,
 }
 
+impl Versioned for Meta {
+    const VERSION: u16 = 1;
+}
+
 impl redb::Value for Id {
     type SelfType<'a>
         = Id
@@ -80,6 +109,12 @@ impl Checksum {
     }
 }
 
+impl std::fmt::Display for Checksum {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0.to_hex())
+    }
+}
+
 impl redb::Value for Checksum {
     type SelfType<'a>
         = Checksum
@@ -130,20 +165,16 @@ pub struct Object {
 
 impl Object {
     /// New object from a serializable type.
-    pub fn new_typed<T: Serialize>(input: &T) -> (Checksum, Object) {
-        let bytes = Self::to_bytes(input);
+    pub fn new_typed<T: Versioned>(input: &T) -> (Checksum, Object) {
+        let bytes = encode_versioned(input);
         (Self::checksum(&bytes), Object { data: bytes })
     }
 
-    pub fn checksum_typed<T: Serialize>(input: &T) -> Checksum {
-        let bytes = Self::to_bytes(input);
+    pub fn checksum_typed<T: Versioned>(input: &T) -> Checksum {
+        let bytes = encode_versioned(input);
         Checksum(blake3::hash(&bytes))
     }
 
-    fn to_bytes<T: Serialize>(input: &T) -> Vec<u8> {
-        postcard::to_stdvec(input).unwrap()
-    }
-
     pub fn checksum(bytes: &[u8]) -> Checksum {
         Checksum(blake3::hash(bytes))
     }
@@ -155,11 +186,8 @@ impl Object {
     }
 
     /// Deserialize an object created from a type.
-    pub fn cast<T>(&self) -> T
-    where
-        T: DeserializeOwned,
-    {
-        postcard::from_bytes(&self.data).unwrap()
+    pub fn cast<T: Versioned>(&self) -> Result<T, DbError> {
+        try_decode_versioned(&self.data)
     }
 }
 
@@ -199,6 +227,108 @@ impl redb::Value for Object {
     }
 }
 
+/// A type that is stored in the database. Stored values are wrapped in an envelope holding the
+/// version of their layout, so maps written by older editors can be migrated when opened.
+pub trait Versioned: Serialize + DeserializeOwned {
+    /// Bump this whenever the serialized layout changes, and register a migration from the
+    /// previous version in `map::migrations`.
+    const VERSION: u16;
+}
+
+/// Prefix of versioned values, followed by the version as little endian u16 and the postcard
+/// payload. Values written before versioning have no envelope and count as version 0. This
+/// relies on no legacy payload starting with these bytes, which postcard makes very unlikely.
+const ENVELOPE_MAGIC: [u8; 4] = [0xFF, b'M', b'L', b'T'];
+
+/// Splits a stored value into its version and payload.
+pub fn split_envelope(data: &[u8]) -> (u16, &[u8]) {
+    match data.strip_prefix(&ENVELOPE_MAGIC) {
+        Some([lo, hi, payload @ ..]) => (u16::from_le_bytes([*lo, *hi]), payload),
+        _ => (0, data),
+    }
+}
+
+/// Returns true if the value was written with a version envelope.
+pub fn has_envelope(data: &[u8]) -> bool {
+    data.starts_with(&ENVELOPE_MAGIC)
+}
+
+pub fn wrap_envelope(version: u16, payload: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(ENVELOPE_MAGIC.len() + 2 + payload.len());
+    data.extend_from_slice(&ENVELOPE_MAGIC);
+    data.extend_from_slice(&version.to_le_bytes());
+    data.extend_from_slice(payload);
+    data
+}
+
+fn encode_versioned<T: Versioned>(value: &T) -> Vec<u8> {
+    wrap_envelope(T::VERSION, &postcard::to_stdvec(value).unwrap())
+}
+
+pub fn try_decode_versioned<T: Versioned>(data: &[u8]) -> Result<T, DbError> {
+    let type_name = std::any::type_name::<T>();
+    let (version, payload) = split_envelope(data);
+    if version != T::VERSION {
+        return Err(DbError::UnexpectedVersion {
+            type_name,
+            version,
+            expected: T::VERSION,
+        });
+    }
+    postcard::from_bytes(payload).map_err(|source| DbError::Decode { type_name, source })
+}
+
+fn decode_versioned<T: Versioned>(data: &[u8]) -> T {
+    // Typed records are decoded once when a map is opened, by `migrate_schema` in the editor and
+    // by `verify_records` in the command line tools. Failing here means the file was changed
+    // while it was open.
+    try_decode_versioned(data).unwrap_or_else(|err| panic!("{}", err))
+}
+
+/// Errors caused by the contents of a map file.
+#[derive(Error, Debug)]
+pub enum DbError {
+    #[error("Failed to open database: {0}")]
+    Open(#[from] redb::DatabaseError),
+    #[error("Storage error: {0}")]
+    Storage(#[from] redb::StorageError),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Object {0} is missing")]
+    MissingObject(Checksum),
+    #[error("Element {elem_id} has unknown role {role:x}")]
+    UnknownRole { elem_id: Id, role: u64 },
+    #[error("Failed to decode {type_name}: {source}")]
+    Decode {
+        type_name: &'static str,
+        source: postcard::Error,
+    },
+    #[error("{type_name} is stored as version {version}, expected version {expected}")]
+    UnexpectedVersion {
+        type_name: &'static str,
+        version: u16,
+        expected: u16,
+    },
+}
+
+#[derive(Error, Debug)]
+pub enum SchemaError {
+    #[error(
+        "{type_name} is stored as version {version}, but this editor only supports up to version \
+         {supported}. Update the editor to open this map"
+    )]
+    FutureVersion {
+        type_name: &'static str,
+        version: u16,
+        supported: u16,
+    },
+    #[error("No migration registered for {type_name} from version {version}")]
+    MissingMigration {
+        type_name: &'static str,
+        version: u16,
+    },
+}
+
 #[derive(Debug)]
 pub struct Typed<T> {
     marker: PhantomData<T>,
@@ -206,7 +336,7 @@ pub struct Typed<T> {
 
 impl<T> redb::Value for Typed<T>
 where
-    T: std::fmt::Debug + Serialize + DeserializeOwned,
+    T: std::fmt::Debug + Versioned,
 {
     type SelfType<'a>
         = T
@@ -226,14 +356,14 @@ where
     where
         Self: 'a,
     {
-        postcard::from_bytes(data).unwrap()
+        decode_versioned(data)
     }
 
     fn as_bytes<'a, 'b: 'a>(value: &'a Self::SelfType<'b>) -> Self::AsBytes<'a>
     where
         Self: 'b,
     {
-        postcard::to_stdvec(value).unwrap()
+        encode_versioned(value)
     }
 
     fn type_name() -> TypeName {
@@ -241,6 +371,66 @@ where
     }
 }
 
+/// The stored bytes of a `Typed<T>` table, for migrating values without decoding them.
+#[derive(Debug)]
+pub struct RawTyped<T> {
+    marker: PhantomData<T>,
+}
+
+impl<T> redb::Value for RawTyped<T>
+where
+    T: std::fmt::Debug + 'static,
+{
+    type SelfType<'a>
+        = Vec<u8>
+    where
+        Self: 'a;
+
+    type AsBytes<'a>
+        = &'a [u8]
+    where
+        Self: 'a;
+
+    fn fixed_width() -> Option<usize> {
+        None
+    }
+
+    fn from_bytes<'a>(data: &'a [u8]) -> Self::SelfType<'a>
+    where
+        Self: 'a,
+    {
+        data.to_vec()
+    }
+
+    fn as_bytes<'a, 'b: 'a>(value: &'a Self::SelfType<'b>) -> Self::AsBytes<'a>
+    where
+        Self: 'b,
+    {
+        value
+    }
+
+    fn type_name() -> TypeName {
+        // Has to match `Typed<T>`, or redb refuses to open the table.
+        TypeName::new(std::any::type_name::<T>())
+    }
+}
+
+/// Decodes every record of a typed table opened with `RawTyped<T>`. Unlike reading through
+/// `Typed<T>`, damaged records come back as errors instead of panicking.
+pub fn decode_records<T>(
+    table: &impl ReadableTable<Id, RawTyped<T>>,
+) -> Result<Vec<(Id, Result<T, DbError>)>, DbError>
+where
+    T: std::fmt::Debug + Versioned + 'static,
+{
+    let mut records = Vec::new();
+    for entry in table.iter()? {
+        let (id, data) = entry?;
+        records.push((id.value(), try_decode_versioned(&data.value())));
+    }
+    Ok(records)
+}
+
 #[derive(Error, Debug)]
 #[error("Invalid database key - not found")]
 pub struct NotFound;
@@ -1,6 +1,15 @@
 pub mod changes;
+pub mod document;
+pub mod edits;
 pub mod elements;
+pub mod fsck;
+pub mod gc;
+pub mod generators;
 pub mod history;
+pub mod migrations;
+pub mod properties;
+pub mod repair;
+pub mod session;
 pub mod states;
 
 use bevy::{log::tracing::Instrument, platform::collections::HashMap, prelude::*};
@@ -9,17 +18,21 @@ use redb::ReadTransaction;
 use thiserror::Error;
 
 use crate::{
-    app_data::{self, AppDataPath},
+    app_data,
     core::{
         db::{Db, EnsureExists, Meta, TBL_META, TBL_OBJECTS},
         map::{
             changes::{ChangeSet, PendingChanges},
             elements::{
-                brush::Brush, light::Light, AppRoleRegistry, ElementId, ElementRoleRegistry,
+                brush::{refresh_brush_surfaces, Brush},
+                light::Light,
+                AppRoleRegistry, ElementId, ElementRoleRegistry,
             },
             history::{HistNode, UpdateCurrentHistNode, TBL_HIST_NODES},
-            states::{MapState, RestoreState, StateSnapshot, TBL_STATES},
+            properties::MapProperties,
+            states::{read_state, write_state, MapState, RestoreState, StateSnapshot, TBL_STATES},
         },
+        media::{surface::Surface, MediaCollection},
     },
     id::{Id, IdGen},
     util::brush_texture_settings,
@@ -54,68 +67,58 @@ pub fn get_current_hist_node(reader: &ReadTransaction) -> Result<HistNode> {
 
 pub fn get_current_state(reader: &ReadTransaction) -> Result<MapState> {
     let hist = get_current_hist_node(reader)?;
-    Ok(reader
-        .open_table(TBL_STATES)?
-        .get(&hist.state_id)?
-        .ensure_exists()?
-        .value())
+    read_state(&reader.open_table(TBL_STATES)?, hist.state_id)
+}
+
+/// Writes the initial meta, state and history node into a fresh map database.
+pub fn init_db(db: &Db, name: String, id_gen: &mut IdGen) -> Result {
+    let writer = db.begin_write()?;
+    {
+        let initial_state_id = id_gen.generate();
+        write_state(
+            &mut writer.open_table(states::TBL_STATES)?,
+            initial_state_id,
+            None,
+            MapState {
+                properties: MapProperties::with_name(name),
+                ..default()
+            },
+        )?;
+
+        // Initial history node
+        let initial_hist_id = id_gen.generate();
+        writer.open_table(history::TBL_HIST_NODES)?.insert(
+            initial_hist_id,
+            HistNode {
+                timestamp: history::new_timestamp(),
+                parent_id: None,
+                child_ids: Vec::default(),
+                state_id: initial_state_id,
+                label: "Create map".to_string(),
+                preferred_child: None,
+            },
+        )?;
+
+        // Init the object table so it exists even if no objects are written.
+        writer.open_table(TBL_OBJECTS)?;
+
+        writer.open_table(TBL_META)?.insert(
+            (),
+            Meta {
+                hist_node_id: initial_hist_id,
+                editor_context: default(),
+            },
+        )?;
+    }
+    writer.commit()?;
+    Ok(())
 }
 
-fn init_map(
+fn init_map_assets(
     asset_server: Res<AssetServer>,
-    app_data_path: Res<AppDataPath>,
     mut commands: Commands,
-    mut id_gen: ResMut<IdGen>,
     mut materials: ResMut<Assets<StandardMaterial>>,
-) -> Result {
-    let db_path = format!("{}/map.mmap", app_data_path.get());
-    let db = Db::new(&db_path);
-
-    let mut restore: Option<Id> = None;
-    let reader = db.begin_read()?;
-    if db_is_initialized(&reader) {
-        // Load the map
-        let hist_node = get_current_hist_node(&reader)?;
-        restore = Some(hist_node.state_id);
-
-        // dumbly insert the editor context too late
-        let meta = get_current_meta(&reader)?;
-        commands.insert_resource(meta.editor_context);
-    } else {
-        // Write initial stuff
-        let writer = db.begin_write()?;
-        {
-            let mut tbl_states = writer.open_table(states::TBL_STATES)?;
-            let initial_state_id = id_gen.generate();
-            tbl_states.insert(initial_state_id, states::MapState::default())?;
-
-            // Initial history node
-            let initial_hist_id = id_gen.generate();
-            writer.open_table(history::TBL_HIST_NODES)?.insert(
-                initial_hist_id,
-                HistNode {
-                    timestamp: history::new_timestamp(),
-                    parent_id: None,
-                    child_ids: Vec::default(),
-                    state_id: initial_state_id,
-                },
-            )?;
-
-            // Init the object table so it exists even if no objects are written.
-            writer.open_table(TBL_OBJECTS)?;
-
-            writer.open_table(TBL_META)?.insert(
-                (),
-                Meta {
-                    name: "test map".to_string(),
-                    hist_node_id: initial_hist_id,
-                    editor_context: default(),
-                },
-            )?;
-        }
-        writer.commit()?;
-    }
-
+) {
     let texture = asset_server
         .load_with_settings("base_content/surfaces/concrete.png", brush_texture_settings);
 
@@ -127,20 +130,9 @@ fn init_map(
     });
 
     commands.insert_resource(MapAssets {
+        base_material: material.clone(),
         default_material: material,
     });
-
-    // Command ordering is important here, db has to exist when state is restored.
-    commands.insert_resource(db);
-
-    if let Some(id) = restore {
-        commands.trigger(RestoreState {
-            id,
-            fresh_map: true,
-        });
-    }
-
-    Ok(())
 }
 
 #[derive(Resource, Default)]
@@ -165,6 +157,10 @@ impl ElementLookup {
     pub fn iter(&self) -> impl Iterator<Item = (&Id, &Entity)> {
         self.0.iter()
     }
+
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
 }
 
 fn track_element_ids(
@@ -182,18 +178,44 @@ fn track_element_ids(
 }
 
 pub fn plugin(app: &mut App) {
-    app.add_plugins((states::plugin, changes::plugin, history::plugin));
+    app.add_plugins((
+        states::plugin,
+        changes::plugin,
+        document::plugin,
+        fsck::plugin,
+        gc::plugin,
+        generators::plugin,
+        history::plugin,
+        properties::plugin,
+        session::plugin,
+    ));
     app.init_resource::<IdGen>();
     app.init_resource::<ElementLookup>();
     app.init_resource::<PendingChanges>();
     app.init_resource::<ElementRoleRegistry>();
+    // Keep in sync with `ElementRoleRegistry::with_builtin_roles`.
     app.register_map_element_role::<Brush>();
     app.register_map_element_role::<Light>();
-    app.add_systems(Startup, init_map);
-    app.add_systems(Update, track_element_ids);
+    // Assets have to exist before the startup map is restored.
+    app.add_systems(
+        Startup,
+        (init_map_assets, session::open_startup_map).chain(),
+    );
+    app.add_systems(
+        Update,
+        (
+            track_element_ids,
+            refresh_brush_surfaces.run_if(
+                resource_exists::<MapAssets>.and(resource_changed::<MediaCollection<Surface>>),
+            ),
+        ),
+    );
 }
 
 #[derive(Resource)]
 pub struct MapAssets {
+    /// Built-in material, used when the map has no default surface.
+    pub base_material: Handle<StandardMaterial>,
+    /// Material of the default surface of the open map.
     pub default_material: Handle<StandardMaterial>,
 }
@@ -1,20 +1,63 @@
-use bevy::prelude::*;
+use bevy::{platform::collections::HashSet, prelude::*};
+use itertools::Itertools;
+use redb::WriteTransaction;
+use thiserror::Error;
 
 use crate::{
     core::{
-        db::{Db, TBL_META},
+        db::{Db, Meta, NotFound, TBL_META, TBL_OBJECTS},
         map::{
             elements::{ElementId, Info, Role},
             history::{new_timestamp, HistNode, UpdateCurrentHistNode, TBL_HIST_NODES},
-            states::{MapState, StateSnapshot, TBL_STATES},
+            session::{MapSession, OpenMap},
+            states::{
+                checkout_elements_in_world, checkout_properties_in_world, read_state, write_state,
+                FullSnapshot, MapState, MissingRoleError, SnapshotObjects, StateSnapshot,
+                SyncedElements, TBL_STATES,
+            },
             ElementLookup,
         },
     },
     id::{Id, IdGen},
 };
 
+#[derive(Error, Debug)]
+pub enum ChangeError {
+    #[error("Element {0} doesn't exist")]
+    MissingElement(Id),
+    #[error("Element {0} already exists")]
+    ElementExists(Id),
+    #[error("Invalid {role}: {reason}")]
+    InvalidParams { role: &'static str, reason: String },
+}
+
 pub trait Change: std::fmt::Debug + Send + Sync {
-    fn apply_to_world(&self, world: &mut World);
+    /// Checks whether the change can be applied to the world as it is.
+    /// While a set is validated, `require_elem` also sees the elements created or removed by the
+    /// earlier changes of the set.
+    fn validate(&self, world: &World) -> Result<(), ChangeError>;
+
+    /// Records the elements this change creates or removes, so the following changes of a set
+    /// can be validated before anything is applied.
+    fn plan(&self, _planned: &mut PlannedElements) {}
+
+    /// Expects the change to be validated, errors here are unexpected.
+    fn apply_to_world(&self, world: &mut World) -> Result;
+
+    /// Short human-readable description, e.g. "Resize brush".
+    /// Called before the change is applied, so the world still holds the old element.
+    fn describe(&self, world: &World) -> String;
+
+    /// Verb used when summarizing several changes, e.g. "Remove" in "Remove 3 elements".
+    fn verb(&self) -> &'static str;
+
+    /// The element this change touches, if it's known before applying it.
+    fn elem_id(&self) -> Option<Id>;
+
+    /// Changes of the same kind to the same element replace each other when coalescing.
+    fn kind(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
 }
 
 #[derive(Debug)]
@@ -22,19 +65,103 @@ pub struct ChangeSet {
     pub changes: Vec<Box<dyn Change>>,
 }
 
+impl ChangeSet {
+    /// Describes the whole set. Single changes describe themselves, larger sets are summarized.
+    pub fn describe(&self, world: &World) -> String {
+        match self.changes.as_slice() {
+            [] => "No changes".to_string(),
+            [change] => change.describe(world),
+            changes => {
+                let mut verb_counts: Vec<(&str, usize)> = Vec::new();
+                for change in changes {
+                    match verb_counts
+                        .iter_mut()
+                        .find(|(verb, _)| *verb == change.verb())
+                    {
+                        Some((_, count)) => *count += 1,
+                        None => verb_counts.push((change.verb(), 1)),
+                    }
+                }
+                verb_counts
+                    .into_iter()
+                    .map(|(verb, count)| {
+                        format!(
+                            "{} {} element{}",
+                            verb,
+                            count,
+                            if count == 1 { "" } else { "s" }
+                        )
+                    })
+                    .join(", ")
+            }
+        }
+    }
+
+    /// Keeps only the last change of each kind per element, e.g. the final step of a drag.
+    pub fn coalesced(self) -> ChangeSet {
+        let mut changes: Vec<Box<dyn Change>> = Vec::new();
+        for change in self.changes {
+            if let Some(elem_id) = change.elem_id() {
+                changes.retain(|existing| {
+                    existing.elem_id() != Some(elem_id) || existing.kind() != change.kind()
+                });
+            }
+            changes.push(change);
+        }
+        ChangeSet { changes }
+    }
+}
+
+/// Untracked changes applied while previewing an edit, see `BeginPreview`.
+struct Preview {
+    changes: Vec<Box<dyn Change>>,
+    elements_before: HashSet<Id>,
+}
+
+fn element_ids(lookup: &ElementLookup) -> HashSet<Id> {
+    lookup.iter().map(|(id, _)| *id).collect()
+}
+
+/// Every element that may differ from the current state after applying some changes.
+/// Elements created with generated ids are only found by comparing the elements before and after.
+fn touched_elements(
+    elem_ids: impl IntoIterator<Item = Id>,
+    elements_before: &HashSet<Id>,
+    lookup: &ElementLookup,
+) -> HashSet<Id> {
+    elem_ids
+        .into_iter()
+        .chain(
+            element_ids(lookup)
+                .symmetric_difference(elements_before)
+                .copied(),
+        )
+        .collect()
+}
+
 #[derive(Resource, Default, Deref, DerefMut)]
-pub struct PendingChanges(Vec<ChangeSet>);
+pub struct PendingChanges {
+    #[deref]
+    sets: Vec<ChangeSet>,
+    preview: Option<Preview>,
+}
 
 impl PendingChanges {
     pub fn push_set(&mut self, set: ChangeSet) {
-        self.0.push(set);
+        self.sets.push(set);
+    }
+
+    /// Drops pending change sets and any preview in progress, without touching the world.
+    pub fn clear(&mut self) {
+        self.sets.clear();
+        self.preview = None;
     }
 
     pub fn push_single<C>(&mut self, change: C)
     where
         C: Change + 'static,
     {
-        self.0.push(ChangeSet {
+        self.sets.push(ChangeSet {
             changes: vec![Box::new(change)],
         });
     }
@@ -47,7 +174,7 @@ impl PendingChanges {
         for c in changes {
             boxed_changes.push(Box::new(c));
         }
-        self.0.push(ChangeSet {
+        self.sets.push(ChangeSet {
             changes: boxed_changes,
         });
     }
@@ -55,7 +182,58 @@ impl PendingChanges {
 
 pub fn get_elem_entity<'a>(world: &'a mut World, elem_id: &Id) -> Option<EntityWorldMut<'a>> {
     let entity_id = world.resource_mut::<ElementLookup>().find(elem_id).ok()?;
-    Some(world.entity_mut(entity_id))
+    world.get_entity_mut(entity_id).ok()
+}
+
+/// Elements created or removed by the changes of a set validated so far, see `validate_change_set`.
+#[derive(Resource, Default, Debug)]
+pub struct PlannedElements {
+    created: HashSet<Id>,
+    removed: HashSet<Id>,
+}
+
+impl PlannedElements {
+    pub fn create(&mut self, elem_id: Id) {
+        self.removed.remove(&elem_id);
+        self.created.insert(elem_id);
+    }
+
+    pub fn remove(&mut self, elem_id: Id) {
+        self.created.remove(&elem_id);
+        self.removed.insert(elem_id);
+    }
+}
+
+/// Fails if the element has no live entity.
+/// The lookup is only cleaned up once per frame, so the entity itself is checked too.
+pub fn require_elem(world: &World, elem_id: &Id) -> Result<(), ChangeError> {
+    if let Some(planned) = world.get_resource::<PlannedElements>() {
+        if planned.removed.contains(elem_id) {
+            return Err(ChangeError::MissingElement(*elem_id));
+        }
+        if planned.created.contains(elem_id) {
+            return Ok(());
+        }
+    }
+    world
+        .resource::<ElementLookup>()
+        .find(elem_id)
+        .ok()
+        .filter(|entity| world.get_entity(*entity).is_ok())
+        .map(|_| ())
+        .ok_or(ChangeError::MissingElement(*elem_id))
+}
+
+/// Reads a component from an element entity, e.g. to compare old and new params.
+pub fn get_elem_component<'a, T: Component>(world: &'a World, elem_id: &Id) -> Option<&'a T> {
+    let entity_id = world.resource::<ElementLookup>().find(elem_id).ok()?;
+    world.get::<T>(entity_id)
+}
+
+fn elem_name(world: &World, elem_id: &Id) -> String {
+    get_elem_component::<Info>(world, elem_id)
+        .map(|info| info.name.clone())
+        .unwrap_or_else(|| elem_id.to_string())
 }
 
 #[derive(Debug)]
@@ -85,7 +263,21 @@ where
     R: Role,
     UpdateElemParams<R>: Change,
 {
-    fn apply_to_world(&self, world: &mut World) {
+    fn validate(&self, world: &World) -> Result<(), ChangeError> {
+        if let Some(id) = self.id_mode.loaded_id_or_none() {
+            if require_elem(world, &id).is_ok() {
+                return Err(ChangeError::ElementExists(id));
+            }
+        }
+        self.params
+            .validate()
+            .map_err(|reason| ChangeError::InvalidParams {
+                role: R::id(),
+                reason,
+            })
+    }
+
+    fn apply_to_world(&self, world: &mut World) -> Result {
         let id = self
             .id_mode
             .loaded_id_or_none()
@@ -98,12 +290,30 @@ where
             elem_id: id,
             new_info: self.info.clone(),
         }
-        .apply_to_world(world);
+        .apply_to_world(world)?;
         UpdateElemParams {
             elem_id: id,
             params: self.params.clone(),
         }
-        .apply_to_world(world);
+        .apply_to_world(world)
+    }
+
+    fn describe(&self, _world: &World) -> String {
+        format!("Create {} '{}'", R::id(), self.info.name)
+    }
+
+    fn plan(&self, planned: &mut PlannedElements) {
+        if let Some(id) = self.id_mode.loaded_id_or_none() {
+            planned.create(id);
+        }
+    }
+
+    fn verb(&self) -> &'static str {
+        "Create"
+    }
+
+    fn elem_id(&self) -> Option<Id> {
+        self.id_mode.loaded_id_or_none()
     }
 }
 
@@ -114,9 +324,32 @@ pub struct UpdateElemInfo {
 }
 
 impl Change for UpdateElemInfo {
-    fn apply_to_world(&self, world: &mut World) {
-        let mut entity = get_elem_entity(world, &self.elem_id).unwrap();
+    fn validate(&self, world: &World) -> Result<(), ChangeError> {
+        require_elem(world, &self.elem_id)
+    }
+
+    fn apply_to_world(&self, world: &mut World) -> Result {
+        let mut entity = get_elem_entity(world, &self.elem_id)
+            .ok_or(ChangeError::MissingElement(self.elem_id))?;
         entity.insert(self.new_info.clone());
+        Ok(())
+    }
+
+    fn describe(&self, world: &World) -> String {
+        match get_elem_component::<Info>(world, &self.elem_id) {
+            Some(old_info) if old_info.name != self.new_info.name => {
+                format!("Rename '{}' to '{}'", old_info.name, self.new_info.name)
+            }
+            _ => format!("Edit info of '{}'", self.new_info.name),
+        }
+    }
+
+    fn verb(&self) -> &'static str {
+        "Edit"
+    }
+
+    fn elem_id(&self) -> Option<Id> {
+        Some(self.elem_id)
     }
 }
 
@@ -132,8 +365,31 @@ pub struct RemoveElement {
 }
 
 impl Change for RemoveElement {
-    fn apply_to_world(&self, world: &mut World) {
-        get_elem_entity(world, &self.elem_id).unwrap().despawn();
+    fn validate(&self, world: &World) -> Result<(), ChangeError> {
+        require_elem(world, &self.elem_id)
+    }
+
+    fn apply_to_world(&self, world: &mut World) -> Result {
+        get_elem_entity(world, &self.elem_id)
+            .ok_or(ChangeError::MissingElement(self.elem_id))?
+            .despawn();
+        Ok(())
+    }
+
+    fn describe(&self, world: &World) -> String {
+        format!("Remove '{}'", elem_name(world, &self.elem_id))
+    }
+
+    fn plan(&self, planned: &mut PlannedElements) {
+        planned.remove(self.elem_id);
+    }
+
+    fn verb(&self) -> &'static str {
+        "Remove"
+    }
+
+    fn elem_id(&self) -> Option<Id> {
+        Some(self.elem_id)
     }
 }
 
@@ -145,46 +401,138 @@ pub fn apply_pending_changes(mut pending_changes: ResMut<PendingChanges>, mut co
     }
 }
 
+/// Triggered when a change set is rejected. The world is put back the way it was before the set.
+#[derive(Event, Debug, Clone)]
+pub struct ChangeRejected {
+    pub label: String,
+    pub reason: String,
+}
+
 fn try_apply_change_set(change_set: In<ChangeSet>, world: &mut World) {
-    if let Err(err) = world.run_system_cached_with(apply_change_set, change_set.0) {
-        error!("Failed to apply change set: {}", err);
+    let change_set = change_set.0;
+    let label = change_set.describe(world);
+
+    // Nothing is touched when the set is invalid as a whole.
+    if let Err(err) = validate_change_set(world, &change_set) {
+        error!("Rejected change '{}': {}", label, err);
+        world.trigger(ChangeRejected {
+            label,
+            reason: err.to_string(),
+        });
+        return;
+    }
+
+    let elements_before = element_ids(world.resource::<ElementLookup>());
+    let elem_ids: Vec<Id> = change_set
+        .changes
+        .iter()
+        .filter_map(|change| change.elem_id())
+        .collect();
+
+    if let Err(err) = apply_change_set(world, change_set, &label) {
+        error!("Failed to apply change '{}': {}", label, err);
         world.remove_resource::<MapState>();
+        world.insert_resource(FullSnapshot);
+
+        // Revert whatever the set got to change before failing.
+        let touched = touched_elements(
+            elem_ids,
+            &elements_before,
+            world.resource::<ElementLookup>(),
+        );
+        if let Err(revert_err) = checkout_elements_in_world(world, touched)
+            .and_then(|_| checkout_properties_in_world(world))
+        {
+            // The world can't be trusted anymore, start over from what's in the db.
+            error!(
+                "Failed to revert change '{}': {}, reloading the map",
+                label, revert_err
+            );
+            if let Some(session) = world.get_resource::<MapSession>() {
+                let path = session.path.clone();
+                world.trigger(OpenMap { path });
+            }
+        }
+        world.trigger(ChangeRejected {
+            label,
+            reason: err.to_string(),
+        });
     }
 }
 
-fn apply_change_set(change_set: In<ChangeSet>, world: &mut World) -> Result {
+/// Validates every change of the set before any of them is applied. Each change is checked
+/// against the world as the earlier changes of the set would leave it, so a set can e.g. create
+/// an element and then edit it.
+fn validate_change_set(world: &mut World, change_set: &ChangeSet) -> Result<(), ChangeError> {
+    world.init_resource::<PlannedElements>();
+    let result = change_set.changes.iter().try_for_each(|change| {
+        change.validate(world)?;
+        change.plan(&mut world.resource_mut::<PlannedElements>());
+        Ok(())
+    });
+    world.remove_resource::<PlannedElements>();
+    result
+}
+
+/// Expects the set to be validated with `validate_change_set`.
+fn apply_change_set(world: &mut World, change_set: ChangeSet, label: &str) -> Result {
     // TODO: Could likely be split into multiple event-triggered systems. Triggers cant return values tho
 
-    let change_set = change_set.0;
     // Step 1: apply to world.
     for change in change_set.changes {
         // Quirk: apply_to_world could in theory take ownership over the "change" and prevent a
         // clone, but it's impossible while the change is a Box<dyn Change>
-        change.apply_to_world(world);
+        change.apply_to_world(world)?;
     }
 
     // Step 2: create a state resource and run the snapshot schedule. other systems will fill out state.
     let reader = world.resource::<Db>().begin_read()?;
-    let meta = reader.open_table(TBL_META)?.get(())?.unwrap().value();
+    let meta = reader
+        .open_table(TBL_META)?
+        .get(())?
+        .ok_or(NotFound)?
+        .value();
     let cur_hist = reader
         .open_table(TBL_HIST_NODES)?
         .get(meta.hist_node_id)?
-        .unwrap()
-        .value();
-    let cur_state = reader
-        .open_table(TBL_STATES)?
-        .get(cur_hist.state_id)?
-        .unwrap()
+        .ok_or(NotFound)?
         .value();
+    let cur_state = read_state(&reader.open_table(TBL_STATES)?, cur_hist.state_id)?;
     drop(reader);
 
-    world.insert_resource(cur_state);
+    world.insert_resource(cur_state.clone());
+    world.insert_resource(SyncedElements::default());
+    world.insert_resource(SnapshotObjects::default());
     world.run_schedule(StateSnapshot);
     world.flush();
 
-    // Step 3: insert new state into db and create a history node.
+    // Every element has to be captured by the sync system of its role, otherwise the state would
+    // be missing it or keep an outdated copy.
+    let synced = world
+        .remove_resource::<SyncedElements>()
+        .unwrap_or_default();
+    let objects = world
+        .remove_resource::<SnapshotObjects>()
+        .unwrap_or_default();
+    let unsynced: Vec<Id> = world
+        .query::<&ElementId>()
+        .iter(world)
+        .map(|id| **id)
+        .filter(|id| !synced.contains(id))
+        .collect();
+    if !unsynced.is_empty() {
+        return Err(MissingRoleError(unsynced).into());
+    }
+
+    // Step 3: insert objects and the new state into db, create a history node and make it the
+    // current one, all in one transaction.
     let writer = world.resource::<Db>().begin_write()?;
-    let new_state_id = world.resource_mut::<IdGen>().generate();
+    {
+        let mut tbl_objects = writer.open_table(TBL_OBJECTS)?;
+        for (checksum, object) in objects.iter() {
+            tbl_objects.insert(checksum, object)?;
+        }
+    }
     let new_state = world.remove_resource::<MapState>().unwrap();
 
     let in_scene = world.query::<&ElementId>().iter(world).len();
@@ -194,60 +542,176 @@ fn apply_change_set(change_set: In<ChangeSet>, world: &mut World) -> Result {
         in_scene
     );
 
-    writer
-        .open_table(TBL_STATES)?
-        .insert(new_state_id, new_state)?;
-    let new_hist_id = world.resource_mut::<IdGen>().generate();
-    {
-        // Update children on the current history node first
-        let mut tbl_hist = writer.open_table(TBL_HIST_NODES)?;
-        let updated_child_ids = cur_hist
-            .child_ids
-            .iter()
-            .copied()
-            .chain(std::iter::once(new_hist_id));
-        tbl_hist.insert(
-            meta.hist_node_id,
-            HistNode {
-                child_ids: updated_child_ids.collect(),
-                ..cur_hist
-            },
-        )?;
-
-        // New hist node as child of current
-        tbl_hist.insert(
-            new_hist_id,
-            HistNode {
-                timestamp: new_timestamp(),
-                parent_id: Some(meta.hist_node_id),
-                child_ids: Vec::new(),
-                state_id: new_state_id,
-            },
-        )?;
-    }
+    let new_hist_id = commit_hist_node(
+        &writer,
+        &mut world.resource_mut::<IdGen>(),
+        meta,
+        cur_hist,
+        &cur_state,
+        new_state,
+        label,
+    )?;
     writer.commit()?;
+    world.remove_resource::<FullSnapshot>();
+    info!("Applied change: {}", label);
     world.trigger(UpdateCurrentHistNode(new_hist_id));
 
-    // TODO: Here might be a good place to clean up the history, preventing file bloat.
+    // NOTE: Unreachable history is cleaned up by the garbage collector when the map is closed.
 
     Ok(())
 }
 
+/// Writes `new_state` as a child of the current history node and makes it the current one.
+/// Objects of the state have to be inserted by the caller, within the same transaction.
+pub fn commit_hist_node(
+    writer: &WriteTransaction,
+    id_gen: &mut IdGen,
+    meta: Meta,
+    cur_hist: HistNode,
+    cur_state: &MapState,
+    new_state: MapState,
+    label: &str,
+) -> Result<Id> {
+    let new_state_id = id_gen.generate();
+    write_state(
+        &mut writer.open_table(TBL_STATES)?,
+        new_state_id,
+        Some((cur_hist.state_id, cur_state)),
+        new_state,
+    )?;
+    let new_hist_id = id_gen.generate();
+    let mut tbl_hist = writer.open_table(TBL_HIST_NODES)?;
+    // Update children on the current history node first
+    let updated_child_ids = cur_hist
+        .child_ids
+        .iter()
+        .copied()
+        .chain(std::iter::once(new_hist_id));
+    tbl_hist.insert(
+        meta.hist_node_id,
+        HistNode {
+            child_ids: updated_child_ids.collect(),
+            preferred_child: Some(new_hist_id),
+            ..cur_hist
+        },
+    )?;
+
+    // New hist node as child of current
+    tbl_hist.insert(
+        new_hist_id,
+        HistNode {
+            timestamp: new_timestamp(),
+            parent_id: Some(meta.hist_node_id),
+            child_ids: Vec::new(),
+            state_id: new_state_id,
+            label: label.to_string(),
+            preferred_child: None,
+        },
+    )?;
+    writer.open_table(TBL_META)?.insert(
+        (),
+        Meta {
+            hist_node_id: new_hist_id,
+            ..meta
+        },
+    )?;
+    Ok(new_hist_id)
+}
+
 #[derive(Event)]
-/// Use to apply a change to the map without invoking a new history node and map state
-pub struct UntrackedChange(Box<dyn Change>);
+/// Use to apply a change to the map without invoking a new history node and map state.
+/// While a preview is in progress, the change becomes part of it.
+pub struct UntrackedChange(Option<Box<dyn Change>>);
 impl UntrackedChange {
     pub fn new(change: impl Change + 'static) -> Self {
-        Self(Box::new(change))
+        Self(Some(Box::new(change)))
+    }
+}
+
+fn apply_untracked_change(mut trigger: Trigger<UntrackedChange>, world: &mut World) -> Result {
+    let change = trigger
+        .event_mut()
+        .0
+        .take()
+        .ok_or("untracked change was already applied")?;
+    if let Err(err) = change.validate(world) {
+        // Previews follow the cursor, some steps are bound to be invalid.
+        debug!("Skipped invalid untracked change: {}", err);
+        return Ok(());
+    }
+    change.apply_to_world(world)?;
+    if let Some(preview) = world.resource_mut::<PendingChanges>().preview.as_mut() {
+        preview.changes.push(change);
+    }
+    Ok(())
+}
+
+/// Starts previewing an edit. Untracked changes applied from now on are collected, until they are
+/// committed with `CommitPreview` or undone with `RollbackPreview`.
+#[derive(Event)]
+pub struct BeginPreview;
+
+/// Turns the changes of the preview into a single history entry.
+#[derive(Event)]
+pub struct CommitPreview;
+
+/// Reverts every element touched by the preview to the current state.
+#[derive(Event)]
+pub struct RollbackPreview;
+
+fn rollback_preview_in_world(world: &mut World, preview: &Preview) -> Result {
+    let touched = touched_elements(
+        preview.changes.iter().filter_map(|change| change.elem_id()),
+        &preview.elements_before,
+        world.resource::<ElementLookup>(),
+    );
+    checkout_elements_in_world(world, touched)?;
+    checkout_properties_in_world(world)
+}
+
+fn begin_preview(_: Trigger<BeginPreview>, world: &mut World) -> Result {
+    if let Some(preview) = world.resource_mut::<PendingChanges>().preview.take() {
+        warn!("Started a preview while another one was in progress, rolling it back");
+        rollback_preview_in_world(world, &preview)?;
     }
+    let elements_before = element_ids(world.resource::<ElementLookup>());
+    world.resource_mut::<PendingChanges>().preview = Some(Preview {
+        changes: Vec::new(),
+        elements_before,
+    });
+    Ok(())
 }
 
-fn apply_untracked_change(trigger: Trigger<UntrackedChange>, world: &mut World) -> Result {
-    trigger.0.apply_to_world(world);
+fn commit_preview(_: Trigger<CommitPreview>, world: &mut World) -> Result {
+    let Some(preview) = world.resource_mut::<PendingChanges>().preview.take() else {
+        warn!("No preview in progress");
+        return Ok(());
+    };
+    // The change set is applied to the reverted world, so it describes the edit as a whole.
+    rollback_preview_in_world(world, &preview)?;
+    if !preview.changes.is_empty() {
+        world.resource_mut::<PendingChanges>().push_set(
+            ChangeSet {
+                changes: preview.changes,
+            }
+            .coalesced(),
+        );
+    }
     Ok(())
 }
 
+fn rollback_preview(_: Trigger<RollbackPreview>, world: &mut World) -> Result {
+    let Some(preview) = world.resource_mut::<PendingChanges>().preview.take() else {
+        warn!("No preview in progress");
+        return Ok(());
+    };
+    rollback_preview_in_world(world, &preview)
+}
+
 pub fn plugin(app: &mut App) {
     app.add_observer(apply_untracked_change);
-    app.add_systems(Last, apply_pending_changes);
+    app.add_observer(begin_preview);
+    app.add_observer(commit_preview);
+    app.add_observer(rollback_preview);
+    app.add_systems(Last, apply_pending_changes.run_if(resource_exists::<Db>));
 }
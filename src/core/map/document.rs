@@ -0,0 +1,187 @@
+use std::path::{Path, PathBuf};
+
+use bevy::prelude::*;
+use itertools::Itertools;
+use redb::{ReadTransaction, ReadableTable};
+use ron::{ser::PrettyConfig, value::RawValue};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    core::{
+        db::{Db, TBL_OBJECTS},
+        map::{
+            changes::{Change, ChangeSet, CreateId, PendingChanges},
+            edits::{EditCommand, EditScript},
+            elements::{ElementRoleRegistry, Info},
+            init_db,
+            properties::{MapProperties, UpdateMapProperties},
+            session::{close_current_map, load_map, map_name_from_path},
+            states::{load_element, read_state, TBL_STATES},
+        },
+    },
+    id::{Id, IdGen},
+};
+
+pub const DOCUMENT_FILE_EXT: &str = "ron";
+
+/// A single map state as a text document, so maps can be diffed and reviewed.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MapDocument {
+    #[serde(default)]
+    pub properties: MapProperties,
+    pub elements: Vec<ElementDocument>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ElementDocument {
+    /// Element id as a ULID string.
+    pub id: String,
+    /// Role id, e.g. "brush".
+    pub role: String,
+    pub info: Info,
+    pub params: Box<RawValue>,
+}
+
+impl MapDocument {
+    pub fn from_ron(text: &str) -> Result<Self> {
+        Ok(ron::from_str(text)?)
+    }
+
+    pub fn to_ron(&self) -> Result<String> {
+        Ok(ron::ser::to_string_pretty(self, PrettyConfig::default())?)
+    }
+
+    /// Edits that fill an empty map with the document, keeping the element ids.
+    pub fn into_edit_script(self) -> EditScript {
+        let mut commands = vec![EditCommand::SetProperties {
+            properties: self.properties,
+        }];
+        commands.extend(self.elements.into_iter().map(|elem| EditCommand::Create {
+            id: Some(elem.id),
+            role: elem.role,
+            info: elem.info,
+            params: elem.params,
+        }));
+        EditScript(commands)
+    }
+}
+
+/// Resolves a stored map state into a document. Elements are sorted by id to keep diffs small.
+pub fn export_state(
+    reader: &ReadTransaction,
+    registry: &ElementRoleRegistry,
+    state_id: Id,
+) -> Result<MapDocument> {
+    let state = read_state(&reader.open_table(TBL_STATES)?, state_id)?;
+    let tbl_objects = reader.open_table(TBL_OBJECTS)?;
+
+    let mut elements = Vec::new();
+    for (id, elem) in state.elements.iter().sorted_by_key(|(id, _)| **id) {
+        let loaded = load_element(&tbl_objects, registry, *id, elem)?;
+        elements.push(ElementDocument {
+            id: id.to_string(),
+            role: loaded.builder.role_id().to_string(),
+            info: loaded.info,
+            params: loaded.builder.params_to_ron(&loaded.params)?,
+        });
+    }
+
+    Ok(MapDocument {
+        properties: state.properties,
+        elements,
+    })
+}
+
+/// Queues the properties and the creation of every element in the document, keeping their ids.
+/// Expects the open map to be empty.
+pub fn queue_import(world: &mut World, document: &MapDocument) -> Result {
+    let registry = world.resource::<ElementRoleRegistry>();
+    let mut changes: Vec<Box<dyn Change>> = vec![Box::new(UpdateMapProperties {
+        properties: document.properties.clone(),
+    })];
+    for elem in document.elements.iter() {
+        let id: Id = elem.id.parse()?;
+        let builder = registry
+            .find_by_role_id(&elem.role)
+            .ok_or_else(|| format!("element {} has unknown role '{}'", id, elem.role))?;
+        changes.push(builder.build_create(
+            CreateId::Loaded(id),
+            elem.info.clone(),
+            builder.params_from_ron(&elem.params)?,
+        )?);
+    }
+
+    world
+        .resource_mut::<PendingChanges>()
+        .push_set(ChangeSet { changes });
+    Ok(())
+}
+
+/// Creates a new map file at `path` from a document, without starting the app. Nothing is left
+/// behind if the document can't be imported.
+pub fn import_document(
+    path: &Path,
+    document: MapDocument,
+    registry: &ElementRoleRegistry,
+    id_gen: &mut IdGen,
+) -> Result {
+    if path.exists() {
+        return Err(format!("Can't import into {:?}, file already exists", path).into());
+    }
+    let db = Db::new(path)?;
+    let result = init_db(&db, map_name_from_path(path), id_gen).and_then(|_| {
+        document
+            .into_edit_script()
+            .apply_to_map(&db, registry, id_gen, "Import document")
+    });
+    if let Err(err) = result {
+        drop(db);
+        std::fs::remove_file(path)?;
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Creates a new map file at `path` and fills it with the elements of a map document.
+#[derive(Event)]
+pub struct ImportMap {
+    pub document: PathBuf,
+    pub path: PathBuf,
+}
+
+pub fn read_document(path: &Path) -> Result<MapDocument> {
+    MapDocument::from_ron(&std::fs::read_to_string(path)?)
+}
+
+fn import_map(trigger: Trigger<ImportMap>, world: &mut World) {
+    let path = &trigger.path;
+    if path.exists() {
+        error!("Can't import into {:?}, file already exists", path);
+        return;
+    }
+    let document = match read_document(&trigger.document) {
+        Ok(document) => document,
+        Err(err) => {
+            error!(
+                "Failed to read map document {:?}: {}",
+                trigger.document, err
+            );
+            return;
+        }
+    };
+
+    close_current_map(world);
+    if let Err(err) = load_map(world, path).and_then(|_| queue_import(world, &document)) {
+        error!("Failed to import map into {:?}: {}", path, err);
+    } else {
+        info!(
+            "Imported {} elements into {:?}",
+            document.elements.len(),
+            path
+        );
+    }
+}
+
+pub fn plugin(app: &mut App) {
+    app.add_observer(import_map);
+}
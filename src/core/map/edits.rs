@@ -0,0 +1,263 @@
+use std::{io::Read, path::Path};
+
+use bevy::prelude::*;
+use redb::ReadableTable;
+use ron::value::RawValue;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    core::{
+        db::{Checksum, Db, NotFound, Object, TBL_META, TBL_OBJECTS},
+        map::{
+            changes::{commit_hist_node, ChangeError},
+            elements::{ChangeBuilder, ElementRoleRegistry, Info},
+            history::TBL_HIST_NODES,
+            properties::MapProperties,
+            states::{read_state, ElementState, MapState, TBL_STATES},
+        },
+    },
+    id::{Id, IdGen},
+};
+
+/// A map edit as data, so edits can be generated by other tools.
+/// Ids are ULID strings and roles are role ids, like in map documents.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum EditCommand {
+    Create {
+        /// Generated when missing.
+        #[serde(default)]
+        id: Option<String>,
+        role: String,
+        info: Info,
+        params: Box<RawValue>,
+    },
+    UpdateInfo {
+        id: String,
+        info: Info,
+    },
+    UpdateParams {
+        id: String,
+        role: String,
+        params: Box<RawValue>,
+    },
+    Remove {
+        id: String,
+    },
+    /// Replaces all map properties, fields that are left out get their defaults.
+    SetProperties {
+        properties: MapProperties,
+    },
+}
+
+fn find_builder<'r>(
+    registry: &'r ElementRoleRegistry,
+    role: &str,
+) -> Result<&'r dyn ChangeBuilder> {
+    Ok(registry
+        .find_by_role_id(role)
+        .ok_or_else(|| format!("unknown role '{}'", role))?)
+}
+
+fn find_elem<'s>(state: &'s mut MapState, id: &str) -> Result<&'s mut ElementState> {
+    let id: Id = id.parse()?;
+    Ok(state
+        .elements
+        .get_mut(&id)
+        .ok_or(ChangeError::MissingElement(id))?)
+}
+
+/// Adds the object to the ones to store and returns its checksum.
+fn store(
+    objects: &mut Vec<(Checksum, Object)>,
+    (checksum, object): (Checksum, Object),
+) -> Checksum {
+    objects.push((checksum.clone(), object));
+    checksum
+}
+
+impl EditCommand {
+    /// Applies the edit to a map state. Objects the state refers to from now on are added to
+    /// `objects`.
+    fn apply_to_state(
+        &self,
+        state: &mut MapState,
+        objects: &mut Vec<(Checksum, Object)>,
+        registry: &ElementRoleRegistry,
+        id_gen: &mut IdGen,
+    ) -> Result {
+        match self {
+            EditCommand::Create {
+                id,
+                role,
+                info,
+                params,
+            } => {
+                let builder = find_builder(registry, role)?;
+                let id = match id {
+                    Some(id) => id.parse()?,
+                    None => id_gen.generate(),
+                };
+                if state.elements.contains_key(&id) {
+                    return Err(ChangeError::ElementExists(id).into());
+                }
+                let params = builder.params_from_ron(params)?;
+                let elem = ElementState {
+                    role: builder.id_hash(),
+                    info: store(objects, Object::new_typed(info)),
+                    params: store(objects, (Object::checksum(&params.data), params)),
+                };
+                state.elements.insert(id, elem);
+            }
+            EditCommand::UpdateInfo { id, info } => {
+                find_elem(state, id)?.info = store(objects, Object::new_typed(info));
+            }
+            EditCommand::UpdateParams { id, role, params } => {
+                let builder = find_builder(registry, role)?;
+                let elem = find_elem(state, id)?;
+                if elem.role != builder.id_hash() {
+                    return Err(format!("element {} is not a {}", id, role).into());
+                }
+                let params = builder.params_from_ron(params)?;
+                elem.params = store(objects, (Object::checksum(&params.data), params));
+            }
+            EditCommand::Remove { id } => {
+                let id: Id = id.parse()?;
+                state
+                    .elements
+                    .remove(&id)
+                    .ok_or(ChangeError::MissingElement(id))?;
+            }
+            EditCommand::SetProperties { properties } => {
+                properties.validate()?;
+                state.properties = properties.clone();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A list of edits, applied together as a single history entry.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(transparent)]
+pub struct EditScript(pub Vec<EditCommand>);
+
+impl EditScript {
+    pub fn from_ron(text: &str) -> Result<Self> {
+        Ok(ron::from_str(text)?)
+    }
+
+    /// Reads a script from a file, or from stdin if the path is "-".
+    pub fn read(path: &Path) -> Result<Self> {
+        let text = if path == Path::new("-") {
+            let mut text = String::new();
+            std::io::stdin().read_to_string(&mut text)?;
+            text
+        } else {
+            std::fs::read_to_string(path)?
+        };
+        Self::from_ron(&text)
+    }
+
+    /// Applies the edits on top of the current state of the map, as a single history node that
+    /// becomes the current one. Nothing is written if any of the edits fails.
+    pub fn apply_to_map(
+        &self,
+        db: &Db,
+        registry: &ElementRoleRegistry,
+        id_gen: &mut IdGen,
+        label: &str,
+    ) -> Result<Id> {
+        let writer = db.begin_write()?;
+        let meta = writer
+            .open_table(TBL_META)?
+            .get(())?
+            .ok_or(NotFound)?
+            .value();
+        let cur_hist = writer
+            .open_table(TBL_HIST_NODES)?
+            .get(meta.hist_node_id)?
+            .ok_or(NotFound)?
+            .value();
+        let cur_state = read_state(&writer.open_table(TBL_STATES)?, cur_hist.state_id)?;
+
+        let mut new_state = cur_state.clone();
+        let mut objects = Vec::new();
+        for (idx, command) in self.0.iter().enumerate() {
+            command
+                .apply_to_state(&mut new_state, &mut objects, registry, id_gen)
+                .map_err(|err| BevyError::from(format!("edit {}: {}", idx, err)))?;
+        }
+
+        {
+            let mut tbl_objects = writer.open_table(TBL_OBJECTS)?;
+            for (checksum, object) in objects {
+                tbl_objects.insert(checksum, object)?;
+            }
+        }
+        let hist_id = commit_hist_node(
+            &writer, id_gen, meta, cur_hist, &cur_state, new_state, label,
+        )?;
+        writer.commit()?;
+        Ok(hist_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::map::{
+        elements::light::{Light, LightType},
+        get_current_hist_node, get_current_state, init_db,
+    };
+
+    fn light(light_type: LightType) -> Result<Box<RawValue>> {
+        Ok(RawValue::from_rust(&Light {
+            position: Vec3::ZERO,
+            light_type,
+            color: Color::WHITE,
+            intensity: 1000.0,
+            range: 10.0,
+        })?)
+    }
+
+    #[test]
+    fn scripts_apply_as_a_whole() -> Result {
+        let db = Db::in_memory()?;
+        let mut id_gen = IdGen::default();
+        init_db(&db, "edits".to_string(), &mut id_gen)?;
+        let registry = ElementRoleRegistry::with_builtin_roles();
+
+        let id = id_gen.generate();
+        let info = Info {
+            name: "lamp".to_string(),
+        };
+        let script = EditScript(vec![EditCommand::Create {
+            id: Some(id.to_string()),
+            role: "light".to_string(),
+            info: info.clone(),
+            params: light(LightType::Point)?,
+        }]);
+        script.apply_to_map(&db, &registry, &mut id_gen, "Add lamp")?;
+        assert!(get_current_state(&db.begin_read()?)?
+            .elements
+            .contains_key(&id));
+
+        // The removal is fine but spot lights aren't, so neither edit is applied.
+        let script = EditScript(vec![
+            EditCommand::Remove { id: id.to_string() },
+            EditCommand::Create {
+                id: None,
+                role: "light".to_string(),
+                info,
+                params: light(LightType::Spot)?,
+            },
+        ]);
+        assert!(script
+            .apply_to_map(&db, &registry, &mut id_gen, "Swap lamp")
+            .is_err());
+        let reader = db.begin_read()?;
+        assert_eq!(get_current_hist_node(&reader)?.label, "Add lamp");
+        assert!(get_current_state(&reader)?.elements.contains_key(&id));
+        Ok(())
+    }
+}
@@ -7,15 +7,19 @@ use std::{
 };
 
 use bevy::{platform::collections::HashMap, prelude::*};
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use ron::value::RawValue;
+use serde::{Deserialize, Serialize};
 
 use crate::{
     core::{
-        db::Object,
+        db::{DbError, Object, Versioned},
         map::{
-            changes::{Change, CreateElem, CreateId, UpdateElemParams},
-            elements::{brush::Brush, light::Light},
-            states::sync_params,
+            changes::{Change, ChangeError, CreateElem, CreateId, UpdateElemParams},
+            elements::{
+                brush::Brush,
+                light::{Light, LightType},
+            },
+            states::{sync_elements, SyncState},
             StateSnapshot,
         },
     },
@@ -45,10 +49,16 @@ pub struct Info {
     pub name: String,
 }
 
-pub trait Role:
-    Send + Sync + std::fmt::Debug + Clone + Serialize + DeserializeOwned + Component
-{
+impl Versioned for Info {
+    const VERSION: u16 = 0;
+}
+
+pub trait Role: Send + Sync + std::fmt::Debug + Clone + Versioned + Component {
     fn id() -> &'static str;
+    /// Checks params before they are put into the world, the error is shown to the user.
+    fn validate(&self) -> Result<(), String> {
+        Ok(())
+    }
     fn id_hash() -> u64 {
         let mut s = DefaultHasher::new();
         Self::id().hash(&mut s);
@@ -56,16 +66,56 @@ pub trait Role:
     }
 }
 
+impl Versioned for Brush {
+    const VERSION: u16 = 3;
+}
+
+impl Versioned for Light {
+    const VERSION: u16 = 0;
+}
+
 impl Role for Brush {
     fn id() -> &'static str {
         "brush"
     }
+
+    fn validate(&self) -> Result<(), String> {
+        if self
+            .faces
+            .iter()
+            .any(|face| !face.plane.normal.is_normalized() || !face.plane.distance.is_finite())
+        {
+            return Err("brush face has an invalid plane".to_string());
+        }
+        if !self.is_closed() {
+            return Err("brush is open on some side".to_string());
+        }
+        if !self.bounds().is_some_and(|bounds| bounds.is_valid()) {
+            return Err("brush is too thin".to_string());
+        }
+        if self
+            .faces
+            .iter()
+            .any(|face| face.uv.scale.x == 0.0 || face.uv.scale.y == 0.0)
+        {
+            return Err("texture scale can't be zero".to_string());
+        }
+        Ok(())
+    }
 }
 
 impl Role for Light {
     fn id() -> &'static str {
         "light"
     }
+
+    fn validate(&self) -> Result<(), String> {
+        match self.light_type {
+            LightType::Point => Ok(()),
+            // Spot lights need a direction, which elements can't store yet.
+            LightType::Spot => Err("spot lights aren't supported yet".to_string()),
+        }
+    }
 }
 
 #[derive(Resource, Default)]
@@ -74,8 +124,23 @@ pub struct ElementRoleRegistry {
 }
 
 pub trait ChangeBuilder: Send + Sync + 'static {
-    fn build_create(&self, id: CreateId, info: Info, raw_params: Object) -> Box<dyn Change>;
-    fn build_update(&self, id: Id, raw_params: Object) -> Box<dyn Change>;
+    fn role_id(&self) -> &'static str;
+    /// Role as stored in element states.
+    fn id_hash(&self) -> u64;
+    fn build_create(
+        &self,
+        id: CreateId,
+        info: Info,
+        raw_params: Object,
+    ) -> Result<Box<dyn Change>, DbError>;
+    fn build_update(&self, id: Id, raw_params: Object) -> Result<Box<dyn Change>, DbError>;
+    fn debug_params(&self, raw_params: &Object) -> Result<String, DbError>;
+    /// Converts stored params to RON text, for exporting.
+    fn params_to_ron(&self, raw_params: &Object) -> Result<Box<RawValue>>;
+    /// Parses and validates params from RON text into a storable object, for importing.
+    fn params_from_ron(&self, value: &RawValue) -> Result<Object>;
+    /// Type name and current version of the params, for migrating stored params.
+    fn params_schema(&self) -> (&'static str, u16);
 }
 
 struct RoleChangeBuilder<R>(PhantomData<R>);
@@ -91,25 +156,82 @@ where
     CreateElem<R>: Change,
     UpdateElemParams<R>: Change,
 {
-    fn build_create(&self, id: CreateId, info: Info, raw_params: Object) -> Box<dyn Change> {
-        let params = raw_params.cast::<R>();
-        Box::new(CreateElem {
+    fn role_id(&self) -> &'static str {
+        R::id()
+    }
+
+    fn id_hash(&self) -> u64 {
+        R::id_hash()
+    }
+
+    fn build_create(
+        &self,
+        id: CreateId,
+        info: Info,
+        raw_params: Object,
+    ) -> Result<Box<dyn Change>, DbError> {
+        let params = raw_params.cast::<R>()?;
+        Ok(Box::new(CreateElem {
             id_mode: id,
             info,
             params,
-        })
+        }))
     }
 
-    fn build_update(&self, elem_id: Id, raw_params: Object) -> Box<dyn Change> {
-        let new_params = raw_params.cast::<R>();
-        Box::new(UpdateElemParams {
+    fn build_update(&self, elem_id: Id, raw_params: Object) -> Result<Box<dyn Change>, DbError> {
+        let new_params = raw_params.cast::<R>()?;
+        Ok(Box::new(UpdateElemParams {
             elem_id,
             params: new_params,
-        })
+        }))
+    }
+
+    fn debug_params(&self, raw_params: &Object) -> Result<String, DbError> {
+        Ok(format!("{:?}", raw_params.cast::<R>()?))
+    }
+
+    fn params_to_ron(&self, raw_params: &Object) -> Result<Box<RawValue>> {
+        Ok(RawValue::from_rust(&raw_params.cast::<R>()?)?)
+    }
+
+    fn params_from_ron(&self, value: &RawValue) -> Result<Object> {
+        let params: R = value.into_rust()?;
+        params
+            .validate()
+            .map_err(|reason| ChangeError::InvalidParams {
+                role: R::id(),
+                reason,
+            })?;
+        Ok(Object::new_typed(&params).1)
+    }
+
+    fn params_schema(&self) -> (&'static str, u16) {
+        (std::any::type_name::<R>(), R::VERSION)
     }
 }
 
 impl ElementRoleRegistry {
+    /// Registry with all roles built into the editor, for use outside of the app.
+    /// Should match the roles registered in the map plugin.
+    pub fn with_builtin_roles() -> Self {
+        let mut registry = Self::default();
+        registry.register::<Brush>();
+        registry.register::<Light>();
+        registry
+    }
+
+    pub fn role_id(&self, id_hash: u64) -> Option<&'static str> {
+        self.roles.get(&id_hash).map(|builder| builder.role_id())
+    }
+
+    /// Looks up a role by its id string, e.g. "brush".
+    pub fn find_by_role_id(&self, role_id: &str) -> Option<&dyn ChangeBuilder> {
+        self.roles
+            .values()
+            .find(|builder| builder.role_id() == role_id)
+            .map(|builder| builder.as_ref())
+    }
+
     pub fn register<R>(&mut self)
     where
         R: Role + 'static,
@@ -140,6 +262,6 @@ impl AppRoleRegistry for App {
         self.world_mut()
             .resource_mut::<ElementRoleRegistry>()
             .register::<R>();
-        self.add_systems(StateSnapshot, sync_params::<R>);
+        self.add_systems(StateSnapshot, sync_elements::<R>.in_set(SyncState));
     }
 }
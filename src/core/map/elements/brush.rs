@@ -1,26 +1,566 @@
+use std::f32::consts::TAU;
+
 use avian3d::prelude::*;
 use bevy::{
     asset::RenderAssetUsages,
+    math::{vec2, vec3, DVec3},
     prelude::*,
     render::mesh::{
         Indices,
         PrimitiveTopology::{self},
     },
 };
+use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    core::map::{
-        changes::{Change, UpdateElemParams},
-        ElementLookup, MapAssets,
+    core::{
+        map::{
+            changes::{get_elem_component, require_elem, Change, ChangeError, UpdateElemParams},
+            elements::Role,
+            ElementLookup, MapAssets,
+        },
+        media::{surface::Surface, MediaCollection},
     },
+    id::Id,
     util::Facing3d,
 };
 
+/// A convex solid, made of the space behind all of its faces.
 #[derive(Component, Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[require(Visibility, Transform)]
 pub struct Brush {
-    pub bounds: BrushBounds,
+    pub faces: Vec<BrushFace>,
+}
+
+/// Order of the faces of box brushes.
+pub const CUBOID_FACINGS: [Facing3d; 6] = [
+    Facing3d::NegX,
+    Facing3d::X,
+    Facing3d::NegZ,
+    Facing3d::Z,
+    Facing3d::NegY,
+    Facing3d::Y,
+];
+
+/// Sides of pillars, enough to look round without getting fiddly to select.
+const PILLAR_SIDES: usize = 8;
+
+/// Half the size of the quad each face outline is cut from. Brushes have to fit well within it.
+const SEED_HALF_EXTENT: f64 = 100_000.0;
+
+impl Brush {
+    pub fn cuboid(bounds: &BrushBounds) -> Self {
+        Self {
+            faces: CUBOID_FACINGS
+                .into_iter()
+                .map(|facing| {
+                    let point = match facing {
+                        Facing3d::X | Facing3d::Y | Facing3d::Z => bounds.end,
+                        Facing3d::NegX | Facing3d::NegY | Facing3d::NegZ => bounds.start,
+                    };
+                    BrushFace::new(*facing.as_dir(), point)
+                })
+                .collect(),
+        }
+    }
+
+    /// A wedge filling the bounds, sloping up towards -Z.
+    pub fn ramp(bounds: &BrushBounds) -> Self {
+        let size = bounds.size();
+        Self {
+            faces: vec![
+                BrushFace::new(Vec3::NEG_X, bounds.start),
+                BrushFace::new(Vec3::X, bounds.end),
+                BrushFace::new(Vec3::NEG_Z, bounds.start),
+                BrushFace::new(Vec3::NEG_Y, bounds.start),
+                BrushFace::new(
+                    vec3(0.0, size.z, size.y),
+                    vec3(bounds.start.x, bounds.start.y, bounds.end.z),
+                ),
+            ],
+        }
+    }
+
+    /// An upright prism filling the bounds, with sides touching the ellipse inside them.
+    pub fn pillar(bounds: &BrushBounds) -> Self {
+        let center = bounds.center();
+        let half_size = bounds.size() / 2.0;
+        let sides = (0..PILLAR_SIDES).map(|idx| {
+            let (sin, cos) = (idx as f32 / PILLAR_SIDES as f32 * TAU).sin_cos();
+            BrushFace::new(
+                vec3(cos / half_size.x, 0.0, sin / half_size.z),
+                center + vec3(cos * half_size.x, 0.0, sin * half_size.z),
+            )
+        });
+        Self {
+            faces: [
+                BrushFace::new(Vec3::NEG_Y, bounds.start),
+                BrushFace::new(Vec3::Y, bounds.end),
+            ]
+            .into_iter()
+            .chain(sides)
+            .collect(),
+        }
+    }
+
+    /// Rotates the brush around its center, keeping the surfaces on their faces.
+    pub fn rotated(&self, rotation: Quat) -> Self {
+        let Some(pivot) = self.bounds().map(|bounds| bounds.center()) else {
+            return self.clone();
+        };
+        Self {
+            faces: self
+                .faces
+                .iter()
+                .map(|face| BrushFace {
+                    plane: face.plane.rotated(rotation, pivot),
+                    ..face.clone()
+                })
+                .collect(),
+        }
+    }
+
+    /// Moves a face along its normal so that it passes through `target_point`.
+    pub fn moved_face(&self, face: usize, target_point: Vec3) -> Self {
+        let mut brush = self.clone();
+        if let Some(face) = brush.faces.get_mut(face) {
+            face.plane.distance = face.plane.normal.dot(target_point);
+        }
+        brush
+    }
+
+    /// Outline of each face, counter-clockwise when seen from outside. Faces that don't touch the
+    /// brush, e.g. because other faces cut them off, have an empty outline.
+    pub fn polygons(&self) -> Vec<Vec<Vec3>> {
+        (0..self.faces.len())
+            .map(|face| self.face_polygon(face))
+            .collect()
+    }
+
+    pub fn face_polygon(&self, face: usize) -> Vec<Vec3> {
+        // Start with a huge quad on the face plane and cut away everything in front of the others.
+        // Doubles, so the far away corners don't cost precision.
+        const MIN_EDGE_LENGTH: f64 = 0.0001;
+
+        let plane = self.faces[face].plane;
+        let normal = plane.normal.as_dvec3();
+        let (right, up) = plane.axes();
+        let right = (right.as_dvec3() - normal * normal.dot(right.as_dvec3())).normalize();
+        let up = right.cross(normal);
+        let origin = normal * plane.distance as f64;
+        let mut polygon = vec![
+            origin + (-right - up) * SEED_HALF_EXTENT,
+            origin + (-right + up) * SEED_HALF_EXTENT,
+            origin + (right + up) * SEED_HALF_EXTENT,
+            origin + (right - up) * SEED_HALF_EXTENT,
+        ];
+        for (idx, other) in self.faces.iter().enumerate() {
+            if idx != face {
+                polygon = clip_polygon(&polygon, &other.plane);
+            }
+        }
+
+        polygon.dedup_by(|a, b| a.distance(*b) < MIN_EDGE_LENGTH);
+        if polygon.len() > 1 && polygon[0].distance(polygon[polygon.len() - 1]) < MIN_EDGE_LENGTH {
+            polygon.pop();
+        }
+        if polygon.len() < 3 {
+            return Vec::new();
+        }
+        polygon.into_iter().map(|point| point.as_vec3()).collect()
+    }
+
+    /// Corners of the brush, once for each face they belong to.
+    pub fn vertices(&self) -> Vec<Vec3> {
+        self.polygons().into_iter().flatten().collect()
+    }
+
+    /// Whether the faces enclose the brush on all sides. Outlines of open brushes still reach the
+    /// edge of the quads they are cut from.
+    pub fn is_closed(&self) -> bool {
+        self.vertices()
+            .iter()
+            .all(|vertex| (vertex.length() as f64) < SEED_HALF_EXTENT / 2.0)
+    }
+
+    /// Box around the brush, or `None` when there's nothing left of it.
+    pub fn bounds(&self) -> Option<BrushBounds> {
+        let vertices = self.vertices();
+        let first = *vertices.first()?;
+        Some(
+            vertices
+                .into_iter()
+                .fold(BrushBounds::new(first, first), |bounds, vertex| {
+                    BrushBounds::new(bounds.start.min(vertex), bounds.end.max(vertex))
+                }),
+        )
+    }
+
+    /// Size of the texture space a face covers, for fitting textures to it.
+    pub fn face_size(&self, face: usize) -> Vec2 {
+        let polygon = self.face_polygon(face);
+        let (min, max) = plane_extent(&self.faces[face].plane, &polygon);
+        (max - min).max(Vec2::ZERO)
+    }
+
+    /// Corners of the brush, each one once.
+    pub fn corners(&self) -> Vec<Vec3> {
+        let mut corners: Vec<Vec3> = Vec::new();
+        for vertex in self.vertices() {
+            if !corners
+                .iter()
+                .any(|corner| corner.distance(vertex) < CORNER_TOLERANCE)
+            {
+                corners.push(vertex);
+            }
+        }
+        corners
+    }
+
+    /// Edges of the brush, each one once.
+    pub fn edges(&self) -> Vec<(Vec3, Vec3)> {
+        let same = |a: Vec3, b: Vec3| a.distance(b) < CORNER_TOLERANCE;
+        let mut edges: Vec<(Vec3, Vec3)> = Vec::new();
+        for polygon in self.polygons() {
+            for (idx, &start) in polygon.iter().enumerate() {
+                let end = polygon[(idx + 1) % polygon.len()];
+                // Neighbouring faces share edges, in opposite directions.
+                if !edges.iter().any(|&(a, b)| {
+                    (same(a, start) && same(b, end)) || (same(a, end) && same(b, start))
+                }) {
+                    edges.push((start, end));
+                }
+            }
+        }
+        edges
+    }
+
+    /// Subtracts `cutter`, giving the convex pieces that are left. `None` when the two don't
+    /// overlap, so there's nothing to carve.
+    pub fn carved(&self, cutter: &Brush) -> Option<Vec<Brush>> {
+        let overlap = Brush {
+            faces: self.faces.iter().chain(&cutter.faces).cloned().collect(),
+        };
+        if !overlap.bounds().is_some_and(|bounds| bounds.is_valid()) {
+            return None;
+        }
+
+        // Peel off the part in front of each cutter face, what's left in the end is the overlap.
+        let mut pieces = Vec::new();
+        let mut remainder = self.clone();
+        for face in &cutter.faces {
+            let mut piece = remainder.clone();
+            // The cut is lined with the surface of the cutter face.
+            piece.faces.push(BrushFace {
+                plane: BrushPlane {
+                    normal: -face.plane.normal,
+                    distance: -face.plane.distance,
+                },
+                ..face.clone()
+            });
+            let piece = piece.pruned();
+            if piece.bounds().is_some_and(|bounds| bounds.is_valid()) {
+                pieces.push(piece);
+            }
+            remainder.faces.push(face.clone());
+        }
+        Some(pieces)
+    }
+
+    /// Drops faces that other faces cut off entirely, and faces on the same plane as an earlier
+    /// one, which would overlap it.
+    fn pruned(self) -> Self {
+        let polygons = self.polygons();
+        let mut faces: Vec<BrushFace> = Vec::new();
+        for (face, polygon) in self.faces.into_iter().zip(polygons) {
+            if !polygon.is_empty() && !faces.iter().any(|kept| kept.plane.is_same(&face.plane)) {
+                faces.push(face);
+            }
+        }
+        Self { faces }
+    }
+
+    /// Moves the given corners by `offset`. Returns `None` when the result wouldn't be convex with
+    /// all corners still being corners, or when the corners aren't on the brush.
+    pub fn with_moved_corners(&self, corners: &[Vec3], offset: Vec3) -> Option<Self> {
+        let mut points = self.corners();
+        let mut moved = 0;
+        for point in points.iter_mut() {
+            if corners
+                .iter()
+                .any(|corner| corner.distance(*point) < CORNER_TOLERANCE)
+            {
+                *point += offset;
+                moved += 1;
+            }
+        }
+        if moved != corners.len() {
+            return None;
+        }
+        if offset == Vec3::ZERO {
+            return Some(self.clone());
+        }
+        self.spanning(&points)
+    }
+
+    /// The convex hull of `points`, as long as all of them end up as its corners. Faces take their
+    /// surface and mapping from the face of this brush facing the most alike.
+    fn spanning(&self, points: &[Vec3]) -> Option<Self> {
+        // Every plane through three points with all the others behind it is a face.
+        let mut planes: Vec<BrushPlane> = Vec::new();
+        for (a, b, c) in points.iter().tuple_combinations() {
+            let normal = (b - a).cross(c - a);
+            if normal.length() < 1e-6 {
+                continue;
+            }
+            let plane = BrushPlane::new(normal, *a);
+            let plane = if points
+                .iter()
+                .all(|point| plane.signed_distance(*point) < CORNER_TOLERANCE)
+            {
+                plane
+            } else if points
+                .iter()
+                .all(|point| plane.signed_distance(*point) > -CORNER_TOLERANCE)
+            {
+                BrushPlane::new(-normal, *a)
+            } else {
+                continue;
+            };
+            if !planes.iter().any(|known| known.is_same(&plane)) {
+                planes.push(plane);
+            }
+        }
+
+        let faces = planes
+            .into_iter()
+            .map(|plane| {
+                let alike = self.faces.iter().max_by(|face_a, face_b| {
+                    face_a
+                        .plane
+                        .normal
+                        .dot(plane.normal)
+                        .total_cmp(&face_b.plane.normal.dot(plane.normal))
+                });
+                match alike {
+                    Some(face) => BrushFace {
+                        plane,
+                        ..face.clone()
+                    },
+                    None => BrushFace::new(plane.normal, plane.normal * plane.distance),
+                }
+            })
+            .collect();
+        let brush = Self { faces };
+
+        let corners = brush.corners();
+        let is_convex = points.iter().all(|point| {
+            corners
+                .iter()
+                .any(|corner| corner.distance(*point) < CORNER_TOLERANCE)
+        });
+        (is_convex && brush.bounds().is_some_and(|bounds| bounds.is_valid())).then_some(brush)
+    }
+}
+
+/// Points closer than this are the same corner.
+const CORNER_TOLERANCE: f32 = 0.001;
+
+/// Keeps the part of a polygon behind the plane.
+fn clip_polygon(polygon: &[DVec3], plane: &BrushPlane) -> Vec<DVec3> {
+    let normal = plane.normal.as_dvec3();
+    let distance = plane.distance as f64;
+    let mut clipped = Vec::with_capacity(polygon.len() + 1);
+    for (idx, &current) in polygon.iter().enumerate() {
+        let next = polygon[(idx + 1) % polygon.len()];
+        let current_dist = normal.dot(current) - distance;
+        let next_dist = normal.dot(next) - distance;
+        if current_dist <= 0.0 {
+            clipped.push(current);
+        }
+        if (current_dist < 0.0 && next_dist > 0.0) || (current_dist > 0.0 && next_dist < 0.0) {
+            clipped.push(current + (next - current) * (current_dist / (current_dist - next_dist)));
+        }
+    }
+    clipped
+}
+
+/// Corners of the rectangle around a polygon, in texture axes of the plane.
+fn plane_extent(plane: &BrushPlane, polygon: &[Vec3]) -> (Vec2, Vec2) {
+    let (right, up) = plane.axes();
+    polygon
+        .iter()
+        .fold((Vec2::MAX, Vec2::MIN), |(min, max), point| {
+            let plane_point = vec2(point.dot(right), point.dot(up));
+            (min.min(plane_point), max.max(plane_point))
+        })
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BrushFace {
+    pub plane: BrushPlane,
+    /// Faces without a surface use the default surface of the map.
+    #[serde(default)]
+    pub surface: Option<Id>,
+    #[serde(default)]
+    pub uv: SideUv,
+}
+
+impl BrushFace {
+    pub fn new(normal: Vec3, point: Vec3) -> Self {
+        Self {
+            plane: BrushPlane::new(normal, point),
+            surface: None,
+            uv: default(),
+        }
+    }
+}
+
+/// Points `p` with `normal.dot(p) == distance` are on the plane, the normal points out of the
+/// brush.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct BrushPlane {
+    pub normal: Vec3,
+    pub distance: f32,
+}
+
+impl BrushPlane {
+    pub fn new(normal: Vec3, point: Vec3) -> Self {
+        let normal = normal.normalize();
+        Self {
+            normal,
+            distance: normal.dot(point),
+        }
+    }
+
+    /// Positive in front of the plane, outside of the brush.
+    pub fn signed_distance(&self, point: Vec3) -> f32 {
+        self.normal.dot(point) - self.distance
+    }
+
+    fn is_same(&self, other: &BrushPlane) -> bool {
+        self.normal.dot(other.normal) > 1.0 - 1e-4
+            && (self.distance - other.distance).abs() < CORNER_TOLERANCE
+    }
+
+    /// Texture axes. Planes facing the same way share them, which keeps world aligned textures
+    /// seamless.
+    fn axes(&self) -> (Vec3, Vec3) {
+        let rotation = Quat::from_rotation_arc(Vec3::NEG_Z, self.normal);
+        (rotation * Vec3::X, rotation * Vec3::Y)
+    }
+
+    fn rotated(&self, rotation: Quat, pivot: Vec3) -> Self {
+        let point = self.normal * self.distance;
+        Self::new(rotation * self.normal, pivot + rotation * (point - pivot))
+    }
+}
+
+/// How many times a texture repeats per world unit, at scale 1.
+const TEXTURE_REPEATS_PER_UNIT: f32 = 0.1;
+
+/// Where texture coordinates of a side are measured from.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum UvAlign {
+    /// From the world origin, so textures line up across brushes and stay put when resizing.
+    #[default]
+    World,
+    /// From the corner of the side, so textures move along with it.
+    Face,
+}
+
+/// Texture mapping of a brush side.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct SideUv {
+    /// In texture repeats.
+    pub offset: Vec2,
+    pub scale: Vec2,
+    /// Counter-clockwise, in degrees.
+    pub rotation: f32,
+    pub align: UvAlign,
+}
+
+impl Default for SideUv {
+    fn default() -> Self {
+        Self {
+            offset: Vec2::ZERO,
+            scale: Vec2::ONE,
+            rotation: 0.0,
+            align: UvAlign::World,
+        }
+    }
+}
+
+impl SideUv {
+    /// Stretches the texture over a side of the given size exactly once.
+    pub fn fit(size: Vec2) -> Self {
+        Self {
+            offset: Vec2::ZERO,
+            scale: size * TEXTURE_REPEATS_PER_UNIT,
+            rotation: 0.0,
+            align: UvAlign::Face,
+        }
+    }
+
+    /// Texture coordinates of a point on the side plane.
+    fn project(&self, point: Vec2) -> Vec2 {
+        Vec2::from_angle(self.rotation.to_radians()).rotate(point) * TEXTURE_REPEATS_PER_UNIT
+            / self.scale
+            + self.offset
+    }
+}
+
+/// Marks the mesh entity of a brush face, as a child of the brush. Holds the index of the face.
+#[derive(Component, Debug, Clone, Copy)]
+pub struct BrushFaceMesh(pub usize);
+
+/// Material for a brush face. Missing surfaces, e.g. ones that aren't loaded yet, fall back to
+/// the default material.
+pub fn face_material(
+    surface: Option<Id>,
+    media: &MediaCollection<Surface>,
+    map_assets: &MapAssets,
+) -> Handle<StandardMaterial> {
+    surface
+        .and_then(|id| media.get(&id))
+        .map(|surface| surface.content.handles.std_material.clone())
+        .unwrap_or_else(|| map_assets.default_material.clone())
+}
+
+/// Mesh of a face, relative to `origin`. The polygon is in world space, for world aligned
+/// textures.
+fn face_mesh(face: &BrushFace, polygon: &[Vec3], origin: Vec3) -> Mesh {
+    let (right, up) = face.plane.axes();
+    let (corner, _) = plane_extent(&face.plane, polygon);
+
+    let positions: Vec<Vec3> = polygon.iter().map(|point| *point - origin).collect();
+    let normals = vec![face.plane.normal.to_array(); polygon.len()];
+    let uvs: Vec<[f32; 2]> = polygon
+        .iter()
+        .map(|point| {
+            let plane_point = vec2(point.dot(right), point.dot(up));
+            let uv_pos = match face.uv.align {
+                UvAlign::Face => plane_point - corner,
+                UvAlign::World => plane_point,
+            };
+            face.uv.project(uv_pos).to_array()
+        })
+        .collect();
+    // Faces are convex, so a fan covers them.
+    let indices = (1..polygon.len() as u32 - 1)
+        .flat_map(|idx| [0, idx, idx + 1])
+        .collect();
+
+    Mesh::new(
+        PrimitiveTopology::TriangleList,
+        RenderAssetUsages::RENDER_WORLD,
+    )
+    .with_inserted_indices(Indices::U32(indices))
+    .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+    .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals)
+    .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, uvs)
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
@@ -49,167 +589,404 @@ impl BrushBounds {
     pub fn size(&self) -> Vec3 {
         self.end - self.start
     }
+}
 
-    pub fn sides_local(&self) -> impl Iterator<Item = BrushSide> {
-        let size = self.size();
-        let half_size = size / 2.0;
-        vec![
-            // X-
-            BrushSide {
-                facing: Facing3d::NegX,
-                pos: Vec3::NEG_X * half_size.x,
-                size: Vec2::new(size.z, size.y),
-            },
-            // X+
-            BrushSide {
-                facing: Facing3d::X,
-                pos: Vec3::X * half_size.x,
-                size: Vec2::new(size.z, size.y),
-            },
-            // Z-
-            BrushSide {
-                facing: Facing3d::NegZ,
-                pos: Vec3::NEG_Z * half_size.z,
-                size: Vec2::new(size.x, size.y),
-            },
-            // Z+
-            BrushSide {
-                facing: Facing3d::Z,
-                pos: Vec3::Z * half_size.z,
-                size: Vec2::new(size.x, size.y),
-            },
-            // Y-
-            BrushSide {
-                facing: Facing3d::NegY,
-                pos: Vec3::NEG_Y * half_size.y,
-                size: Vec2::new(size.x, size.z),
-            },
-            // Y+
-            BrushSide {
-                facing: Facing3d::Y,
-                pos: Vec3::Y * half_size.y,
-                size: Vec2::new(size.x, size.z),
-            },
-        ]
-        .into_iter()
+impl Change for UpdateElemParams<Brush> {
+    fn validate(&self, world: &World) -> Result<(), ChangeError> {
+        require_elem(world, &self.elem_id)?;
+        self.params
+            .validate()
+            .map_err(|reason| ChangeError::InvalidParams {
+                role: Brush::id(),
+                reason,
+            })
     }
 
-    pub fn sides_world(&self) -> impl Iterator<Item = BrushSide> {
-        let offset = self.center();
-        self.sides_local().map(move |mut side| {
-            side.pos += offset;
-            side
-        })
+    fn apply_to_world(&self, world: &mut World) -> Result {
+        world.run_system_cached_with(
+            |change: In<Self>,
+             lookup: Res<ElementLookup>,
+             map_assets: Res<MapAssets>,
+             media: Res<MediaCollection<Surface>>,
+             mut meshes: ResMut<Assets<Mesh>>,
+             mut commands: Commands|
+             -> Result {
+                let entity_id = lookup.find(&change.elem_id)?;
+                let brush = change.params.clone();
+                let polygons = brush.polygons();
+
+                // Brush will use base entity as a container for faces.
+                let center = brush.bounds().ok_or("brush has no volume")?.center();
+                let collider = Collider::convex_hull(
+                    polygons
+                        .iter()
+                        .flatten()
+                        .map(|vertex| *vertex - center)
+                        .collect(),
+                )
+                .ok_or("brush has no volume")?;
+
+                let mut entity = commands.entity(entity_id);
+                entity.insert((
+                    brush.clone(),
+                    Transform::IDENTITY.with_translation(center),
+                    RigidBody::Static,
+                    collider,
+                ));
+                entity.despawn_related::<Children>();
+                entity.with_children(|cmds| {
+                    for (idx, (face, polygon)) in brush.faces.iter().zip(&polygons).enumerate() {
+                        if polygon.is_empty() {
+                            continue;
+                        }
+                        cmds.spawn((
+                            BrushFaceMesh(idx),
+                            Mesh3d(meshes.add(face_mesh(face, polygon, center))),
+                            MeshMaterial3d(face_material(face.surface, &media, &map_assets)),
+                        ));
+                    }
+                });
+                Ok(())
+            },
+            self.clone(),
+        )??;
+        Ok(())
     }
 
-    pub fn resized(&self, side: Facing3d, target_point: Vec3) -> Self {
-        let (start, end) = match side {
-            Facing3d::NegX => (self.start.with_x(target_point.x), self.end),
-            Facing3d::NegY => (self.start.with_y(target_point.y), self.end),
-            Facing3d::NegZ => (self.start.with_z(target_point.z), self.end),
-            Facing3d::X => (self.start, self.end.with_x(target_point.x)),
-            Facing3d::Y => (self.start, self.end.with_y(target_point.y)),
-            Facing3d::Z => (self.start, self.end.with_z(target_point.z)),
+    fn describe(&self, world: &World) -> String {
+        let planes = |brush: &Brush| {
+            brush
+                .faces
+                .iter()
+                .map(|face| face.plane)
+                .collect::<Vec<_>>()
+        };
+        let normals = |brush: &Brush| {
+            brush
+                .faces
+                .iter()
+                .map(|face| face.plane.normal)
+                .collect::<Vec<_>>()
+        };
+        let edge_lengths = |brush: &Brush| {
+            brush
+                .edges()
+                .into_iter()
+                .map(|(start, end)| (start.distance(end) / CORNER_TOLERANCE).round() as i64)
+                .sorted()
+                .collect::<Vec<_>>()
         };
-        // Run thru constructor to ensure the brush don't flip inside out.
-        Self::new(start, end)
+        let new = &self.params;
+        match get_elem_component::<Brush>(world, &self.elem_id) {
+            Some(old) if planes(old) == planes(new) => {
+                if old
+                    .faces
+                    .iter()
+                    .zip(&new.faces)
+                    .any(|(a, b)| a.surface != b.surface)
+                {
+                    "Set brush surface"
+                } else if old.faces.iter().zip(&new.faces).any(|(a, b)| a.uv != b.uv) {
+                    "Align brush surface"
+                } else {
+                    "Edit brush"
+                }
+            }
+            Some(old) if normals(old) == normals(new) => {
+                let size = |brush: &Brush| brush.bounds().map(|bounds| bounds.size());
+                if size(old) == size(new) {
+                    "Move brush"
+                } else {
+                    "Resize brush"
+                }
+            }
+            // Turning a brush keeps its edges as they are, dragging corners doesn't.
+            Some(old) if edge_lengths(old) == edge_lengths(new) => "Rotate brush",
+            Some(_) => "Reshape brush",
+            None => "Edit brush",
+        }
+        .to_string()
     }
-}
 
-#[derive(Clone)]
-pub struct BrushSide {
-    pub facing: Facing3d,
-    pub pos: Vec3,
-    pub size: Vec2,
-}
+    fn verb(&self) -> &'static str {
+        "Edit"
+    }
 
-impl Meshable for BrushSide {
-    type Output = BrushSideMeshBuilder;
+    fn elem_id(&self) -> Option<Id> {
+        Some(self.elem_id)
+    }
+}
 
-    fn mesh(&self) -> Self::Output {
-        BrushSideMeshBuilder(self.clone())
+/// Surfaces can be loaded after the brushes using them, so their faces are updated whenever the
+/// surface collection changes.
+pub fn refresh_brush_surfaces(
+    media: Res<MediaCollection<Surface>>,
+    map_assets: Res<MapAssets>,
+    q_brushes: Query<&Brush>,
+    mut q_faces: Query<(
+        &BrushFaceMesh,
+        &ChildOf,
+        &mut MeshMaterial3d<StandardMaterial>,
+    )>,
+) {
+    for (face_mesh, child_of, mut material) in q_faces.iter_mut() {
+        let Some(face) = q_brushes
+            .get(child_of.parent())
+            .ok()
+            .and_then(|brush| brush.faces.get(face_mesh.0))
+        else {
+            continue;
+        };
+        if face.surface.is_none() {
+            continue;
+        }
+        let new_material = face_material(face.surface, &media, &map_assets);
+        if material.0 != new_material {
+            material.0 = new_material;
+        }
     }
 }
 
-pub struct BrushSideMeshBuilder(BrushSide);
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::FRAC_PI_2;
+
+    use super::*;
+    use crate::id::IdGen;
 
-impl MeshBuilder for BrushSideMeshBuilder {
-    fn build(&self) -> Mesh {
-        const VERTS: usize = 4;
-        let mut positions: Vec<Vec3> = Vec::with_capacity(VERTS);
-        let mut normals: Vec<[f32; 3]> = Vec::with_capacity(VERTS);
-        let mut uvs: Vec<[f32; 2]> = Vec::with_capacity(VERTS);
+    fn cuboid(start: Vec3, end: Vec3) -> Brush {
+        Brush::cuboid(&BrushBounds::new(start, end))
+    }
 
-        let rotation = Quat::from_rotation_arc(Vec3::NEG_Z, *self.0.facing.as_dir());
-        let size = self.0.size;
+    fn assert_bounds(brush: &Brush, start: Vec3, end: Vec3) {
+        let bounds = brush.bounds().expect("brush has no volume");
+        assert!(
+            bounds.start.abs_diff_eq(start, 1e-3) && bounds.end.abs_diff_eq(end, 1e-3),
+            "{:?} isn't {} to {}",
+            bounds,
+            start,
+            end
+        );
+    }
 
-        // Vertices (and vertex data)
-        for z in 0..=1 {
-            for x in 0..=1 {
-                let tx = x as f32;
-                let tz = z as f32;
-                let pos = rotation * Vec3::new((-0.5 + tx) * size.x, (-0.5 + tz) * size.y, 0.0);
-                positions.push(pos);
-                normals.push(self.0.facing.as_dir().to_array());
-                uvs.push([tx * size.x * 0.1, tz * size.y * 0.1]);
+    #[test]
+    fn cuboid_faces_outline_the_box() {
+        let brush = cuboid(Vec3::ZERO, vec3(2.0, 1.0, 3.0));
+        for (face, polygon) in brush.faces.iter().zip(brush.polygons()) {
+            assert_eq!(polygon.len(), 4);
+            for vertex in polygon.iter() {
+                assert!(face.plane.signed_distance(*vertex).abs() < 1e-3);
             }
+            // Counter-clockwise when seen from outside.
+            let winding = (polygon[1] - polygon[0]).cross(polygon[2] - polygon[0]);
+            assert!(winding.dot(face.plane.normal) > 0.0);
         }
+        assert_eq!(brush.corners().len(), 8);
+        assert_eq!(brush.edges().len(), 12);
+        assert_bounds(&brush, Vec3::ZERO, vec3(2.0, 1.0, 3.0));
+    }
 
-        let indices = vec![3, 1, 2, 0, 2, 1];
+    #[test]
+    fn shapes_fill_their_bounds() {
+        let (start, end) = (vec3(-1.0, 0.0, -2.0), vec3(1.0, 2.0, 2.0));
+        let bounds = BrushBounds::new(start, end);
 
-        Mesh::new(
-            PrimitiveTopology::TriangleList,
-            RenderAssetUsages::RENDER_WORLD,
-        )
-        .with_inserted_indices(Indices::U32(indices))
-        .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
-        .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals)
-        .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, uvs)
+        let ramp = Brush::ramp(&bounds);
+        assert_eq!(ramp.faces.len(), 5);
+        assert_eq!(ramp.corners().len(), 6);
+        assert_eq!(ramp.edges().len(), 9);
+        assert_bounds(&ramp, start, end);
+
+        let pillar = Brush::pillar(&bounds);
+        assert_eq!(pillar.faces.len(), PILLAR_SIDES + 2);
+        assert_eq!(pillar.corners().len(), PILLAR_SIDES * 2);
+        assert_eq!(pillar.edges().len(), PILLAR_SIDES * 3);
+        assert_bounds(&pillar, start, end);
     }
-}
 
-impl Change for UpdateElemParams<Brush> {
-    fn apply_to_world(&self, world: &mut World) {
-        world
-            .run_system_cached_with(
-                |change: In<Self>,
-                 lookup: Res<ElementLookup>,
-                 map_assets: Res<MapAssets>,
-                 mut meshes: ResMut<Assets<Mesh>>,
-                 mut commands: Commands|
-                 -> Result {
-                    let entity_id = lookup.find(&change.elem_id)?;
-                    let mut entity = commands.entity(entity_id);
-                    //let mut entity = get_elem_entity(world, &self.elem_id).unwrap();
-                    let brush = change.params.clone();
-
-                    // Brush will use base entity as a container for sides.
-                    let center = brush.bounds.center();
-                    let size = brush.bounds.size();
-
-                    entity.insert((
-                        brush.clone(),
-                        Transform::IDENTITY.with_translation(center),
-                        RigidBody::Static,
-                        Collider::cuboid(size.x, size.y, size.z),
-                    ));
-                    entity.despawn_related::<Children>();
-                    entity.with_children(|cmds| {
-                        for side in brush.bounds.sides_local() {
-                            let mesh = meshes.add(side.mesh());
-                            let material = map_assets.default_material.clone();
-                            cmds.spawn((
-                                Transform::IDENTITY.with_translation(side.pos),
-                                Mesh3d(mesh),
-                                MeshMaterial3d(material),
-                            ));
-                        }
-                    });
-                    Ok(())
-                },
-                self.clone(),
-            )
-            .expect("error running system")
-            .expect("system returned an error");
+    #[test]
+    fn faces_cut_off_by_others_are_empty() {
+        let mut brush = cuboid(Vec3::ZERO, Vec3::ONE);
+        brush
+            .faces
+            .push(BrushFace::new(Vec3::X, vec3(5.0, 0.0, 0.0)));
+        assert!(brush.face_polygon(6).is_empty());
+        assert_bounds(&brush, Vec3::ZERO, Vec3::ONE);
+
+        // Bevels the edge along Z at x = 1, y = 1.
+        brush
+            .faces
+            .push(BrushFace::new(vec3(1.0, 1.0, 0.0), vec3(1.0, 0.5, 0.0)));
+        assert_eq!(brush.face_polygon(7).len(), 4);
+        assert_eq!(brush.corners().len(), 10);
+        assert!(brush
+            .corners()
+            .iter()
+            .all(|corner| corner.x + corner.y < 1.5 + 1e-3));
+    }
+
+    #[test]
+    fn open_brushes_are_rejected() {
+        let mut brush = cuboid(Vec3::ZERO, Vec3::ONE);
+        assert!(brush.is_closed());
+        assert!(brush.validate().is_ok());
+
+        brush.faces.pop();
+        assert!(!brush.is_closed());
+        assert!(brush.validate().is_err());
+    }
+
+    #[test]
+    fn clipping_keeps_the_part_behind_the_plane() {
+        let square = [
+            DVec3::new(-1.0, -1.0, 0.0),
+            DVec3::new(1.0, -1.0, 0.0),
+            DVec3::new(1.0, 1.0, 0.0),
+            DVec3::new(-1.0, 1.0, 0.0),
+        ];
+        let plane = |distance| BrushPlane {
+            normal: Vec3::X,
+            distance,
+        };
+
+        let half = clip_polygon(&square, &plane(0.0));
+        assert_eq!(half.len(), 4);
+        assert!(half.iter().all(|point| point.x <= 0.0));
+        assert!(half.contains(&DVec3::new(0.0, -1.0, 0.0)));
+        assert!(half.contains(&DVec3::new(0.0, 1.0, 0.0)));
+
+        assert_eq!(clip_polygon(&square, &plane(5.0)), square);
+        assert!(clip_polygon(&square, &plane(-5.0)).is_empty());
+    }
+
+    #[test]
+    fn moved_face_resizes() {
+        // Faces are in the order of CUBOID_FACINGS, 1 is +X.
+        let brush = cuboid(Vec3::ZERO, Vec3::ONE).moved_face(1, vec3(3.0, 0.5, 0.5));
+        assert_bounds(&brush, Vec3::ZERO, vec3(3.0, 1.0, 1.0));
+    }
+
+    #[test]
+    fn rotating_keeps_the_center() {
+        let brush =
+            cuboid(Vec3::ZERO, vec3(2.0, 1.0, 4.0)).rotated(Quat::from_rotation_y(FRAC_PI_2));
+        assert_bounds(&brush, vec3(-1.0, 0.0, 1.0), vec3(3.0, 1.0, 3.0));
+    }
+
+    #[test]
+    fn moving_corners_spans_a_new_brush() {
+        let surface = IdGen::default().generate();
+        let mut brush = cuboid(Vec3::ZERO, Vec3::ONE);
+        brush.faces[5].surface = Some(surface);
+
+        let top: Vec<Vec3> = brush
+            .corners()
+            .into_iter()
+            .filter(|corner| corner.y > 0.5)
+            .collect();
+        let taller = brush.with_moved_corners(&top, Vec3::Y).unwrap();
+        assert_eq!(taller.faces.len(), 6);
+        assert_bounds(&taller, Vec3::ZERO, vec3(1.0, 2.0, 1.0));
+        let top_face = taller
+            .faces
+            .iter()
+            .find(|face| face.plane.normal.abs_diff_eq(Vec3::Y, 1e-4))
+            .unwrap();
+        assert_eq!(top_face.surface, Some(surface));
+
+        let pulled = brush
+            .with_moved_corners(&[Vec3::ONE], Vec3::splat(0.5))
+            .unwrap();
+        assert_eq!(pulled.corners().len(), 8);
+        assert!(pulled
+            .corners()
+            .iter()
+            .any(|corner| corner.abs_diff_eq(Vec3::splat(1.5), 1e-3)));
+
+        // Pushing a corner in would leave it inside, the brush can't be concave.
+        assert!(brush
+            .with_moved_corners(&[Vec3::ONE], Vec3::splat(-0.5))
+            .is_none());
+        assert!(brush
+            .with_moved_corners(&[Vec3::splat(0.5)], Vec3::Y)
+            .is_none());
+    }
+
+    fn with_surfaces(mut brush: Brush) -> Brush {
+        let mut id_gen = IdGen::default();
+        for face in brush.faces.iter_mut() {
+            face.surface = Some(id_gen.generate());
+        }
+        brush
+    }
+
+    #[test]
+    fn carving_a_hole_leaves_the_boxes_around_it() {
+        let target = cuboid(Vec3::ZERO, Vec3::splat(4.0));
+        let cutter = with_surfaces(cuboid(vec3(1.0, -1.0, 1.0), vec3(3.0, 5.0, 3.0)));
+        let pieces = target.carved(&cutter).unwrap();
+
+        // Peeled off in the order of the cutter faces, there's nothing below or above the box.
+        let expected = [
+            (Vec3::ZERO, vec3(1.0, 4.0, 4.0)),
+            (vec3(3.0, 0.0, 0.0), Vec3::splat(4.0)),
+            (vec3(1.0, 0.0, 0.0), vec3(3.0, 4.0, 1.0)),
+            (vec3(1.0, 0.0, 3.0), vec3(3.0, 4.0, 4.0)),
+        ];
+        assert_eq!(pieces.len(), expected.len());
+        for (piece, (start, end)) in pieces.iter().zip(expected) {
+            assert_eq!(piece.faces.len(), 6);
+            assert_bounds(piece, start, end);
+        }
+
+        // The cut is lined with the flipped -X face of the cutter.
+        let cut = pieces[0]
+            .faces
+            .iter()
+            .find(|face| face.plane.normal.abs_diff_eq(Vec3::X, 1e-4))
+            .unwrap();
+        assert!((cut.plane.distance - 1.0).abs() < 1e-4);
+        assert_eq!(cut.surface, cutter.faces[0].surface);
+    }
+
+    #[test]
+    fn carved_pieces_have_no_coplanar_faces() {
+        // The notch starts at x = 0, so its -X face lies on the -X face of the target.
+        let target = cuboid(Vec3::ZERO, Vec3::splat(4.0));
+        let cutter = cuboid(vec3(0.0, 1.0, -1.0), vec3(2.0, 3.0, 5.0));
+        let pieces = target.carved(&cutter).unwrap();
+
+        let expected = [
+            (vec3(2.0, 0.0, 0.0), Vec3::splat(4.0)),
+            (Vec3::ZERO, vec3(2.0, 1.0, 4.0)),
+            (vec3(0.0, 3.0, 0.0), vec3(2.0, 4.0, 4.0)),
+        ];
+        assert_eq!(pieces.len(), expected.len());
+        for (piece, (start, end)) in pieces.iter().zip(expected) {
+            assert_eq!(piece.faces.len(), 6);
+            assert!(piece
+                .faces
+                .iter()
+                .tuple_combinations()
+                .all(|(a, b)| !a.plane.is_same(&b.plane)));
+            assert_bounds(piece, start, end);
+        }
+    }
+
+    #[test]
+    fn carving_needs_an_overlap() {
+        let target = cuboid(Vec3::ZERO, Vec3::ONE);
+        assert!(target
+            .carved(&cuboid(Vec3::splat(2.0), Vec3::splat(3.0)))
+            .is_none());
+        // Only touching isn't overlapping.
+        assert!(target
+            .carved(&cuboid(vec3(1.0, 0.0, 0.0), vec3(2.0, 1.0, 1.0)))
+            .is_none());
+        // Carving all of it leaves nothing.
+        assert_eq!(
+            target.carved(&cuboid(Vec3::splat(-1.0), Vec3::splat(2.0))),
+            Some(Vec::new())
+        );
     }
 }
@@ -1,7 +1,16 @@
 use bevy::prelude::*;
 use serde::{Deserialize, Serialize};
 
-use crate::core::map::changes::{get_elem_entity, Change, UpdateElemParams};
+use crate::{
+    core::map::{
+        changes::{
+            get_elem_component, get_elem_entity, require_elem, Change, ChangeError,
+            UpdateElemParams,
+        },
+        elements::Role,
+    },
+    id::Id,
+};
 
 #[derive(Component, Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Light {
@@ -19,8 +28,19 @@ pub enum LightType {
 }
 
 impl Change for UpdateElemParams<Light> {
-    fn apply_to_world(&self, world: &mut World) {
-        let mut entity = get_elem_entity(world, &self.elem_id).unwrap();
+    fn validate(&self, world: &World) -> Result<(), ChangeError> {
+        require_elem(world, &self.elem_id)?;
+        self.params
+            .validate()
+            .map_err(|reason| ChangeError::InvalidParams {
+                role: Light::id(),
+                reason,
+            })
+    }
+
+    fn apply_to_world(&self, world: &mut World) -> Result {
+        let mut entity = get_elem_entity(world, &self.elem_id)
+            .ok_or(ChangeError::MissingElement(self.elem_id))?;
         //entity.insert(self.params.clone());
         let light = self.params.clone();
         entity.insert((
@@ -34,9 +54,44 @@ impl Change for UpdateElemParams<Light> {
                     ..default()
                 },
                 LightType::Spot => {
-                    unimplemented!("u would have to rotate it n shit")
+                    return Err("spot lights aren't supported yet".into());
                 }
             },
         ));
+        Ok(())
+    }
+
+    fn describe(&self, world: &World) -> String {
+        let Some(old) = get_elem_component::<Light>(world, &self.elem_id) else {
+            return "Edit light".to_string();
+        };
+        let new = &self.params;
+        let edited = [
+            (old.light_type != new.light_type, "type"),
+            (old.color != new.color, "color"),
+            (old.intensity != new.intensity, "intensity"),
+            (old.range != new.range, "range"),
+        ]
+        .into_iter()
+        .filter_map(|(is_edited, field)| is_edited.then_some(field))
+        .collect::<Vec<_>>();
+
+        if edited.is_empty() {
+            if old.position != new.position {
+                "Move light".to_string()
+            } else {
+                "Edit light".to_string()
+            }
+        } else {
+            format!("Edit light {}", edited.join(", "))
+        }
+    }
+
+    fn verb(&self) -> &'static str {
+        "Edit"
+    }
+
+    fn elem_id(&self) -> Option<Id> {
+        Some(self.elem_id)
     }
 }
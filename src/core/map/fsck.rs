@@ -0,0 +1,436 @@
+use bevy::{
+    input::common_conditions::input_just_pressed,
+    platform::collections::{HashMap, HashSet},
+    prelude::*,
+};
+use redb::{ReadTransaction, ReadableTable};
+
+use crate::{
+    core::{
+        binds::Binding,
+        db::{
+            decode_records, try_decode_versioned, Checksum, Db, Meta, NotFound, Object, TBL_OBJECTS,
+        },
+        map::{
+            elements::ElementRoleRegistry,
+            history::HistNode,
+            migrations::{RAW_HIST_NODES, RAW_META, RAW_STATES},
+            states::StoredState,
+        },
+    },
+    id::Id,
+};
+
+/// A single inconsistency found in a map database.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Issue {
+    /// A record that can't be decoded, e.g. because it was damaged. The meta record has no id.
+    UndecodableRecord {
+        table: &'static str,
+        id: Option<Id>,
+        error: String,
+    },
+    /// `Meta.hist_node_id` points at a history node that doesn't exist.
+    MissingCurrentHistNode(Id),
+    MissingState {
+        hist_node_id: Id,
+        state_id: Id,
+    },
+    MissingParent {
+        hist_node_id: Id,
+        parent_id: Id,
+    },
+    /// The parent of a node doesn't list it as a child.
+    NotAChildOfParent {
+        hist_node_id: Id,
+        parent_id: Id,
+    },
+    MissingChild {
+        hist_node_id: Id,
+        child_id: Id,
+    },
+    /// A child of a node has a different parent.
+    NotAParentOfChild {
+        hist_node_id: Id,
+        child_id: Id,
+        child_parent_id: Option<Id>,
+    },
+    /// A delta state is based on a state that doesn't exist.
+    MissingBaseState {
+        state_id: Id,
+        parent_id: Id,
+    },
+    MissingObject {
+        state_id: Id,
+        elem_id: Id,
+        checksum: Checksum,
+    },
+    /// The stored data of an object doesn't hash to its key.
+    ChecksumMismatch {
+        checksum: Checksum,
+        actual: Checksum,
+    },
+    UnknownRole {
+        state_id: Id,
+        elem_id: Id,
+        role: u64,
+    },
+}
+
+impl std::fmt::Display for Issue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Issue::UndecodableRecord {
+                table,
+                id: Some(id),
+                error,
+            } => write!(f, "{} {} can't be decoded: {}", table, id, error),
+            Issue::UndecodableRecord {
+                table,
+                id: None,
+                error,
+            } => write!(f, "{} record can't be decoded: {}", table, error),
+            Issue::MissingCurrentHistNode(id) => {
+                write!(f, "current history node {} does not exist", id)
+            }
+            Issue::MissingState {
+                hist_node_id,
+                state_id,
+            } => write!(
+                f,
+                "history node {} refers to missing state {}",
+                hist_node_id, state_id
+            ),
+            Issue::MissingParent {
+                hist_node_id,
+                parent_id,
+            } => write!(
+                f,
+                "history node {} refers to missing parent {}",
+                hist_node_id, parent_id
+            ),
+            Issue::NotAChildOfParent {
+                hist_node_id,
+                parent_id,
+            } => write!(
+                f,
+                "history node {} is not listed as a child of its parent {}",
+                hist_node_id, parent_id
+            ),
+            Issue::MissingChild {
+                hist_node_id,
+                child_id,
+            } => write!(
+                f,
+                "history node {} refers to missing child {}",
+                hist_node_id, child_id
+            ),
+            Issue::NotAParentOfChild {
+                hist_node_id,
+                child_id,
+                child_parent_id,
+            } => write!(
+                f,
+                "history node {} lists {} as a child, but its parent is {:?}",
+                hist_node_id, child_id, child_parent_id
+            ),
+            Issue::MissingBaseState {
+                state_id,
+                parent_id,
+            } => write!(
+                f,
+                "state {} is a delta to missing state {}",
+                state_id, parent_id
+            ),
+            Issue::MissingObject {
+                state_id,
+                elem_id,
+                checksum,
+            } => write!(
+                f,
+                "element {} in state {} refers to missing object {}",
+                elem_id, state_id, checksum
+            ),
+            Issue::ChecksumMismatch { checksum, actual } => {
+                write!(f, "object {} hashes to {}", checksum, actual)
+            }
+            Issue::UnknownRole {
+                state_id,
+                elem_id,
+                role,
+            } => write!(
+                f,
+                "element {} in state {} has unknown role {:x}",
+                elem_id, state_id, role
+            ),
+        }
+    }
+}
+
+/// Result of checking a map database. The map is consistent if there are no issues.
+#[derive(Debug, Default, Clone)]
+pub struct FsckReport {
+    pub issues: Vec<Issue>,
+    pub hist_nodes: usize,
+    pub states: usize,
+    pub objects: usize,
+}
+
+impl FsckReport {
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+impl std::fmt::Display for FsckReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "checked {} history nodes, {} states and {} objects: {} issues",
+            self.hist_nodes,
+            self.states,
+            self.objects,
+            self.issues.len()
+        )
+    }
+}
+
+/// Checks the links between history nodes, states and objects of a map.
+pub fn check_map(reader: &ReadTransaction, registry: &ElementRoleRegistry) -> Result<FsckReport> {
+    let mut report = FsckReport::default();
+
+    // Damaged records are reported and left out, everything else is still checked.
+    let meta = reader
+        .open_table(RAW_META)?
+        .get(())?
+        .ok_or(NotFound)?
+        .value();
+    let current_hist_node_id = match try_decode_versioned::<Meta>(&meta) {
+        Ok(meta) => Some(meta.hist_node_id),
+        Err(err) => {
+            report.issues.push(Issue::UndecodableRecord {
+                table: "meta",
+                id: None,
+                error: err.to_string(),
+            });
+            None
+        }
+    };
+
+    let mut nodes: HashMap<Id, HistNode> = HashMap::new();
+    for (id, node) in decode_records(&reader.open_table(RAW_HIST_NODES)?)? {
+        match node {
+            Ok(node) => {
+                nodes.insert(id, node);
+            }
+            Err(err) => report.issues.push(Issue::UndecodableRecord {
+                table: "history node",
+                id: Some(id),
+                error: err.to_string(),
+            }),
+        }
+    }
+    report.hist_nodes = nodes.len();
+    if let Some(id) = current_hist_node_id.filter(|id| !nodes.contains_key(id)) {
+        report.issues.push(Issue::MissingCurrentHistNode(id));
+    }
+
+    let mut records: HashMap<Id, StoredState> = HashMap::new();
+    for (id, record) in decode_records(&reader.open_table(RAW_STATES)?)? {
+        match record {
+            Ok(record) => {
+                records.insert(id, record);
+            }
+            Err(err) => report.issues.push(Issue::UndecodableRecord {
+                table: "state",
+                id: Some(id),
+                error: err.to_string(),
+            }),
+        }
+    }
+    report.states = records.len();
+
+    // History nodes.
+    for (id, node) in nodes.iter() {
+        if !records.contains_key(&node.state_id) {
+            report.issues.push(Issue::MissingState {
+                hist_node_id: *id,
+                state_id: node.state_id,
+            });
+        }
+        if let Some(parent_id) = node.parent_id {
+            match nodes.get(&parent_id) {
+                None => report.issues.push(Issue::MissingParent {
+                    hist_node_id: *id,
+                    parent_id,
+                }),
+                Some(parent) if !parent.child_ids.contains(id) => {
+                    report.issues.push(Issue::NotAChildOfParent {
+                        hist_node_id: *id,
+                        parent_id,
+                    })
+                }
+                Some(_) => (),
+            }
+        }
+        for child_id in node.child_ids.iter() {
+            match nodes.get(child_id) {
+                None => report.issues.push(Issue::MissingChild {
+                    hist_node_id: *id,
+                    child_id: *child_id,
+                }),
+                Some(child) if child.parent_id != Some(*id) => {
+                    report.issues.push(Issue::NotAParentOfChild {
+                        hist_node_id: *id,
+                        child_id: *child_id,
+                        child_parent_id: child.parent_id,
+                    })
+                }
+                Some(_) => (),
+            }
+        }
+    }
+
+    // States and the objects they refer to.
+    let tbl_objects = reader.open_table(TBL_OBJECTS)?;
+    let mut checked_objects: HashSet<Checksum> = HashSet::new();
+    for (state_id, record) in records.iter() {
+        if let Some(parent_id) = record.parent_id() {
+            if !records.contains_key(&parent_id) {
+                report.issues.push(Issue::MissingBaseState {
+                    state_id: *state_id,
+                    parent_id,
+                });
+            }
+        }
+
+        for (elem_id, elem) in record.entries() {
+            if !registry.roles.contains_key(&elem.role) {
+                report.issues.push(Issue::UnknownRole {
+                    state_id: *state_id,
+                    elem_id: *elem_id,
+                    role: elem.role,
+                });
+            }
+
+            for checksum in [&elem.info, &elem.params] {
+                let Some(object) = tbl_objects.get(checksum)? else {
+                    report.issues.push(Issue::MissingObject {
+                        state_id: *state_id,
+                        elem_id: *elem_id,
+                        checksum: checksum.clone(),
+                    });
+                    continue;
+                };
+                if checked_objects.insert(checksum.clone()) {
+                    let actual = Object::checksum(&object.value().data);
+                    if &actual != checksum {
+                        report.issues.push(Issue::ChecksumMismatch {
+                            checksum: checksum.clone(),
+                            actual,
+                        });
+                    }
+                }
+            }
+        }
+    }
+    report.objects = checked_objects.len();
+
+    Ok(report)
+}
+
+/// Checks the open map and logs the report.
+#[derive(Event)]
+pub struct CheckMap;
+
+fn on_check_map(_: Trigger<CheckMap>, db: Res<Db>, registry: Res<ElementRoleRegistry>) -> Result {
+    let report = check_map(&db.begin_read()?, &registry)?;
+    for issue in report.issues.iter() {
+        warn!("Map check: {}", issue);
+    }
+    if report.is_ok() {
+        info!("Map check: {}", report);
+    } else {
+        warn!("Map check: {}", report);
+    }
+    Ok(())
+}
+
+fn trigger_check_map(mut commands: Commands) {
+    commands.trigger(CheckMap);
+}
+
+pub fn plugin(app: &mut App) {
+    app.add_observer(on_check_map);
+    app.add_systems(
+        Update,
+        trigger_check_map.run_if(resource_exists::<Db>.and(input_just_pressed(Binding::CheckMap))),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::{
+            db::{wrap_envelope, Versioned},
+            map::init_db,
+        },
+        id::IdGen,
+    };
+
+    #[test]
+    fn fresh_map_has_no_issues() -> Result {
+        let db = Db::in_memory()?;
+        init_db(&db, "test".to_string(), &mut IdGen::default())?;
+        let report = check_map(
+            &db.begin_read()?,
+            &ElementRoleRegistry::with_builtin_roles(),
+        )?;
+        assert!(report.is_ok(), "{:?}", report.issues);
+        assert_eq!((report.hist_nodes, report.states), (1, 1));
+        Ok(())
+    }
+
+    #[test]
+    fn damaged_records_are_listed() -> Result {
+        let db = Db::in_memory()?;
+        let mut id_gen = IdGen::default();
+        init_db(&db, "test".to_string(), &mut id_gen)?;
+
+        // Valid envelopes around payloads that don't decode.
+        let (hist_node_id, state_id) = (id_gen.generate(), id_gen.generate());
+        let writer = db.begin_write()?;
+        writer
+            .open_table(RAW_HIST_NODES)?
+            .insert(hist_node_id, wrap_envelope(HistNode::VERSION, &[0xFF]))?;
+        writer
+            .open_table(RAW_STATES)?
+            .insert(state_id, wrap_envelope(StoredState::VERSION, &[0xFF, 0xFF]))?;
+        writer.commit()?;
+
+        let report = check_map(
+            &db.begin_read()?,
+            &ElementRoleRegistry::with_builtin_roles(),
+        )?;
+        let undecodable: Vec<_> = report
+            .issues
+            .iter()
+            .filter_map(|issue| match issue {
+                Issue::UndecodableRecord { table, id, .. } => Some((*table, *id)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            undecodable,
+            [
+                ("history node", Some(hist_node_id)),
+                ("state", Some(state_id))
+            ]
+        );
+        // The intact records are still checked.
+        assert_eq!((report.hist_nodes, report.states), (1, 1));
+        assert_eq!(report.issues.len(), 2);
+        Ok(())
+    }
+}
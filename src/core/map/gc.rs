@@ -0,0 +1,481 @@
+use bevy::{
+    platform::collections::{HashMap, HashSet},
+    prelude::*,
+};
+use itertools::Itertools;
+use redb::ReadableTable;
+
+use crate::{
+    core::{
+        db::{Checksum, Db, NotFound, TBL_META, TBL_OBJECTS},
+        map::{
+            history::{ancestors, read_all_hist_nodes, HistNode, TBL_HIST_NODES},
+            states::{read_state, StoredState, TBL_STATES},
+        },
+    },
+    id::Id,
+};
+
+/// Decides which reachable history nodes survive a garbage collection.
+/// The current history node is always kept. When both limits are set, a node has to satisfy both.
+#[derive(Debug, Clone, Default)]
+pub struct RetentionPolicy {
+    /// Keep only this many of the most recent history nodes.
+    pub keep_last: Option<usize>,
+    /// Keep only history nodes with a timestamp at or after this unix timestamp.
+    pub keep_newer_than: Option<i64>,
+}
+
+impl RetentionPolicy {
+    /// Only removes rows that can't be reached from the current history node.
+    pub fn keep_all() -> Self {
+        Self::default()
+    }
+
+    fn retains(&self, node: &HistNode) -> bool {
+        self.keep_newer_than
+            .is_none_or(|timestamp| node.timestamp >= timestamp)
+    }
+}
+
+#[derive(Resource, Debug, Clone)]
+pub struct GcSettings {
+    /// Collect garbage (and compact the file) whenever a map is closed.
+    pub on_close: bool,
+    pub policy: RetentionPolicy,
+}
+
+impl Default for GcSettings {
+    fn default() -> Self {
+        Self {
+            on_close: true,
+            policy: RetentionPolicy::keep_all(),
+        }
+    }
+}
+
+/// Number of rows deleted by a garbage collection, per table.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct GcReport {
+    pub hist_nodes: usize,
+    pub states: usize,
+    pub objects: usize,
+}
+
+impl std::fmt::Display for GcReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "removed {} history nodes, {} states and {} objects",
+            self.hist_nodes, self.states, self.objects
+        )
+    }
+}
+
+/// Collect garbage on demand using the given policy.
+#[derive(Event)]
+pub struct CollectGarbage {
+    pub policy: RetentionPolicy,
+}
+
+fn reachable_from_root(nodes: &HashMap<Id, HistNode>, root: Id) -> HashSet<Id> {
+    let mut reachable = HashSet::new();
+    let mut stack = vec![root];
+    while let Some(id) = stack.pop() {
+        if let Some(node) = nodes.get(&id) {
+            if reachable.insert(id) {
+                stack.extend(node.child_ids.iter().copied());
+            }
+        }
+    }
+    reachable
+}
+
+/// Deletes history nodes that are unreachable or dropped by the retention policy, then every
+/// state and object that is no longer referenced by a remaining history node.
+///
+/// When old history is dropped, the closest common ancestor of all retained nodes becomes the
+/// new history root, so the history stays a single tree.
+pub fn collect_garbage(db: &Db, policy: &RetentionPolicy) -> Result<GcReport> {
+    let mut report = GcReport::default();
+    let writer = db.begin_write()?;
+    {
+        let meta = writer
+            .open_table(TBL_META)?
+            .get(())?
+            .ok_or(NotFound)?
+            .value();
+
+        // Step 1: figure out which history nodes to keep.
+        let mut tbl_hist = writer.open_table(TBL_HIST_NODES)?;
+        let nodes = read_all_hist_nodes(&tbl_hist)?;
+
+        let cur_chain = ancestors(&nodes, meta.hist_node_id);
+        let root = *cur_chain.last().unwrap();
+        let reachable = reachable_from_root(&nodes, root);
+
+        let mut retained: HashSet<Id> = reachable
+            .iter()
+            .map(|id| (*id, &nodes[id]))
+            .filter(|(_, node)| policy.retains(node))
+            .sorted_by_key(|(id, node)| std::cmp::Reverse((node.timestamp, *id)))
+            .take(policy.keep_last.unwrap_or(usize::MAX))
+            .map(|(id, _)| id)
+            .collect();
+        retained.insert(meta.hist_node_id);
+
+        // The new root is the deepest ancestor of the current node shared by all retained nodes.
+        let mut new_root_idx = 0;
+        for id in retained.iter() {
+            let chain: HashSet<Id> = ancestors(&nodes, *id).into_iter().collect();
+            let shared_idx = cur_chain
+                .iter()
+                .position(|ancestor| chain.contains(ancestor))
+                .unwrap_or(cur_chain.len() - 1);
+            new_root_idx = new_root_idx.max(shared_idx);
+        }
+        let new_root = cur_chain[new_root_idx];
+
+        // Keep the paths connecting retained nodes to the new root.
+        let mut kept: HashSet<Id> = HashSet::new();
+        for id in retained.iter() {
+            for ancestor in ancestors(&nodes, *id) {
+                if !kept.insert(ancestor) || ancestor == new_root {
+                    break;
+                }
+            }
+        }
+
+        // Step 2: rewrite links of kept nodes and delete the rest.
+        for (id, node) in nodes.iter() {
+            if kept.contains(id) {
+                let parent_id = if *id == new_root {
+                    None
+                } else {
+                    node.parent_id
+                };
+                let child_ids = node
+                    .child_ids
+                    .iter()
+                    .filter(|child_id| kept.contains(*child_id))
+                    .copied()
+                    .collect_vec();
+                let preferred_child = node.preferred_child.filter(|id| kept.contains(id));
+                if parent_id != node.parent_id
+                    || child_ids != node.child_ids
+                    || preferred_child != node.preferred_child
+                {
+                    tbl_hist.insert(
+                        id,
+                        HistNode {
+                            parent_id,
+                            child_ids,
+                            preferred_child,
+                            ..node.clone()
+                        },
+                    )?;
+                }
+            } else {
+                tbl_hist.remove(id)?;
+                report.hist_nodes += 1;
+            }
+        }
+
+        // Step 3: delete states not referenced by kept nodes, collecting objects along the way.
+        // Deltas whose parent state is about to be deleted are rewritten as keyframes first.
+        let live_state_ids: HashSet<Id> = kept.iter().map(|id| nodes[id].state_id).collect();
+        let mut tbl_states = writer.open_table(TBL_STATES)?;
+        let mut orphaned_deltas = Vec::new();
+        let mut dead_state_ids = Vec::new();
+        for entry in tbl_states.iter()? {
+            let (id, record) = entry?;
+            let id = id.value();
+            if live_state_ids.contains(&id) {
+                if record
+                    .value()
+                    .parent_id()
+                    .is_some_and(|parent_id| !live_state_ids.contains(&parent_id))
+                {
+                    orphaned_deltas.push(id);
+                }
+            } else {
+                dead_state_ids.push(id);
+            }
+        }
+        let resolved = orphaned_deltas
+            .into_iter()
+            .map(|id| read_state(&tbl_states, id).map(|state| (id, state)))
+            .collect::<Result<Vec<_>>>()?;
+        for (id, state) in resolved {
+            tbl_states.insert(id, StoredState::Keyframe(state))?;
+        }
+        for id in dead_state_ids {
+            tbl_states.remove(id)?;
+            report.states += 1;
+        }
+
+        let mut live_objects: HashSet<Checksum> = HashSet::new();
+        for entry in tbl_states.iter()? {
+            let (_, record) = entry?;
+            for elem in record.value().element_states() {
+                live_objects.insert(elem.info.clone());
+                live_objects.insert(elem.params.clone());
+            }
+        }
+
+        // Step 4: delete unreferenced objects.
+        let mut tbl_objects = writer.open_table(TBL_OBJECTS)?;
+        let mut dead_checksums = Vec::new();
+        for entry in tbl_objects.iter()? {
+            let (checksum, _) = entry?;
+            let checksum = checksum.value();
+            if !live_objects.contains(&checksum) {
+                dead_checksums.push(checksum);
+            }
+        }
+        for checksum in dead_checksums {
+            tbl_objects.remove(&checksum)?;
+            report.objects += 1;
+        }
+    }
+    writer.commit()?;
+
+    Ok(report)
+}
+
+fn on_collect_garbage(trigger: Trigger<CollectGarbage>, db: Res<Db>) -> Result {
+    let report = collect_garbage(&db, &trigger.policy)?;
+    info!("Collected garbage: {}", report);
+    Ok(())
+}
+
+pub fn plugin(app: &mut App) {
+    app.init_resource::<GcSettings>();
+    app.add_observer(on_collect_garbage);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::{
+            db::{Meta, Object},
+            map::states::{write_state, ElementState, MapState},
+        },
+        id::IdGen,
+    };
+
+    /// Adds a history node whose state adds one element, along with the objects of the element.
+    fn add_node(db: &Db, id_gen: &mut IdGen, parent_id: Option<Id>, timestamp: i64) -> Result<Id> {
+        let id = id_gen.generate();
+        let state_id = id_gen.generate();
+        let writer = db.begin_write()?;
+        {
+            let mut tbl_hist = writer.open_table(TBL_HIST_NODES)?;
+            let mut tbl_states = writer.open_table(TBL_STATES)?;
+            let mut tbl_objects = writer.open_table(TBL_OBJECTS)?;
+
+            let parent = match parent_id {
+                Some(parent_id) => {
+                    let node = tbl_hist.get(parent_id)?.ok_or(NotFound)?.value();
+                    let state = read_state(&tbl_states, node.state_id)?;
+                    Some((parent_id, node, state))
+                }
+                None => None,
+            };
+
+            let (info, info_obj) = Object::new_raw(format!("info of {}", id).into_bytes());
+            let (params, params_obj) = Object::new_raw(format!("params of {}", id).into_bytes());
+            tbl_objects.insert(&info, info_obj)?;
+            tbl_objects.insert(&params, params_obj)?;
+
+            let mut state = parent
+                .as_ref()
+                .map(|(_, _, state)| state.clone())
+                .unwrap_or_default();
+            state.elements.insert(
+                id_gen.generate(),
+                ElementState {
+                    role: 0,
+                    info,
+                    params,
+                },
+            );
+            write_state(
+                &mut tbl_states,
+                state_id,
+                parent
+                    .as_ref()
+                    .map(|(_, node, state)| (node.state_id, state)),
+                state,
+            )?;
+
+            tbl_hist.insert(
+                id,
+                HistNode {
+                    timestamp,
+                    parent_id,
+                    child_ids: Vec::new(),
+                    state_id,
+                    label: "Test change".to_string(),
+                    preferred_child: None,
+                },
+            )?;
+            if let Some((parent_id, mut node, _)) = parent {
+                node.child_ids.push(id);
+                tbl_hist.insert(parent_id, node)?;
+            }
+        }
+        writer.commit()?;
+        Ok(id)
+    }
+
+    fn set_current(db: &Db, hist_node_id: Id) -> Result {
+        let writer = db.begin_write()?;
+        writer.open_table(TBL_META)?.insert(
+            (),
+            Meta {
+                hist_node_id,
+                editor_context: default(),
+            },
+        )?;
+        writer.commit()?;
+        Ok(())
+    }
+
+    fn hist_node(db: &Db, id: Id) -> Result<Option<HistNode>> {
+        let reader = db.begin_read()?;
+        let node = reader.open_table(TBL_HIST_NODES)?.get(id)?;
+        Ok(node.map(|node| node.value()))
+    }
+
+    fn stored_state(db: &Db, hist_node_id: Id) -> Result<(StoredState, MapState)> {
+        let state_id = hist_node(db, hist_node_id)?.ok_or(NotFound)?.state_id;
+        let reader = db.begin_read()?;
+        let tbl_states = reader.open_table(TBL_STATES)?;
+        let record = tbl_states.get(state_id)?.ok_or(NotFound)?.value();
+        Ok((record, read_state(&tbl_states, state_id)?))
+    }
+
+    /// ```text
+    /// root(0) - a(1) - b(2) - c(5)
+    ///                \
+    ///                 x(3) - y(4)
+    /// ```
+    /// With c as the current node. Returns [root, a, b, c, x, y].
+    fn branched_history(db: &Db) -> Result<[Id; 6]> {
+        let mut id_gen = IdGen::default();
+        let root = add_node(db, &mut id_gen, None, 0)?;
+        let a = add_node(db, &mut id_gen, Some(root), 1)?;
+        let b = add_node(db, &mut id_gen, Some(a), 2)?;
+        let x = add_node(db, &mut id_gen, Some(a), 3)?;
+        let y = add_node(db, &mut id_gen, Some(x), 4)?;
+        let c = add_node(db, &mut id_gen, Some(b), 5)?;
+        set_current(db, c)?;
+        Ok([root, a, b, c, x, y])
+    }
+
+    #[test]
+    fn keep_all_keeps_the_whole_tree() -> Result {
+        let db = Db::in_memory()?;
+        let ids = branched_history(&db)?;
+
+        let report = collect_garbage(&db, &RetentionPolicy::keep_all())?;
+        assert_eq!(
+            (report.hist_nodes, report.states, report.objects),
+            (0, 0, 0)
+        );
+        for id in ids {
+            assert!(hist_node(&db, id)?.is_some());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn keep_last_reroots_at_the_shared_ancestor() -> Result {
+        let db = Db::in_memory()?;
+        let [root, a, b, c, x, y] = branched_history(&db)?;
+        let (_, state_c) = stored_state(&db, c)?;
+        let (_, state_y) = stored_state(&db, y)?;
+
+        // c and y are the newest, a is the closest node connecting them. The element added by root
+        // is still part of the other states, so no objects go.
+        let report = collect_garbage(
+            &db,
+            &RetentionPolicy {
+                keep_last: Some(2),
+                ..default()
+            },
+        )?;
+        assert_eq!(
+            (report.hist_nodes, report.states, report.objects),
+            (1, 1, 2)
+        );
+        assert!(hist_node(&db, root)?.is_none());
+        for id in [a, b, c, x, y] {
+            assert!(hist_node(&db, id)?.is_some());
+        }
+
+        let new_root = hist_node(&db, a)?.ok_or(NotFound)?;
+        assert_eq!(new_root.parent_id, None);
+        assert_eq!(new_root.child_ids, [b, x]);
+
+        // The state of a was a delta to the deleted root state.
+        let (record, _) = stored_state(&db, a)?;
+        assert!(matches!(record, StoredState::Keyframe(_)));
+        assert_eq!(stored_state(&db, c)?.1, state_c);
+        assert_eq!(stored_state(&db, y)?.1, state_y);
+        Ok(())
+    }
+
+    #[test]
+    fn keep_newer_than_drops_old_history_and_branches() -> Result {
+        let db = Db::in_memory()?;
+        let [root, a, b, c, x, y] = branched_history(&db)?;
+        let (_, state_c) = stored_state(&db, c)?;
+
+        let report = collect_garbage(
+            &db,
+            &RetentionPolicy {
+                keep_newer_than: Some(5),
+                ..default()
+            },
+        )?;
+        // Only c is left, its keyframe still refers to the elements added by root, a and b.
+        assert_eq!(
+            (report.hist_nodes, report.states, report.objects),
+            (5, 5, 4)
+        );
+        for id in [root, a, b, x, y] {
+            assert!(hist_node(&db, id)?.is_none());
+        }
+
+        let node = hist_node(&db, c)?.ok_or(NotFound)?;
+        assert_eq!(node.parent_id, None);
+        let (record, state) = stored_state(&db, c)?;
+        assert!(matches!(record, StoredState::Keyframe(_)));
+        assert_eq!(state, state_c);
+        Ok(())
+    }
+
+    #[test]
+    fn current_node_survives_any_policy() -> Result {
+        let db = Db::in_memory()?;
+        let [root, a, b, c, x, y] = branched_history(&db)?;
+        set_current(&db, b)?;
+
+        let report = collect_garbage(
+            &db,
+            &RetentionPolicy {
+                keep_last: Some(1),
+                keep_newer_than: Some(100),
+            },
+        )?;
+        assert_eq!(report.hist_nodes, 5);
+        assert!(hist_node(&db, b)?.is_some());
+        for id in [root, a, c, x, y] {
+            assert!(hist_node(&db, id)?.is_none());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,482 @@
+use bevy::{
+    color::palettes::css,
+    math::{ivec2, vec3},
+    platform::collections::HashSet,
+    prelude::*,
+};
+use itertools::Itertools;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::core::map::{
+    changes::{Change, ChangeSet, CreateElem, CreateId, PendingChanges},
+    elements::{
+        brush::{Brush, BrushBounds},
+        light::{Light, LightType},
+        Info,
+    },
+};
+
+/// Creates map elements procedurally. The same params, origin and seed always give the same
+/// elements, so generated content can be reproduced.
+pub trait Generator: Send + Sync + std::fmt::Debug {
+    fn name(&self) -> &'static str;
+    fn generate(&self, origin: Vec3, seed: u64) -> ChangeSet;
+}
+
+fn create_brush(name: String, bounds: BrushBounds) -> Box<dyn Change> {
+    Box::new(CreateElem {
+        id_mode: CreateId::Generated,
+        info: Info { name },
+        params: Brush::cuboid(&bounds),
+    })
+}
+
+fn create_light(name: String, position: Vec3) -> Box<dyn Change> {
+    Box::new(CreateElem {
+        id_mode: CreateId::Generated,
+        info: Info { name },
+        params: Light {
+            position,
+            light_type: LightType::Point,
+            color: Color::Srgba(css::WHITE),
+            intensity: 30000.0,
+            range: 20.0,
+        },
+    })
+}
+
+/// Walkable space on a grid in the XZ plane, turned into enclosing floor, ceiling and wall brushes.
+/// Neighbouring cells merge, so carving overlapping rooms and corridors gives a single space.
+struct FloorPlan {
+    cell_size: Vec2,
+    cells: HashSet<IVec2>,
+    /// Sides of cells that get no wall, as (cell, direction).
+    openings: HashSet<(IVec2, IVec2)>,
+}
+
+/// Runs of consecutive cells along X, as (z, first x, last x).
+fn runs_along_x(cells: impl Iterator<Item = IVec2>) -> Vec<(i32, i32, i32)> {
+    let mut runs = Vec::new();
+    for (z, row) in &cells
+        .sorted_by_key(|cell| (cell.y, cell.x))
+        .chunk_by(|cell| cell.y)
+    {
+        let mut xs = row.map(|cell| cell.x);
+        let Some(first) = xs.next() else {
+            continue;
+        };
+        let (mut start, mut end) = (first, first);
+        for x in xs {
+            if x != end + 1 {
+                runs.push((z, start, end));
+                start = x;
+            }
+            end = x;
+        }
+        runs.push((z, start, end));
+    }
+    runs
+}
+
+fn swap_xz(cell: IVec2) -> IVec2 {
+    ivec2(cell.y, cell.x)
+}
+
+impl FloorPlan {
+    fn new(cell_size: Vec2) -> Self {
+        Self {
+            cell_size,
+            cells: HashSet::new(),
+            openings: HashSet::new(),
+        }
+    }
+
+    /// Carves all cells between two corners, inclusive.
+    fn carve(&mut self, a: IVec2, b: IVec2) {
+        let (min, max) = (a.min(b), a.max(b));
+        for z in min.y..=max.y {
+            for x in min.x..=max.x {
+                self.cells.insert(ivec2(x, z));
+            }
+        }
+    }
+
+    fn open(&mut self, cell: IVec2, dir: IVec2) {
+        self.openings.insert((cell, dir));
+    }
+
+    fn is_carved(&self, cell: IVec2) -> bool {
+        self.cells.contains(&cell)
+    }
+
+    /// Cells whose neighbour in `dir` is solid, so they need a wall on that side.
+    fn edge_cells(&self, dir: IVec2) -> impl Iterator<Item = IVec2> + '_ {
+        self.cells.iter().copied().filter(move |cell| {
+            !self.is_carved(*cell + dir) && !self.openings.contains(&(*cell, dir))
+        })
+    }
+
+    /// Brushes enclosing the carved space. Cell (0, 0) starts at `origin`.
+    fn brushes(&self, origin: Vec3, height: f32, thickness: f32) -> Vec<BrushBounds> {
+        let (cx, cz) = (self.cell_size.x, self.cell_size.y);
+        let bounds = |min: Vec3, max: Vec3| BrushBounds::new(origin + min, origin + max);
+        let mut brushes = Vec::new();
+
+        for (z, x0, x1) in runs_along_x(self.cells.iter().copied()) {
+            let (min_x, max_x) = (x0 as f32 * cx, (x1 + 1) as f32 * cx);
+            let (min_z, max_z) = (z as f32 * cz, (z + 1) as f32 * cz);
+            brushes.push(bounds(
+                vec3(min_x, -thickness, min_z),
+                vec3(max_x, 0.0, max_z),
+            ));
+            brushes.push(bounds(
+                vec3(min_x, height, min_z),
+                vec3(max_x, height + thickness, max_z),
+            ));
+        }
+
+        // Walls along X also cover the corners where they meet walls along Z.
+        for (dir, wall_z) in [(IVec2::Y, 1), (IVec2::NEG_Y, 0)] {
+            for (z, x0, x1) in runs_along_x(self.edge_cells(dir)) {
+                let extend_start = if self.is_carved(ivec2(x0 - 1, z)) {
+                    0.0
+                } else {
+                    thickness
+                };
+                let extend_end = if self.is_carved(ivec2(x1 + 1, z)) {
+                    0.0
+                } else {
+                    thickness
+                };
+                let edge_z = (z + wall_z) as f32 * cz;
+                let outward = dir.y as f32 * thickness;
+                brushes.push(bounds(
+                    vec3(x0 as f32 * cx - extend_start, -thickness, edge_z),
+                    vec3(
+                        (x1 + 1) as f32 * cx + extend_end,
+                        height + thickness,
+                        edge_z + outward,
+                    ),
+                ));
+            }
+        }
+        for (dir, wall_x) in [(IVec2::X, 1), (IVec2::NEG_X, 0)] {
+            for (x, z0, z1) in runs_along_x(self.edge_cells(dir).map(swap_xz)) {
+                let edge_x = (x + wall_x) as f32 * cx;
+                let outward = dir.x as f32 * thickness;
+                brushes.push(bounds(
+                    vec3(edge_x, -thickness, z0 as f32 * cz),
+                    vec3(edge_x + outward, height + thickness, (z1 + 1) as f32 * cz),
+                ));
+            }
+        }
+
+        brushes
+    }
+}
+
+fn named_brushes(prefix: &str, brushes: Vec<BrushBounds>) -> Vec<Box<dyn Change>> {
+    brushes
+        .into_iter()
+        .enumerate()
+        .map(|(idx, bounds)| create_brush(format!("{} {}", prefix, idx + 1), bounds))
+        .collect()
+}
+
+/// Straight flight of solid steps, going up towards -Z from the origin.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Stairs {
+    pub steps: u32,
+    pub step_height: f32,
+    pub step_depth: f32,
+    pub width: f32,
+}
+
+impl Default for Stairs {
+    fn default() -> Self {
+        Self {
+            steps: 8,
+            step_height: 0.25,
+            step_depth: 0.3,
+            width: 1.5,
+        }
+    }
+}
+
+impl Generator for Stairs {
+    fn name(&self) -> &'static str {
+        "stairs"
+    }
+
+    fn generate(&self, origin: Vec3, _seed: u64) -> ChangeSet {
+        let half_width = self.width / 2.0;
+        let changes = (0..self.steps)
+            .map(|step| {
+                let bounds = BrushBounds::new(
+                    origin + vec3(-half_width, 0.0, -(step as f32) * self.step_depth),
+                    origin
+                        + vec3(
+                            half_width,
+                            (step + 1) as f32 * self.step_height,
+                            -((step + 1) as f32) * self.step_depth,
+                        ),
+                );
+                create_brush(format!("step {}", step + 1), bounds)
+            })
+            .collect();
+        ChangeSet { changes }
+    }
+}
+
+/// Closed box room with the origin in the middle of its floor.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Room {
+    /// Inner size.
+    pub size: Vec3,
+    pub wall_thickness: f32,
+}
+
+impl Default for Room {
+    fn default() -> Self {
+        Self {
+            size: vec3(6.0, 3.0, 6.0),
+            wall_thickness: 0.2,
+        }
+    }
+}
+
+impl Generator for Room {
+    fn name(&self) -> &'static str {
+        "room"
+    }
+
+    fn generate(&self, origin: Vec3, _seed: u64) -> ChangeSet {
+        let mut plan = FloorPlan::new(self.size.xz());
+        plan.carve(IVec2::ZERO, IVec2::ZERO);
+        let corner = origin - vec3(self.size.x, 0.0, self.size.z) / 2.0;
+        ChangeSet {
+            changes: named_brushes(
+                "room",
+                plan.brushes(corner, self.size.y, self.wall_thickness),
+            ),
+        }
+    }
+}
+
+/// Level corridor from the origin to `to`, going along X first and then along Z.
+/// Both ends are left open. The end snaps to a multiple of the width, height differences are
+/// ignored.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Corridor {
+    /// End of the corridor, relative to the origin.
+    pub to: Vec3,
+    pub width: f32,
+    pub height: f32,
+    pub wall_thickness: f32,
+}
+
+impl Default for Corridor {
+    fn default() -> Self {
+        Self {
+            to: vec3(10.0, 0.0, -10.0),
+            width: 2.0,
+            height: 2.5,
+            wall_thickness: 0.2,
+        }
+    }
+}
+
+impl Generator for Corridor {
+    fn name(&self) -> &'static str {
+        "corridor"
+    }
+
+    fn generate(&self, origin: Vec3, _seed: u64) -> ChangeSet {
+        let mut plan = FloorPlan::new(Vec2::splat(self.width));
+        let end = (self.to.xz() / self.width).round().as_ivec2();
+        plan.carve(IVec2::ZERO, ivec2(end.x, 0));
+        plan.carve(ivec2(end.x, 0), end);
+
+        // Leave the ends open, in the direction the corridor runs there.
+        if end != IVec2::ZERO {
+            let first_dir = match end.x {
+                0 => ivec2(0, end.y.signum()),
+                x => ivec2(x.signum(), 0),
+            };
+            let last_dir = match end.y {
+                0 => ivec2(end.x.signum(), 0),
+                z => ivec2(0, z.signum()),
+            };
+            plan.open(IVec2::ZERO, -first_dir);
+            plan.open(end, last_dir);
+        }
+
+        let brushes = plan.brushes(
+            origin - vec3(self.width, 0.0, self.width) / 2.0,
+            self.height,
+            self.wall_thickness,
+        );
+        ChangeSet {
+            changes: named_brushes("corridor", brushes),
+        }
+    }
+}
+
+/// Random rooms connected by corridors, with a light in every room.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Dungeon {
+    pub rooms: u32,
+    /// Size of a grid cell, everything is aligned to it.
+    pub cell_size: f32,
+    /// Size of the area rooms are placed in, in cells.
+    pub area: IVec2,
+    pub min_room_size: IVec2,
+    pub max_room_size: IVec2,
+    pub height: f32,
+    pub wall_thickness: f32,
+}
+
+impl Default for Dungeon {
+    fn default() -> Self {
+        Self {
+            rooms: 6,
+            cell_size: 2.0,
+            area: ivec2(24, 24),
+            min_room_size: ivec2(2, 2),
+            max_room_size: ivec2(5, 5),
+            height: 3.0,
+            wall_thickness: 0.2,
+        }
+    }
+}
+
+impl Generator for Dungeon {
+    fn name(&self) -> &'static str {
+        "dungeon"
+    }
+
+    fn generate(&self, origin: Vec3, seed: u64) -> ChangeSet {
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        // Params come straight from the editor, so sizes are clamped to ones that make sense:
+        // at least a cell, with rooms no larger than the area.
+        let area = self.area.max(IVec2::ONE);
+        let max_room_size = self.max_room_size.clamp(IVec2::ONE, area);
+        let min_room_size = self.min_room_size.clamp(IVec2::ONE, max_room_size);
+
+        // Rooms as (min, max) cells, kept a cell apart so they don't merge.
+        let mut rooms: Vec<(IVec2, IVec2)> = Vec::new();
+        for _ in 0..self.rooms * 10 {
+            if rooms.len() as u32 >= self.rooms {
+                break;
+            }
+            let size = ivec2(
+                rng.random_range(min_room_size.x..=max_room_size.x),
+                rng.random_range(min_room_size.y..=max_room_size.y),
+            );
+            let max_min = area - size;
+            let min = ivec2(
+                rng.random_range(0..=max_min.x),
+                rng.random_range(0..=max_min.y),
+            );
+            let max = min + size - IVec2::ONE;
+            let overlaps = rooms.iter().any(|(other_min, other_max)| {
+                min.cmple(*other_max + IVec2::ONE).all() && max.cmpge(*other_min - IVec2::ONE).all()
+            });
+            if !overlaps {
+                rooms.push((min, max));
+            }
+        }
+
+        let mut plan = FloorPlan::new(Vec2::splat(self.cell_size));
+        for (min, max) in rooms.iter().copied() {
+            plan.carve(min, max);
+        }
+        for ((a_min, a_max), (b_min, b_max)) in rooms.iter().copied().tuple_windows() {
+            let a = (a_min + a_max) / 2;
+            let b = (b_min + b_max) / 2;
+            let corner = if rng.random_bool(0.5) {
+                ivec2(b.x, a.y)
+            } else {
+                ivec2(a.x, b.y)
+            };
+            plan.carve(a, corner);
+            plan.carve(corner, b);
+        }
+
+        // Centered on the origin.
+        let corner = origin - vec3(area.x as f32, 0.0, area.y as f32) * self.cell_size / 2.0;
+        let mut changes = named_brushes(
+            "dungeon",
+            plan.brushes(corner, self.height, self.wall_thickness),
+        );
+        for (idx, (min, max)) in rooms.iter().enumerate() {
+            let center = (min.as_vec2() + max.as_vec2() + Vec2::ONE) / 2.0 * self.cell_size;
+            changes.push(create_light(
+                format!("dungeon light {}", idx + 1),
+                corner + vec3(center.x, self.height - 0.5, center.y),
+            ));
+        }
+        ChangeSet { changes }
+    }
+}
+
+/// Generators available in the editor, with their default params.
+#[derive(Resource)]
+pub struct GeneratorPresets {
+    pub generators: Vec<Box<dyn Generator>>,
+    pub selected: usize,
+}
+
+impl Default for GeneratorPresets {
+    fn default() -> Self {
+        Self {
+            generators: vec![
+                Box::new(Room::default()),
+                Box::new(Stairs::default()),
+                Box::new(Corridor::default()),
+                Box::new(Dungeon::default()),
+            ],
+            selected: 0,
+        }
+    }
+}
+
+impl GeneratorPresets {
+    pub fn selected(&self) -> &dyn Generator {
+        self.generators[self.selected].as_ref()
+    }
+
+    pub fn select_next(&mut self) {
+        self.selected = (self.selected + 1) % self.generators.len();
+    }
+}
+
+/// Runs the selected generator. Everything it creates is a single history step.
+#[derive(Event)]
+pub struct Generate {
+    pub origin: Vec3,
+    pub seed: u64,
+}
+
+fn generate(
+    trigger: Trigger<Generate>,
+    presets: Res<GeneratorPresets>,
+    mut pending: ResMut<PendingChanges>,
+) {
+    let generator = presets.selected();
+    let change_set = generator.generate(trigger.origin, trigger.seed);
+    info!(
+        "Generated {} with {} elements (seed {})",
+        generator.name(),
+        change_set.changes.len(),
+        trigger.seed
+    );
+    pending.push_set(change_set);
+}
+
+pub fn plugin(app: &mut App) {
+    app.init_resource::<GeneratorPresets>();
+    app.add_observer(generate);
+}
@@ -1,11 +1,13 @@
-use bevy::{input::common_conditions::input_just_pressed, prelude::*};
-use redb::TableDefinition;
+use bevy::{
+    input::common_conditions::input_just_pressed, platform::collections::HashMap, prelude::*,
+};
+use redb::{ReadableTable, TableDefinition};
 use serde::{Deserialize, Serialize};
 
 use crate::{
     core::{
         binds::Binding,
-        db::{Db, Meta, Typed, TBL_META},
+        db::{Db, Meta, NotFound, Typed, Versioned, TBL_META},
         map::states::RestoreState,
     },
     id::Id,
@@ -13,20 +15,78 @@ use crate::{
 
 pub const TBL_HIST_NODES: TableDefinition<Id, Typed<HistNode>> = TableDefinition::new("hist_nodes");
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct HistNode {
     pub timestamp: i64,
     pub parent_id: Option<Id>,
     pub child_ids: Vec<Id>,
     pub state_id: Id,
+    /// What the change leading to this node did, e.g. "Resize brush".
+    pub label: String,
+    /// Child to follow on redo. Points at the branch that was last visited.
+    pub preferred_child: Option<Id>,
+}
+
+impl Versioned for HistNode {
+    const VERSION: u16 = 1;
+}
+
+impl HistNode {
+    /// The child a redo should jump to, if any.
+    pub fn redo_child(&self) -> Option<Id> {
+        self.preferred_child
+            .filter(|id| self.child_ids.contains(id))
+            .or(self.child_ids.last().copied())
+    }
 }
 
 pub fn new_timestamp() -> i64 {
     time::OffsetDateTime::now_utc().unix_timestamp()
 }
 
+/// Formats a history timestamp as UTC date and time.
+pub fn format_timestamp(timestamp: i64) -> String {
+    match time::OffsetDateTime::from_unix_timestamp(timestamp) {
+        Ok(dt) => format!(
+            "{} {:02}:{:02}:{:02}",
+            dt.date(),
+            dt.hour(),
+            dt.minute(),
+            dt.second()
+        ),
+        Err(_) => timestamp.to_string(),
+    }
+}
+
+pub fn read_all_hist_nodes(
+    tbl_hist: &impl ReadableTable<Id, Typed<HistNode>>,
+) -> Result<HashMap<Id, HistNode>> {
+    let mut nodes = HashMap::new();
+    for entry in tbl_hist.iter()? {
+        let (id, node) = entry?;
+        nodes.insert(id.value(), node.value());
+    }
+    Ok(nodes)
+}
+
+/// Walks from a history node up to the root, including the node itself.
+pub fn ancestors(nodes: &HashMap<Id, HistNode>, id: Id) -> Vec<Id> {
+    let mut chain = vec![id];
+    let mut cur = id;
+    while let Some(parent_id) = nodes.get(&cur).and_then(|node| node.parent_id) {
+        // Guard against dangling parents and cycles in a broken history.
+        if !nodes.contains_key(&parent_id) || chain.contains(&parent_id) {
+            break;
+        }
+        chain.push(parent_id);
+        cur = parent_id;
+    }
+    chain
+}
+
+/// Restores the state of any history node and makes it the current one.
 #[derive(Event)]
-struct JumpToHistoryNode {
+pub struct JumpToHistoryNode {
     pub id: Id,
 }
 
@@ -35,53 +95,80 @@ fn jump_to_hist_node(
     db: Res<Db>,
     mut commands: Commands,
 ) -> Result {
-    let reader = db.begin_read()?;
-    let hist_node = reader
-        .open_table(TBL_HIST_NODES)?
-        .get(trigger.id)?
-        .unwrap()
-        .value();
-    commands.trigger(RestoreState {
-        id: hist_node.state_id,
-        fresh_map: false,
-    });
+    let writer = db.begin_write()?;
+    {
+        let meta = writer
+            .open_table(TBL_META)?
+            .get(())?
+            .ok_or(NotFound)?
+            .value();
+        let mut tbl_hist = writer.open_table(TBL_HIST_NODES)?;
+        let nodes = read_all_hist_nodes(&tbl_hist)?;
+        let hist_node = nodes.get(&trigger.id).ok_or(NotFound)?;
+        info!("Jumping to history node: {}", hist_node.label);
+        commands.trigger(RestoreState {
+            id: hist_node.state_id,
+            fresh_map: false,
+        });
+
+        // Remember the branch we came from when going back, and make the path leading to the
+        // target node the preferred one, so redo finds its way back here.
+        let mut preferred: Vec<(Id, Id)> = Vec::new();
+        if hist_node.child_ids.contains(&meta.hist_node_id) {
+            preferred.push((trigger.id, meta.hist_node_id));
+        }
+        let path = ancestors(&nodes, trigger.id);
+        for (child_id, parent_id) in path.iter().zip(path.iter().skip(1)) {
+            preferred.push((*parent_id, *child_id));
+        }
+        for (parent_id, child_id) in preferred {
+            let parent = &nodes[&parent_id];
+            if parent.preferred_child != Some(child_id) {
+                tbl_hist.insert(
+                    parent_id,
+                    HistNode {
+                        preferred_child: Some(child_id),
+                        ..parent.clone()
+                    },
+                )?;
+            }
+        }
+        writer.open_table(TBL_META)?.insert(
+            (),
+            Meta {
+                hist_node_id: trigger.id,
+                ..meta
+            },
+        )?;
+    }
+    writer.commit()?;
     commands.trigger(UpdateCurrentHistNode(trigger.id));
 
     Ok(())
 }
 
+/// Sent after the current history node moved. The db is already updated by then, this is only
+/// for refreshing views of the history.
 #[derive(Event)]
 pub struct UpdateCurrentHistNode(pub Id);
 
-fn update_cur_hist_node(trigger: Trigger<UpdateCurrentHistNode>, db: Res<Db>) -> Result {
-    let reader = db.begin_read()?;
-    let meta = reader.open_table(TBL_META)?.get(())?.unwrap().value();
-
-    let writer = db.begin_write()?;
-    writer.open_table(TBL_META)?.insert(
-        (),
-        Meta {
-            hist_node_id: trigger.0,
-            ..meta
-        },
-    )?;
-    writer.commit()?;
-    Ok(())
-}
-
 fn undo(db: Res<Db>, mut commands: Commands) -> Result {
     let reader = db.begin_read()?;
-    let meta = reader.open_table(TBL_META)?.get(())?.unwrap().value();
+    let meta = reader
+        .open_table(TBL_META)?
+        .get(())?
+        .ok_or(NotFound)?
+        .value();
     let cur_hist_node = reader
         .open_table(TBL_HIST_NODES)?
         .get(meta.hist_node_id)?
-        .unwrap()
+        .ok_or(NotFound)?
         .value();
     if let Some(parent_hist_node_id) = cur_hist_node.parent_id {
         commands.trigger(JumpToHistoryNode {
             id: parent_hist_node_id,
         });
-        info!("doing an undo");
+        info!("Undo: {}", cur_hist_node.label);
     } else {
         info!("not doing an undo - no parent on this hist node");
     }
@@ -90,31 +177,76 @@ fn undo(db: Res<Db>, mut commands: Commands) -> Result {
 
 fn redo(db: Res<Db>, mut commands: Commands) -> Result {
     let reader = db.begin_read()?;
-    let meta = reader.open_table(TBL_META)?.get(())?.unwrap().value();
-    let cur_hist_node = reader
-        .open_table(TBL_HIST_NODES)?
-        .get(meta.hist_node_id)?
-        .unwrap()
+    let meta = reader
+        .open_table(TBL_META)?
+        .get(())?
+        .ok_or(NotFound)?
         .value();
-    if let Some(last_child_if_hist_node) = cur_hist_node.child_ids.last() {
-        commands.trigger(JumpToHistoryNode {
-            id: *last_child_if_hist_node,
-        });
-        info!("doing a redo");
+    let tbl_hist = reader.open_table(TBL_HIST_NODES)?;
+    let cur_hist_node = tbl_hist.get(meta.hist_node_id)?.ok_or(NotFound)?.value();
+    if let Some(redo_child_id) = cur_hist_node.redo_child() {
+        let child_hist_node = tbl_hist.get(redo_child_id)?.ok_or(NotFound)?.value();
+        commands.trigger(JumpToHistoryNode { id: redo_child_id });
+        info!("Redo: {}", child_hist_node.label);
     } else {
         info!("not doing a redo - no children on this hist node");
     }
     Ok(())
 }
 
+/// Switches which branch the next redo follows, for nodes with more than one child.
+fn cycle_redo_branch(db: Res<Db>) -> Result {
+    let writer = db.begin_write()?;
+    {
+        let meta = writer
+            .open_table(TBL_META)?
+            .get(())?
+            .ok_or(NotFound)?
+            .value();
+        let mut tbl_hist = writer.open_table(TBL_HIST_NODES)?;
+        let cur_hist_node = tbl_hist.get(meta.hist_node_id)?.ok_or(NotFound)?.value();
+
+        let Some(redo_child_id) = cur_hist_node.redo_child() else {
+            info!("no redo branches on this hist node");
+            return Ok(());
+        };
+        let branch_count = cur_hist_node.child_ids.len();
+        let next_idx = cur_hist_node
+            .child_ids
+            .iter()
+            .position(|id| *id == redo_child_id)
+            .map(|idx| (idx + 1) % branch_count)
+            .unwrap_or(0);
+        let next_child_id = cur_hist_node.child_ids[next_idx];
+        let next_child = tbl_hist.get(next_child_id)?.ok_or(NotFound)?.value();
+        info!(
+            "Redo branch {}/{}: {}",
+            next_idx + 1,
+            branch_count,
+            next_child.label
+        );
+
+        tbl_hist.insert(
+            meta.hist_node_id,
+            HistNode {
+                preferred_child: Some(next_child_id),
+                ..cur_hist_node
+            },
+        )?;
+    }
+    writer.commit()?;
+    Ok(())
+}
+
 pub fn plugin(app: &mut App) {
     app.add_observer(jump_to_hist_node);
-    app.add_observer(update_cur_hist_node);
     app.add_systems(
         Update,
         (
             undo.run_if(input_just_pressed(Binding::Undo)),
             redo.run_if(input_just_pressed(Binding::Redo)),
-        ),
+            cycle_redo_branch.run_if(input_just_pressed(Binding::CycleRedoBranch)),
+        )
+            .run_if(resource_exists::<Db>),
     );
 }
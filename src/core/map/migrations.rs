@@ -0,0 +1,1105 @@
+use bevy::{platform::collections::HashMap, prelude::*};
+use redb::{
+    ReadTransaction, ReadableTable, ReadableTableMetadata, TableDefinition, TableError,
+    WriteTransaction,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    core::{
+        db::{
+            decode_records, has_envelope, split_envelope, try_decode_versioned, wrap_envelope,
+            Checksum, Db, Meta, NotFound, Object, RawTyped, SchemaError, Typed, Versioned,
+            TBL_OBJECTS,
+        },
+        map::{
+            elements::{
+                brush::{Brush, BrushBounds, SideUv, UvAlign, CUBOID_FACINGS},
+                ElementRoleRegistry, Info,
+            },
+            history::HistNode,
+            properties::MapProperties,
+            states::{ElementState, MapState, StateDelta, StoredState, TBL_STATES},
+        },
+    },
+    editor::EditorContext,
+    id::Id,
+    util::Facing3d,
+};
+
+/// States stored in full, before delta encoding was introduced.
+const TBL_LEGACY_STATES: TableDefinition<Id, RawTyped<MapState>> = TableDefinition::new("states");
+
+// The typed tables, read as raw bytes.
+pub const RAW_META: TableDefinition<(), RawTyped<Meta>> = TableDefinition::new("meta");
+pub const RAW_HIST_NODES: TableDefinition<Id, RawTyped<HistNode>> =
+    TableDefinition::new("hist_nodes");
+pub const RAW_STATES: TableDefinition<Id, RawTyped<StoredState>> =
+    TableDefinition::new("state_records");
+
+/// Turns a payload of one version into a payload of the next version.
+/// Envelopes are handled by `Migrations`, steps only see postcard data.
+type MigrateFn = fn(&[u8]) -> Result<Vec<u8>>;
+
+/// Upgrades stored values written by older editors, one version at a time.
+#[derive(Default)]
+pub struct Migrations {
+    steps: HashMap<&'static str, Vec<MigrateFn>>,
+}
+
+impl Migrations {
+    /// All migrations known to this editor.
+    ///
+    /// A step encodes the layout of the next version. While that is the current version, the
+    /// step can use the current type. Once the type changes again, freeze its old layout in
+    /// here as e.g. `HistNodeV1` and switch the step over to it.
+    pub fn builtin() -> Self {
+        let mut migrations = Self::default();
+        migrations.register::<Meta>(0, meta_v0_to_v1);
+        migrations.register::<HistNode>(0, hist_node_v0_to_v1);
+        migrations.register::<Brush>(0, brush_v0_to_v1);
+        migrations.register::<Brush>(1, brush_v1_to_v2);
+        migrations.register::<Brush>(2, brush_v2_to_v3);
+        migrations.register::<StoredState>(0, stored_state_v0_to_v1);
+        migrations.register::<StoredState>(1, stored_state_v1_to_v2);
+        migrations
+    }
+
+    /// Registers the step from `from_version` to `from_version + 1`.
+    /// Steps of a type have to be registered in order, starting at 0.
+    pub fn register<T: Versioned>(&mut self, from_version: u16, step: MigrateFn) {
+        let type_name = std::any::type_name::<T>();
+        let steps = self.steps.entry(type_name).or_default();
+        assert_eq!(
+            steps.len(),
+            from_version as usize,
+            "migrations for {} registered out of order",
+            type_name
+        );
+        steps.push(step);
+    }
+
+    /// Brings a stored value up to `current_version`.
+    /// Returns None if the value is already up to date.
+    pub fn upgrade(
+        &self,
+        type_name: &'static str,
+        current_version: u16,
+        data: &[u8],
+    ) -> Result<Option<Vec<u8>>> {
+        if !is_outdated(type_name, current_version, data)? {
+            return Ok(None);
+        }
+
+        let (version, payload) = split_envelope(data);
+        let mut payload = payload.to_vec();
+        for from_version in version..current_version {
+            let step = self
+                .steps
+                .get(type_name)
+                .and_then(|steps| steps.get(from_version as usize))
+                .ok_or(SchemaError::MissingMigration {
+                    type_name,
+                    version: from_version,
+                })?;
+            payload = step(&payload)?;
+        }
+        Ok(Some(wrap_envelope(current_version, &payload)))
+    }
+
+    pub fn upgrade_typed<T: Versioned>(&self, data: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.upgrade(std::any::type_name::<T>(), T::VERSION, data)
+    }
+}
+
+/// Whether a stored value was written by an older editor. Values from a newer editor are an error,
+/// they can't be read at all.
+fn is_outdated(type_name: &'static str, current_version: u16, data: &[u8]) -> Result<bool> {
+    let (version, _) = split_envelope(data);
+    if version > current_version {
+        return Err(SchemaError::FutureVersion {
+            type_name,
+            version,
+            supported: current_version,
+        }
+        .into());
+    }
+    Ok(version < current_version || !has_envelope(data))
+}
+
+/// A stored value that couldn't be upgraded, dropped while repairing a map.
+#[derive(Debug, Clone)]
+pub struct DroppedRecord {
+    pub table: String,
+    pub key: String,
+    pub reason: String,
+}
+
+/// What migrations do with stored values that can't be upgraded, e.g. because they are damaged.
+/// Values written by a newer editor aren't damaged, they always fail the migration.
+pub enum OnBroken<'a> {
+    /// Fail without touching the map.
+    Fail,
+    /// Drop them from the map and note them down, for repairing maps.
+    Drop(&'a mut Vec<DroppedRecord>),
+}
+
+impl OnBroken<'_> {
+    /// Passes the error on, or notes the value down so the caller drops it.
+    fn handle(&mut self, table: &str, key: impl std::fmt::Display, err: BevyError) -> Result {
+        match self {
+            OnBroken::Fail => Err(err),
+            OnBroken::Drop(dropped) => {
+                warn!("Dropping broken {} from {}: {}", key, table, err);
+                dropped.push(DroppedRecord {
+                    table: table.to_string(),
+                    key: key.to_string(),
+                    reason: err.to_string(),
+                });
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Converts states from maps created before delta encoding into keyframes.
+/// Has to run before `migrate_schema`, which only knows about the current states table.
+pub fn migrate_legacy_states(db: &Db, on_broken: &mut OnBroken) -> Result {
+    let reader = db.begin_read()?;
+    let has_legacy_states = reader.open_table(TBL_LEGACY_STATES).is_ok();
+    drop(reader);
+
+    if has_legacy_states {
+        let writer = db.begin_write()?;
+        let mut count = 0;
+        {
+            let tbl_legacy = writer.open_table(TBL_LEGACY_STATES)?;
+            let mut tbl_states = writer.open_table(TBL_STATES)?;
+            for entry in tbl_legacy.iter()? {
+                let (id, data) = entry?;
+                // Legacy states were never written with a version other than 0.
+                let (_, payload) = split_envelope(&data.value());
+                match postcard::from_bytes::<MapStateV0>(payload) {
+                    Ok(state) => {
+                        tbl_states
+                            .insert(id.value(), StoredState::Keyframe(state.upgrade().upgrade()))?;
+                        count += 1;
+                    }
+                    Err(err) => on_broken.handle("legacy states", id.value(), err.into())?,
+                }
+            }
+        }
+        writer.delete_table(TBL_LEGACY_STATES)?;
+        writer.commit()?;
+        info!("Migrated {} legacy map states to keyframes", count);
+    }
+    Ok(())
+}
+
+/// Number of stored values that were upgraded, per table.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MigrationReport {
+    pub meta: usize,
+    pub hist_nodes: usize,
+    pub states: usize,
+    pub objects: usize,
+    /// Values that couldn't be upgraded and were dropped, see `OnBroken::Drop`.
+    pub dropped: usize,
+}
+
+impl MigrationReport {
+    pub fn is_empty(&self) -> bool {
+        self.meta + self.hist_nodes + self.states + self.objects + self.dropped == 0
+    }
+}
+
+impl std::fmt::Display for MigrationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} meta records, {} history nodes, {} states and {} objects",
+            self.meta, self.hist_nodes, self.states, self.objects
+        )?;
+        if self.dropped > 0 {
+            write!(f, ", {} broken values dropped", self.dropped)?;
+        }
+        Ok(())
+    }
+}
+
+/// Types of the objects used by any state, as (type name, version). Objects of unknown roles are
+/// left out.
+fn object_schemas(
+    tbl_states: &impl ReadableTable<Id, Typed<StoredState>>,
+    registry: &ElementRoleRegistry,
+) -> Result<HashMap<Checksum, (&'static str, u16)>> {
+    let mut schemas = HashMap::new();
+    for entry in tbl_states.iter()? {
+        let (_, record) = entry?;
+        for elem in record.value().element_states() {
+            schemas.insert(
+                elem.info.clone(),
+                (std::any::type_name::<Info>(), Info::VERSION),
+            );
+            if let Some(builder) = registry.roles.get(&elem.role) {
+                schemas.insert(elem.params.clone(), builder.params_schema());
+            }
+        }
+    }
+    Ok(schemas)
+}
+
+/// Counts the outdated records of a typed table. A missing table has none.
+fn count_outdated<T>(
+    reader: &ReadTransaction,
+    definition: TableDefinition<Id, RawTyped<T>>,
+) -> Result<usize>
+where
+    T: std::fmt::Debug + Versioned + 'static,
+{
+    let table = match reader.open_table(definition) {
+        Ok(table) => table,
+        Err(TableError::TableDoesNotExist(_)) => return Ok(0),
+        Err(err) => return Err(err.into()),
+    };
+    let mut count = 0;
+    for entry in table.iter()? {
+        let (_, data) = entry?;
+        if is_outdated(std::any::type_name::<T>(), T::VERSION, &data.value())? {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Counts the stored values `migrate_legacy_states` and `migrate_schema` would upgrade, without
+/// writing anything. Fails if anything was written by a newer editor.
+pub fn pending_migrations(
+    reader: &ReadTransaction,
+    registry: &ElementRoleRegistry,
+) -> Result<MigrationReport> {
+    let mut report = MigrationReport::default();
+    match reader.open_table(TBL_LEGACY_STATES) {
+        Ok(tbl_legacy) => report.states += tbl_legacy.len()? as usize,
+        Err(TableError::TableDoesNotExist(_)) => (),
+        Err(err) => return Err(err.into()),
+    }
+
+    let meta = reader
+        .open_table(RAW_META)?
+        .get(())?
+        .ok_or(NotFound)?
+        .value();
+    if is_outdated(std::any::type_name::<Meta>(), Meta::VERSION, &meta)? {
+        report.meta += 1;
+    }
+    report.hist_nodes = count_outdated(reader, RAW_HIST_NODES)?;
+    report.states += count_outdated(reader, RAW_STATES)?;
+
+    // Object types are taken from the states, which can only be read once they are up to date.
+    if report.states == 0 {
+        if let Ok(tbl_states) = reader.open_table(TBL_STATES) {
+            let tbl_objects = reader.open_table(TBL_OBJECTS)?;
+            for (checksum, (type_name, version)) in object_schemas(&tbl_states, registry)? {
+                if let Some(object) = tbl_objects.get(&checksum)? {
+                    if is_outdated(type_name, version, &object.value().data)? {
+                        report.objects += 1;
+                    }
+                }
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Decodes the meta record, every history node and every state, failing on the first damaged one.
+/// Reading them through the typed tables would panic instead, so read-only tools run this first.
+pub fn verify_records(reader: &ReadTransaction) -> Result {
+    let meta = reader
+        .open_table(RAW_META)?
+        .get(())?
+        .ok_or(NotFound)?
+        .value();
+    try_decode_versioned::<Meta>(&meta).map_err(|err| format!("meta record: {}", err))?;
+    for (id, node) in decode_records(&reader.open_table(RAW_HIST_NODES)?)? {
+        node.map_err(|err| format!("history node {}: {}", id, err))?;
+    }
+    for (id, state) in decode_records(&reader.open_table(RAW_STATES)?)? {
+        state.map_err(|err| format!("state {}: {}", id, err))?;
+    }
+    Ok(())
+}
+
+/// Upgrades the records of a typed table. Returns the number of upgraded and dropped records.
+fn migrate_id_table<T>(
+    writer: &WriteTransaction,
+    definition: TableDefinition<Id, RawTyped<T>>,
+    migrations: &Migrations,
+    on_broken: &mut OnBroken,
+) -> Result<(usize, usize)>
+where
+    T: std::fmt::Debug + Versioned + 'static,
+{
+    let mut table = writer.open_table(definition)?;
+    let mut upgraded = Vec::new();
+    let mut broken = Vec::new();
+    for entry in table.iter()? {
+        let (id, data) = entry?;
+        let (id, data) = (id.value(), data.value());
+        is_outdated(std::any::type_name::<T>(), T::VERSION, &data)?;
+        let checked = migrations.upgrade_typed::<T>(&data).and_then(|new_data| {
+            try_decode_versioned::<T>(new_data.as_deref().unwrap_or(&data))?;
+            Ok(new_data)
+        });
+        match checked {
+            Ok(Some(new_data)) => upgraded.push((id, new_data)),
+            Ok(None) => (),
+            Err(err) => {
+                on_broken.handle(definition.name(), id, err)?;
+                broken.push(id);
+            }
+        }
+    }
+    let counts = (upgraded.len(), broken.len());
+    for (id, data) in upgraded {
+        table.insert(id, data)?;
+    }
+    for id in broken {
+        table.remove(id)?;
+    }
+    Ok(counts)
+}
+
+/// Upgrades every stored value of an opened map to the current schema, in a single transaction.
+/// Fails without touching the map if anything was written by a newer editor.
+///
+/// Records of typed tables are decoded once on the way, so a damaged record is reported here
+/// instead of crashing the editor later. `on_broken` decides whether damaged records and objects
+/// fail the migration or are dropped. The meta record can't be dropped, it always fails.
+///
+/// Objects are untyped, so their types are taken from the states referring to them. Upgrading an
+/// object changes its checksum, so states are rewritten to refer to the new checksums.
+pub fn migrate_schema(
+    db: &Db,
+    registry: &ElementRoleRegistry,
+    on_broken: &mut OnBroken,
+) -> Result<MigrationReport> {
+    let migrations = Migrations::builtin();
+    let mut report = MigrationReport::default();
+    let writer = db.begin_write()?;
+    {
+        let mut tbl_meta = writer.open_table(RAW_META)?;
+        let data = tbl_meta.get(())?.ok_or(NotFound)?.value();
+        let new_data = migrations.upgrade_typed::<Meta>(&data)?;
+        try_decode_versioned::<Meta>(new_data.as_deref().unwrap_or(&data))?;
+        if let Some(new_data) = new_data {
+            tbl_meta.insert((), new_data)?;
+            report.meta += 1;
+        }
+    }
+    let (hist_nodes, dropped_hist_nodes) =
+        migrate_id_table(&writer, RAW_HIST_NODES, &migrations, on_broken)?;
+    let (states, dropped_states) = migrate_id_table(&writer, RAW_STATES, &migrations, on_broken)?;
+    report.hist_nodes = hist_nodes;
+    report.states = states;
+    report.dropped = dropped_hist_nodes + dropped_states;
+
+    let object_schemas = object_schemas(&writer.open_table(TBL_STATES)?, registry)?;
+
+    let mut remapped: HashMap<Checksum, Checksum> = HashMap::new();
+    {
+        let mut tbl_objects = writer.open_table(TBL_OBJECTS)?;
+        for (checksum, (type_name, version)) in object_schemas {
+            // Missing objects are skipped, restoring a state that uses them reports them.
+            let data = match tbl_objects.get(&checksum)? {
+                Some(object) => object.value().data,
+                None => continue,
+            };
+            is_outdated(type_name, version, &data)?;
+            match migrations.upgrade(type_name, version, &data) {
+                Ok(Some(data)) => {
+                    let (new_checksum, object) = Object::new_raw(data);
+                    tbl_objects.remove(&checksum)?;
+                    tbl_objects.insert(&new_checksum, object)?;
+                    remapped.insert(checksum, new_checksum);
+                }
+                Ok(None) => (),
+                // States using a dropped object lose the element when the map is repaired.
+                Err(err) => {
+                    on_broken.handle("objects", &checksum, err)?;
+                    tbl_objects.remove(&checksum)?;
+                    report.dropped += 1;
+                }
+            }
+        }
+    }
+    report.objects = remapped.len();
+
+    if !remapped.is_empty() {
+        let mut tbl_states = writer.open_table(TBL_STATES)?;
+        let mut rewritten = Vec::new();
+        for entry in tbl_states.iter()? {
+            let (id, record) = entry?;
+            let mut record = record.value();
+            let mut changed = false;
+            for elem in record.element_states_mut() {
+                for checksum in [&mut elem.info, &mut elem.params] {
+                    if let Some(new_checksum) = remapped.get(checksum) {
+                        *checksum = new_checksum.clone();
+                        changed = true;
+                    }
+                }
+            }
+            if changed {
+                rewritten.push((id.value(), record));
+            }
+        }
+        for (id, record) in rewritten {
+            tbl_states.insert(id, record)?;
+        }
+    }
+
+    if report.is_empty() {
+        // Nothing to do, don't bother writing.
+        writer.abort()?;
+    } else {
+        writer.commit()?;
+    }
+    Ok(report)
+}
+
+/// Layout before the name moved into the map properties.
+#[derive(Serialize, Deserialize)]
+struct MetaV0 {
+    _name: String,
+    hist_node_id: Id,
+    editor_context: EditorContext,
+}
+
+/// The old name was either a placeholder or the file name, so it's dropped. Maps without a name
+/// show their file name.
+fn meta_v0_to_v1(payload: &[u8]) -> Result<Vec<u8>> {
+    let old: MetaV0 = postcard::from_bytes(payload)?;
+    Ok(postcard::to_stdvec(&Meta {
+        hist_node_id: old.hist_node_id,
+        editor_context: old.editor_context,
+    })?)
+}
+
+/// Layout before change labels and redo branch preferences were added.
+#[derive(Serialize, Deserialize)]
+struct HistNodeV0 {
+    timestamp: i64,
+    parent_id: Option<Id>,
+    child_ids: Vec<Id>,
+    state_id: Id,
+}
+
+fn hist_node_v0_to_v1(payload: &[u8]) -> Result<Vec<u8>> {
+    let old: HistNodeV0 = postcard::from_bytes(payload)?;
+    let label = match old.parent_id {
+        None => "Create map",
+        Some(_) => "Unnamed change",
+    };
+    Ok(postcard::to_stdvec(&HistNode {
+        timestamp: old.timestamp,
+        parent_id: old.parent_id,
+        child_ids: old.child_ids,
+        state_id: old.state_id,
+        label: label.to_string(),
+        preferred_child: None,
+    })?)
+}
+
+/// Layout before the role of an element became required.
+#[derive(Serialize, Deserialize)]
+struct ElementStateV0 {
+    role: Option<u64>,
+    info: Checksum,
+    params: Checksum,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MapStateV0 {
+    elements: HashMap<Id, ElementStateV0>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StateDeltaV0 {
+    parent_id: Id,
+    depth: u32,
+    added: HashMap<Id, ElementStateV0>,
+    changed: HashMap<Id, ElementStateV0>,
+    removed: Vec<Id>,
+}
+
+#[derive(Serialize, Deserialize)]
+enum StoredStateV0 {
+    Keyframe(MapStateV0),
+    Delta(StateDeltaV0),
+}
+
+/// Elements without a role could never be restored, so they are dropped.
+/// Returns the ids of the dropped elements.
+fn element_states_v0_to_v1(
+    elements: HashMap<Id, ElementStateV0>,
+) -> (HashMap<Id, ElementState>, Vec<Id>) {
+    let mut kept = HashMap::new();
+    let mut dropped = Vec::new();
+    for (id, elem) in elements {
+        match elem.role {
+            Some(role) => {
+                kept.insert(
+                    id,
+                    ElementState {
+                        role,
+                        info: elem.info,
+                        params: elem.params,
+                    },
+                );
+            }
+            None => {
+                warn!(
+                    "Dropping element {} from a stored state, it has no role",
+                    id
+                );
+                dropped.push(id);
+            }
+        }
+    }
+    (kept, dropped)
+}
+
+impl MapStateV0 {
+    fn upgrade(self) -> MapStateV1 {
+        MapStateV1 {
+            elements: element_states_v0_to_v1(self.elements).0,
+        }
+    }
+}
+
+fn stored_state_v0_to_v1(payload: &[u8]) -> Result<Vec<u8>> {
+    let old: StoredStateV0 = postcard::from_bytes(payload)?;
+    let new = match old {
+        StoredStateV0::Keyframe(state) => StoredStateV1::Keyframe(state.upgrade()),
+        StoredStateV0::Delta(delta) => {
+            let (added, _) = element_states_v0_to_v1(delta.added);
+            // A changed element without a role has to go, or the previous version would stick.
+            let (changed, dropped) = element_states_v0_to_v1(delta.changed);
+            StoredStateV1::Delta(StateDeltaV1 {
+                parent_id: delta.parent_id,
+                depth: delta.depth,
+                added,
+                changed,
+                removed: delta.removed.into_iter().chain(dropped).collect(),
+            })
+        }
+    };
+    Ok(postcard::to_stdvec(&new)?)
+}
+
+/// Layout before map properties were stored with the state.
+#[derive(Serialize, Deserialize)]
+struct MapStateV1 {
+    elements: HashMap<Id, ElementState>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StateDeltaV1 {
+    parent_id: Id,
+    depth: u32,
+    added: HashMap<Id, ElementState>,
+    changed: HashMap<Id, ElementState>,
+    removed: Vec<Id>,
+}
+
+#[derive(Serialize, Deserialize)]
+enum StoredStateV1 {
+    Keyframe(MapStateV1),
+    Delta(StateDeltaV1),
+}
+
+impl MapStateV1 {
+    fn upgrade(self) -> MapState {
+        MapState {
+            elements: self.elements,
+            properties: MapProperties::default(),
+        }
+    }
+}
+
+/// Keyframes get the default properties, deltas leave them as they are.
+fn stored_state_v1_to_v2(payload: &[u8]) -> Result<Vec<u8>> {
+    let old: StoredStateV1 = postcard::from_bytes(payload)?;
+    let new = match old {
+        StoredStateV1::Keyframe(state) => StoredState::Keyframe(state.upgrade()),
+        StoredStateV1::Delta(delta) => StoredState::Delta(StateDelta {
+            parent_id: delta.parent_id,
+            depth: delta.depth,
+            added: delta.added,
+            changed: delta.changed,
+            removed: delta.removed,
+            properties: None,
+        }),
+    };
+    Ok(postcard::to_stdvec(&new)?)
+}
+
+/// Layout of `BrushBounds` as stored by brushes up to V2.
+#[derive(Serialize, Deserialize)]
+struct BrushBoundsV0 {
+    start: Vec3,
+    end: Vec3,
+}
+
+/// Layout before brushes had surfaces.
+#[derive(Serialize, Deserialize)]
+struct BrushV0 {
+    bounds: BrushBoundsV0,
+}
+
+fn brush_v0_to_v1(payload: &[u8]) -> Result<Vec<u8>> {
+    let old: BrushV0 = postcard::from_bytes(payload)?;
+    Ok(postcard::to_stdvec(&BrushV1 {
+        bounds: old.bounds,
+        surfaces: default(),
+    })?)
+}
+
+/// Layout before brush sides had texture mapping.
+#[derive(Serialize, Deserialize)]
+struct BrushV1 {
+    bounds: BrushBoundsV0,
+    surfaces: PerSideV2<Option<Id>>,
+}
+
+/// Existing brushes keep the face aligned textures they were made with.
+fn brush_v1_to_v2(payload: &[u8]) -> Result<Vec<u8>> {
+    let old: BrushV1 = postcard::from_bytes(payload)?;
+    let uv = SideUvV2 {
+        offset: Vec2::ZERO,
+        scale: Vec2::ONE,
+        rotation: 0.0,
+        align: UvAlignV2::Face,
+    };
+    Ok(postcard::to_stdvec(&BrushV2 {
+        bounds: old.bounds,
+        surfaces: old.surfaces,
+        uvs: PerSideV2 {
+            x: uv,
+            neg_x: uv,
+            y: uv,
+            neg_y: uv,
+            z: uv,
+            neg_z: uv,
+        },
+    })?)
+}
+
+/// A value for each side of a box brush.
+#[derive(Serialize, Deserialize, Default)]
+struct PerSideV2<T> {
+    x: T,
+    neg_x: T,
+    y: T,
+    neg_y: T,
+    z: T,
+    neg_z: T,
+}
+
+impl<T> PerSideV2<T> {
+    fn get(&self, side: Facing3d) -> &T {
+        match side {
+            Facing3d::X => &self.x,
+            Facing3d::NegX => &self.neg_x,
+            Facing3d::Y => &self.y,
+            Facing3d::NegY => &self.neg_y,
+            Facing3d::Z => &self.z,
+            Facing3d::NegZ => &self.neg_z,
+        }
+    }
+}
+
+/// Layout of `UvAlign` as stored by V2 brushes.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+enum UvAlignV2 {
+    World,
+    Face,
+}
+
+/// Layout of `SideUv` as stored by V2 brushes.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+struct SideUvV2 {
+    offset: Vec2,
+    scale: Vec2,
+    rotation: f32,
+    align: UvAlignV2,
+}
+
+impl SideUvV2 {
+    fn upgrade(self) -> SideUv {
+        SideUv {
+            offset: self.offset,
+            scale: self.scale,
+            rotation: self.rotation,
+            align: match self.align {
+                UvAlignV2::World => UvAlign::World,
+                UvAlignV2::Face => UvAlign::Face,
+            },
+        }
+    }
+}
+
+/// Layout before brushes could be any convex shape, when they were all boxes.
+#[derive(Serialize, Deserialize)]
+struct BrushV2 {
+    bounds: BrushBoundsV0,
+    surfaces: PerSideV2<Option<Id>>,
+    uvs: PerSideV2<SideUvV2>,
+}
+
+/// Boxes become their six faces, keeping the surface and mapping of each side.
+fn brush_v2_to_v3(payload: &[u8]) -> Result<Vec<u8>> {
+    let old: BrushV2 = postcard::from_bytes(payload)?;
+    let mut brush = Brush::cuboid(&BrushBounds::new(old.bounds.start, old.bounds.end));
+    for (face, side) in brush.faces.iter_mut().zip(CUBOID_FACINGS) {
+        face.surface = *old.surfaces.get(side);
+        face.uv = old.uvs.get(side).upgrade();
+    }
+    Ok(postcard::to_stdvec(&brush)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::map::{elements::Role, init_db},
+        id::IdGen,
+    };
+
+    fn stored<T: Serialize>(version: u16, value: &T) -> Vec<u8> {
+        wrap_envelope(version, &postcard::to_stdvec(value).unwrap())
+    }
+
+    fn upgraded<T: Versioned>(data: &[u8]) -> Result<T> {
+        let data = Migrations::builtin()
+            .upgrade_typed::<T>(data)?
+            .ok_or("value is already up to date")?;
+        Ok(try_decode_versioned::<T>(&data)?)
+    }
+
+    fn ids<const N: usize>() -> [Id; N] {
+        let mut id_gen = IdGen::default();
+        std::array::from_fn(|_| id_gen.generate())
+    }
+
+    fn elem_v0(role: Option<u64>) -> ElementStateV0 {
+        ElementStateV0 {
+            role,
+            info: Object::checksum(b"info"),
+            params: Object::checksum(b"params"),
+        }
+    }
+
+    #[test]
+    fn meta_v0_drops_the_name() -> Result {
+        let [hist_node_id] = ids();
+        let meta: Meta = upgraded(&stored(
+            0,
+            &MetaV0 {
+                _name: "untitled".to_string(),
+                hist_node_id,
+                editor_context: default(),
+            },
+        ))?;
+        assert_eq!(meta.hist_node_id, hist_node_id);
+        Ok(())
+    }
+
+    #[test]
+    fn hist_node_v0_gets_a_label() -> Result {
+        let [root_id, child_id, state_id] = ids();
+        let root: HistNode = upgraded(&stored(
+            0,
+            &HistNodeV0 {
+                timestamp: 1,
+                parent_id: None,
+                child_ids: vec![child_id],
+                state_id,
+            },
+        ))?;
+        assert_eq!(root.label, "Create map");
+        assert_eq!(root.child_ids, [child_id]);
+        assert_eq!(root.preferred_child, None);
+
+        let child: HistNode = upgraded(&stored(
+            0,
+            &HistNodeV0 {
+                timestamp: 2,
+                parent_id: Some(root_id),
+                child_ids: Vec::new(),
+                state_id,
+            },
+        ))?;
+        assert_eq!(child.label, "Unnamed change");
+        assert_eq!(child.parent_id, Some(root_id));
+        Ok(())
+    }
+
+    #[test]
+    fn stored_state_v0_keyframe_drops_elements_without_role() -> Result {
+        let [kept, dropped] = ids();
+        let record: StoredState = upgraded(&stored(
+            0,
+            &StoredStateV0::Keyframe(MapStateV0 {
+                elements: [(kept, elem_v0(Some(7))), (dropped, elem_v0(None))]
+                    .into_iter()
+                    .collect(),
+            }),
+        ))?;
+        let StoredState::Keyframe(state) = record else {
+            panic!("expected a keyframe");
+        };
+        assert_eq!(state.elements.keys().collect::<Vec<_>>(), [&kept]);
+        assert_eq!(state.elements[&kept].role, 7);
+        assert_eq!(state.properties, MapProperties::default());
+        Ok(())
+    }
+
+    #[test]
+    fn stored_state_v0_delta_removes_elements_without_role() -> Result {
+        let [parent_id, added, changed, removed] = ids();
+        let record: StoredState = upgraded(&stored(
+            0,
+            &StoredStateV0::Delta(StateDeltaV0 {
+                parent_id,
+                depth: 3,
+                added: [(added, elem_v0(Some(7)))].into_iter().collect(),
+                changed: [(changed, elem_v0(None))].into_iter().collect(),
+                removed: vec![removed],
+            }),
+        ))?;
+        let StoredState::Delta(delta) = record else {
+            panic!("expected a delta");
+        };
+        assert_eq!((delta.parent_id, delta.depth), (parent_id, 3));
+        assert_eq!(delta.added.keys().collect::<Vec<_>>(), [&added]);
+        assert!(delta.changed.is_empty());
+        assert_eq!(delta.removed, [removed, changed]);
+        assert_eq!(delta.properties, None);
+        Ok(())
+    }
+
+    #[test]
+    fn brush_v0_becomes_a_face_aligned_box() -> Result {
+        let (start, end) = (vec3(-1.0, 0.0, -1.0), vec3(1.0, 2.0, 3.0));
+        let brush: Brush = upgraded(&stored(
+            0,
+            &BrushV0 {
+                bounds: BrushBoundsV0 { start, end },
+            },
+        ))?;
+
+        let mut expected = Brush::cuboid(&BrushBounds::new(start, end));
+        for face in expected.faces.iter_mut() {
+            face.uv.align = UvAlign::Face;
+        }
+        assert_eq!(brush, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn brush_v2_keeps_surfaces_and_mapping_per_side() -> Result {
+        let [x, neg_x, y, neg_y, z, neg_z] = ids();
+        let uv = |rotation| SideUvV2 {
+            offset: vec2(0.5, 0.0),
+            scale: Vec2::splat(2.0),
+            rotation,
+            align: UvAlignV2::World,
+        };
+        let brush: Brush = upgraded(&stored(
+            2,
+            &BrushV2 {
+                bounds: BrushBoundsV0 {
+                    start: Vec3::ZERO,
+                    end: Vec3::ONE,
+                },
+                surfaces: PerSideV2 {
+                    x: Some(x),
+                    neg_x: Some(neg_x),
+                    y: Some(y),
+                    neg_y: Some(neg_y),
+                    z: Some(z),
+                    neg_z: Some(neg_z),
+                },
+                uvs: PerSideV2 {
+                    x: uv(0.0),
+                    neg_x: uv(1.0),
+                    y: uv(2.0),
+                    neg_y: uv(3.0),
+                    z: uv(4.0),
+                    neg_z: uv(5.0),
+                },
+            },
+        ))?;
+
+        assert_eq!(brush.faces.len(), 6);
+        let surfaces = [
+            (x, 0.0),
+            (neg_x, 1.0),
+            (y, 2.0),
+            (neg_y, 3.0),
+            (z, 4.0),
+            (neg_z, 5.0),
+        ];
+        let facings = [
+            Facing3d::X,
+            Facing3d::NegX,
+            Facing3d::Y,
+            Facing3d::NegY,
+            Facing3d::Z,
+            Facing3d::NegZ,
+        ];
+        for (facing, (surface, rotation)) in facings.into_iter().zip(surfaces) {
+            let face = brush
+                .faces
+                .iter()
+                .find(|face| face.plane.normal == *facing.as_dir())
+                .unwrap();
+            assert_eq!(face.surface, Some(surface));
+            assert_eq!(face.uv, uv(rotation).upgrade());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn future_versions_are_rejected() {
+        let data = stored(Meta::VERSION + 1, &());
+        assert!(Migrations::builtin().upgrade_typed::<Meta>(&data).is_err());
+    }
+
+    #[test]
+    fn current_versions_are_left_alone() -> Result {
+        let data = stored(HistNode::VERSION, &());
+        assert_eq!(
+            Migrations::builtin().upgrade_typed::<HistNode>(&data)?,
+            None
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn legacy_states_become_keyframes() -> Result {
+        let db = Db::in_memory()?;
+        let [state_id, broken_id, elem_id] = ids();
+        let writer = db.begin_write()?;
+        {
+            let mut tbl_legacy = writer.open_table(TBL_LEGACY_STATES)?;
+            let state = MapStateV0 {
+                elements: [(elem_id, elem_v0(Some(7)))].into_iter().collect(),
+            };
+            tbl_legacy.insert(state_id, stored(0, &state))?;
+            tbl_legacy.insert(broken_id, vec![0xFF])?;
+        }
+        writer.commit()?;
+
+        let mut dropped = Vec::new();
+        migrate_legacy_states(&db, &mut OnBroken::Drop(&mut dropped))?;
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(dropped[0].key, broken_id.to_string());
+
+        let reader = db.begin_read()?;
+        assert!(reader.open_table(TBL_LEGACY_STATES).is_err());
+        let tbl_states = reader.open_table(TBL_STATES)?;
+        assert!(tbl_states.get(broken_id)?.is_none());
+        let StoredState::Keyframe(state) = tbl_states.get(state_id)?.ok_or(NotFound)?.value()
+        else {
+            panic!("expected a keyframe");
+        };
+        assert_eq!(state.elements.keys().collect::<Vec<_>>(), [&elem_id]);
+        Ok(())
+    }
+
+    #[test]
+    fn schema_migration_rewrites_object_checksums() -> Result {
+        let db = Db::in_memory()?;
+        let mut id_gen = IdGen::default();
+        init_db(&db, "test".to_string(), &mut id_gen)?;
+
+        let bounds = BrushBoundsV0 {
+            start: Vec3::ZERO,
+            end: Vec3::ONE,
+        };
+        let (old_checksum, old_object) = Object::new_raw(stored(0, &BrushV0 { bounds }));
+        let (state_id, elem_id) = (id_gen.generate(), id_gen.generate());
+        let writer = db.begin_write()?;
+        {
+            writer
+                .open_table(TBL_OBJECTS)?
+                .insert(&old_checksum, old_object)?;
+            let state = MapState {
+                elements: [(
+                    elem_id,
+                    ElementState {
+                        role: Brush::id_hash(),
+                        info: Checksum::nil(),
+                        params: old_checksum.clone(),
+                    },
+                )]
+                .into_iter()
+                .collect(),
+                ..default()
+            };
+            writer
+                .open_table(TBL_STATES)?
+                .insert(state_id, StoredState::Keyframe(state))?;
+        }
+        writer.commit()?;
+
+        let registry = ElementRoleRegistry::with_builtin_roles();
+        let pending = pending_migrations(&db.begin_read()?, &registry)?;
+        assert_eq!(pending.objects, 1);
+
+        let report = migrate_schema(&db, &registry, &mut OnBroken::Fail)?;
+        assert_eq!(report.objects, 1);
+        assert!(pending_migrations(&db.begin_read()?, &registry)?.is_empty());
+
+        let reader = db.begin_read()?;
+        let StoredState::Keyframe(state) = reader
+            .open_table(TBL_STATES)?
+            .get(state_id)?
+            .ok_or(NotFound)?
+            .value()
+        else {
+            panic!("expected a keyframe");
+        };
+        let new_checksum = &state.elements[&elem_id].params;
+        assert_ne!(new_checksum, &old_checksum);
+        let tbl_objects = reader.open_table(TBL_OBJECTS)?;
+        assert!(tbl_objects.get(&old_checksum)?.is_none());
+        let brush = tbl_objects
+            .get(new_checksum)?
+            .ok_or(NotFound)?
+            .value()
+            .cast::<Brush>()?;
+        assert_eq!(brush.faces.len(), 6);
+        Ok(())
+    }
+
+    #[test]
+    fn verifying_finds_damaged_records() -> Result {
+        let db = Db::in_memory()?;
+        let mut id_gen = IdGen::default();
+        init_db(&db, "test".to_string(), &mut id_gen)?;
+        verify_records(&db.begin_read()?)?;
+
+        // A valid envelope around a payload that isn't a history node.
+        let writer = db.begin_write()?;
+        writer
+            .open_table(RAW_HIST_NODES)?
+            .insert(id_gen.generate(), wrap_envelope(HistNode::VERSION, &[0xFF]))?;
+        writer.commit()?;
+        assert!(verify_records(&db.begin_read()?).is_err());
+        Ok(())
+    }
+}
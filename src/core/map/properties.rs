@@ -0,0 +1,190 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    core::{
+        map::{
+            changes::{Change, ChangeError},
+            states::{MapState, StateChange, StateSnapshot, SyncState},
+            MapAssets,
+        },
+        media::{surface::Surface, MediaCollection},
+        view::GimbalPos,
+    },
+    id::Id,
+};
+
+/// Map-wide settings. They are part of every map state, so editing them can be undone like any
+/// other change.
+#[derive(Resource, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct MapProperties {
+    /// Empty for maps made before the name was stored here, the file name is shown instead.
+    pub name: String,
+    pub author: String,
+    pub description: String,
+    pub gravity: Vec3,
+    /// Where players start, unless the game is started from the editor camera.
+    pub spawn: GimbalPos,
+    pub clear_color: Color,
+    pub ambient_color: Color,
+    pub ambient_brightness: f32,
+    /// Surface used for brushes. The built-in concrete is used when not set.
+    pub default_surface: Option<Id>,
+}
+
+impl Default for MapProperties {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            author: String::new(),
+            description: String::new(),
+            gravity: Vec3::NEG_Y * 9.81 * 2.0,
+            spawn: GimbalPos {
+                pos: Vec3::Y * 5.0,
+                rot: default(),
+            },
+            clear_color: Color::BLACK,
+            ambient_color: Color::WHITE,
+            ambient_brightness: 0.0,
+            default_surface: None,
+        }
+    }
+}
+
+impl MapProperties {
+    pub fn with_name(name: String) -> Self {
+        Self { name, ..default() }
+    }
+
+    pub fn validate(&self) -> Result<(), ChangeError> {
+        if self.ambient_brightness < 0.0 {
+            return Err(ChangeError::InvalidParams {
+                role: "map properties",
+                reason: "ambient brightness can't be negative".to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Replaces the map properties as a whole.
+#[derive(Debug, Clone)]
+pub struct UpdateMapProperties {
+    pub properties: MapProperties,
+}
+
+impl Change for UpdateMapProperties {
+    fn validate(&self, _world: &World) -> Result<(), ChangeError> {
+        self.properties.validate()
+    }
+
+    fn apply_to_world(&self, world: &mut World) -> Result {
+        world
+            .resource_mut::<MapProperties>()
+            .set_if_neq(self.properties.clone());
+        Ok(())
+    }
+
+    fn describe(&self, world: &World) -> String {
+        let old = world.resource::<MapProperties>();
+        let new = &self.properties;
+        let edited = [
+            (old.name != new.name, "name"),
+            (old.author != new.author, "author"),
+            (old.description != new.description, "description"),
+            (old.gravity != new.gravity, "gravity"),
+            (old.spawn != new.spawn, "spawn"),
+            (old.clear_color != new.clear_color, "sky color"),
+            (
+                old.ambient_color != new.ambient_color
+                    || old.ambient_brightness != new.ambient_brightness,
+                "ambient light",
+            ),
+            (
+                old.default_surface != new.default_surface,
+                "default surface",
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(is_edited, field)| is_edited.then_some(field))
+        .collect::<Vec<_>>();
+
+        if edited.is_empty() {
+            "Edit map properties".to_string()
+        } else {
+            format!("Edit map {}", edited.join(", "))
+        }
+    }
+
+    fn verb(&self) -> &'static str {
+        "Edit"
+    }
+
+    fn elem_id(&self) -> Option<Id> {
+        None
+    }
+}
+
+fn sync_properties(
+    properties: Res<MapProperties>,
+    state: Res<MapState>,
+    mut changes: EventWriter<StateChange>,
+) {
+    if state.properties != *properties {
+        changes.write(StateChange::SetProperties(properties.clone()));
+    }
+}
+
+fn apply_environment(properties: Res<MapProperties>, mut commands: Commands) {
+    commands.insert_resource(ClearColor(properties.clear_color));
+    commands.insert_resource(AmbientLight {
+        color: properties.ambient_color,
+        brightness: properties.ambient_brightness,
+        ..default()
+    });
+}
+
+/// Points brushes at the default surface of the map. Brushes only hold on to the material
+/// handle, so the ones using the previous default are swapped over.
+fn apply_default_surface(
+    properties: Res<MapProperties>,
+    surfaces: Res<MediaCollection<Surface>>,
+    mut map_assets: ResMut<MapAssets>,
+    mut q_materials: Query<&mut MeshMaterial3d<StandardMaterial>>,
+) {
+    let material = match properties.default_surface {
+        Some(id) => match surfaces.get(&id) {
+            Some(surface) => surface.content.handles.std_material.clone(),
+            None => {
+                warn!("Default surface {} not found, using the built-in one", id);
+                map_assets.base_material.clone()
+            }
+        },
+        None => map_assets.base_material.clone(),
+    };
+    if material == map_assets.default_material {
+        return;
+    }
+
+    for mut mesh_material in q_materials.iter_mut() {
+        if mesh_material.0 == map_assets.default_material {
+            mesh_material.0 = material.clone();
+        }
+    }
+    map_assets.default_material = material;
+}
+
+pub fn plugin(app: &mut App) {
+    app.init_resource::<MapProperties>();
+    app.add_systems(StateSnapshot, sync_properties.in_set(SyncState));
+    app.add_systems(
+        Update,
+        (
+            apply_environment.run_if(resource_changed::<MapProperties>),
+            apply_default_surface.run_if(resource_exists::<MapAssets>.and(
+                resource_changed::<MapProperties>.or(resource_changed::<MediaCollection<Surface>>),
+            )),
+        ),
+    );
+}
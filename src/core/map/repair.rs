@@ -0,0 +1,367 @@
+use bevy::{
+    platform::collections::{HashMap, HashSet},
+    prelude::*,
+};
+use itertools::Itertools;
+use redb::ReadableTable;
+
+use crate::{
+    core::{
+        db::{Checksum, Db, DbError, Meta, NotFound, Object, TBL_META, TBL_OBJECTS},
+        map::{
+            changes::CreateId,
+            elements::ElementRoleRegistry,
+            history::{read_all_hist_nodes, HistNode, TBL_HIST_NODES},
+            migrations::{migrate_legacy_states, migrate_schema, DroppedRecord, OnBroken},
+            states::{load_element, ElementState, MapState, StoredState, TBL_STATES},
+        },
+    },
+    id::Id,
+};
+
+/// An element that was removed from a state because it couldn't be loaded.
+#[derive(Debug, Clone)]
+pub struct DroppedElement {
+    pub state_id: Id,
+    pub elem_id: Id,
+    pub reason: String,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct RepairReport {
+    /// Stored values that couldn't be upgraded to the current schema.
+    pub records: Vec<DroppedRecord>,
+    /// Links to dropped records that were fixed, and how.
+    pub relinked: Vec<String>,
+    pub dropped: Vec<DroppedElement>,
+    pub states: usize,
+}
+
+impl std::fmt::Display for RepairReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "dropped {} broken records and {} elements from {} states, fixed {} links",
+            self.records.len(),
+            self.dropped.len(),
+            self.states,
+            self.relinked.len()
+        )
+    }
+}
+
+/// Finds elements that can't be put into the world, along with the reason.
+/// Storage errors aren't the element's fault and abort the repair instead.
+fn broken_elements(
+    objs: &impl ReadableTable<Checksum, Object>,
+    registry: &ElementRoleRegistry,
+    elements: &HashMap<Id, ElementState>,
+) -> Result<Vec<(Id, DbError)>> {
+    let mut broken = Vec::new();
+    for (elem_id, elem) in elements.iter() {
+        let loaded = load_element(objs, registry, *elem_id, elem).and_then(|loaded| {
+            // Building the change decodes the params.
+            loaded
+                .builder
+                .build_create(CreateId::Loaded(*elem_id), loaded.info, loaded.params)
+        });
+        match loaded {
+            Ok(_) => (),
+            Err(DbError::Storage(err)) => return Err(err.into()),
+            Err(err) => broken.push((*elem_id, err)),
+        }
+    }
+    Ok(broken)
+}
+
+/// Closest ancestor that is still there, skipping over dropped history nodes.
+fn closest_remaining(
+    nodes: &HashMap<Id, HistNode>,
+    dropped: &HashMap<Id, Option<Id>>,
+    mut id: Option<Id>,
+) -> Option<Id> {
+    // Bounded, in case the dropped nodes form a cycle.
+    for _ in 0..=dropped.len() {
+        match id {
+            Some(cur) if dropped.contains_key(&cur) => id = dropped[&cur],
+            _ => break,
+        }
+    }
+    id.filter(|id| nodes.contains_key(id))
+}
+
+/// Fixes what points at dropped records, so the map can be opened again:
+/// - Deltas based on a dropped state become keyframes of what the delta itself stored. Elements
+///   the dropped state carried over from before are lost.
+/// - History nodes without a state are dropped, their children move to the closest remaining
+///   ancestor.
+/// - If the current history node is gone, the closest remaining ancestor or the newest node
+///   becomes the current one.
+fn relink(db: &Db, relinked: &mut Vec<String>) -> Result {
+    let writer = db.begin_write()?;
+    {
+        let mut tbl_states = writer.open_table(TBL_STATES)?;
+        let mut records: HashMap<Id, StoredState> = HashMap::new();
+        for entry in tbl_states.iter()? {
+            let (id, record) = entry?;
+            records.insert(id.value(), record.value());
+        }
+        let state_ids: HashSet<Id> = records.keys().copied().collect();
+        for (state_id, record) in records {
+            let StoredState::Delta(delta) = record else {
+                continue;
+            };
+            if state_ids.contains(&delta.parent_id) {
+                continue;
+            }
+            relinked.push(format!(
+                "state {}: base state {} is gone, kept what it changed as a keyframe",
+                state_id, delta.parent_id
+            ));
+            let state = MapState {
+                elements: delta.added.into_iter().chain(delta.changed).collect(),
+                properties: delta.properties.unwrap_or_default(),
+            };
+            tbl_states.insert(state_id, StoredState::Keyframe(state))?;
+        }
+
+        let mut tbl_hist = writer.open_table(TBL_HIST_NODES)?;
+        let mut nodes = read_all_hist_nodes(&tbl_hist)?;
+        // Dropped nodes with their parent, so their children can be moved up.
+        let mut dropped: HashMap<Id, Option<Id>> = HashMap::new();
+        nodes.retain(|id, node| {
+            let keep = state_ids.contains(&node.state_id);
+            if !keep {
+                relinked.push(format!(
+                    "history node {}: state {} is gone, dropped the node",
+                    id, node.state_id
+                ));
+                dropped.insert(*id, node.parent_id);
+            }
+            keep
+        });
+        for id in dropped.keys() {
+            tbl_hist.remove(id)?;
+        }
+
+        let parents: HashMap<Id, Option<Id>> = nodes
+            .iter()
+            .map(|(id, node)| (*id, closest_remaining(&nodes, &dropped, node.parent_id)))
+            .collect();
+        for (id, node) in nodes.iter() {
+            let parent_id = parents[id];
+            if let Some(old_parent_id) = node.parent_id.filter(|_| node.parent_id != parent_id) {
+                relinked.push(match parent_id {
+                    Some(parent_id) => format!(
+                        "history node {}: parent {} is gone, moved to {}",
+                        id, old_parent_id, parent_id
+                    ),
+                    None => format!(
+                        "history node {}: parent {} is gone, it's a root now",
+                        id, old_parent_id
+                    ),
+                });
+            }
+            // Children that moved here go last, oldest first.
+            let mut child_ids: Vec<Id> = node
+                .child_ids
+                .iter()
+                .copied()
+                .filter(|child_id| parents.get(child_id) == Some(&Some(*id)))
+                .collect();
+            let moved: Vec<Id> = nodes
+                .iter()
+                .filter(|(child_id, _)| {
+                    parents[*child_id] == Some(*id) && !child_ids.contains(*child_id)
+                })
+                .sorted_by_key(|(_, child)| child.timestamp)
+                .map(|(child_id, _)| *child_id)
+                .collect();
+            child_ids.extend(moved);
+            let preferred_child = node
+                .preferred_child
+                .filter(|child_id| child_ids.contains(child_id));
+
+            if parent_id != node.parent_id
+                || child_ids != node.child_ids
+                || preferred_child != node.preferred_child
+            {
+                tbl_hist.insert(
+                    id,
+                    HistNode {
+                        parent_id,
+                        child_ids,
+                        preferred_child,
+                        ..node.clone()
+                    },
+                )?;
+            }
+        }
+
+        let meta = writer
+            .open_table(TBL_META)?
+            .get(())?
+            .ok_or(NotFound)?
+            .value();
+        if !nodes.contains_key(&meta.hist_node_id) {
+            let hist_node_id = closest_remaining(&nodes, &dropped, Some(meta.hist_node_id))
+                .or_else(|| {
+                    nodes
+                        .iter()
+                        .max_by_key(|(_, node)| node.timestamp)
+                        .map(|(id, _)| *id)
+                })
+                .ok_or("no history node is left to open the map at")?;
+            relinked.push(format!(
+                "current history node {} is gone, moved to {}",
+                meta.hist_node_id, hist_node_id
+            ));
+            writer.open_table(TBL_META)?.insert(
+                (),
+                Meta {
+                    hist_node_id,
+                    ..meta
+                },
+            )?;
+        }
+    }
+    writer.commit()?;
+    Ok(())
+}
+
+/// Drops every element that can't be loaded from all stored states, so the map opens again.
+///
+/// The map is upgraded to the current schema first. Records and objects that can't be upgraded
+/// would fail that, so they are dropped too. Elements using a dropped object are then dropped
+/// like any other broken element.
+///
+/// Links to dropped records are fixed as described in `relink`.
+///
+/// Broken elements in keyframes are removed. In deltas, broken additions are removed and broken
+/// changes turn into removals, so the element disappears from that state on.
+pub fn repair_map(db: &Db, registry: &ElementRoleRegistry) -> Result<RepairReport> {
+    let mut report = RepairReport::default();
+    migrate_legacy_states(db, &mut OnBroken::Drop(&mut report.records))?;
+    migrate_schema(db, registry, &mut OnBroken::Drop(&mut report.records))?;
+    relink(db, &mut report.relinked)?;
+
+    let writer = db.begin_write()?;
+    {
+        let objs = writer.open_table(TBL_OBJECTS)?;
+        let mut tbl_states = writer.open_table(TBL_STATES)?;
+
+        let mut repaired = Vec::new();
+        for entry in tbl_states.iter()? {
+            let (state_id, record) = entry?;
+            let state_id = state_id.value();
+            let mut record = record.value();
+
+            let mut dropped = Vec::new();
+            match &mut record {
+                StoredState::Keyframe(state) => {
+                    for (elem_id, err) in broken_elements(&objs, registry, &state.elements)? {
+                        state.elements.remove(&elem_id);
+                        dropped.push((elem_id, err));
+                    }
+                }
+                StoredState::Delta(delta) => {
+                    for (elem_id, err) in broken_elements(&objs, registry, &delta.added)? {
+                        delta.added.remove(&elem_id);
+                        dropped.push((elem_id, err));
+                    }
+                    for (elem_id, err) in broken_elements(&objs, registry, &delta.changed)? {
+                        delta.changed.remove(&elem_id);
+                        delta.removed.push(elem_id);
+                        dropped.push((elem_id, err));
+                    }
+                }
+            }
+
+            if !dropped.is_empty() {
+                report
+                    .dropped
+                    .extend(dropped.into_iter().map(|(elem_id, err)| DroppedElement {
+                        state_id,
+                        elem_id,
+                        reason: err.to_string(),
+                    }));
+                repaired.push((state_id, record));
+            }
+        }
+
+        report.states = repaired.len();
+        for (state_id, record) in repaired {
+            tbl_states.insert(state_id, record)?;
+        }
+    }
+    writer.commit()?;
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::{
+            db::{wrap_envelope, Versioned},
+            map::{
+                edits::{EditCommand, EditScript},
+                fsck::check_map,
+                get_current_hist_node, get_current_state, init_db,
+                migrations::RAW_STATES,
+                properties::MapProperties,
+            },
+        },
+        id::IdGen,
+    };
+
+    #[test]
+    fn dropped_states_are_unlinked() -> Result {
+        let db = Db::in_memory()?;
+        let mut id_gen = IdGen::default();
+        init_db(&db, "test".to_string(), &mut id_gen)?;
+        let registry = ElementRoleRegistry::with_builtin_roles();
+        let mut rename = |name: &str| {
+            EditScript(vec![EditCommand::SetProperties {
+                properties: MapProperties::with_name(name.to_string()),
+            }])
+            .apply_to_map(&db, &registry, &mut id_gen, name)
+        };
+        let first = rename("first")?;
+        let middle = rename("middle")?;
+        let last = rename("last")?;
+
+        // Damage the state in the middle of the chain, the last state is a delta to it.
+        let middle_state_id = get_hist_node(&db, middle)?.state_id;
+        let writer = db.begin_write()?;
+        writer.open_table(RAW_STATES)?.insert(
+            middle_state_id,
+            wrap_envelope(StoredState::VERSION, &[0xFF, 0xFF]),
+        )?;
+        writer.commit()?;
+
+        let report = repair_map(&db, &registry)?;
+        assert_eq!(report.records.len(), 1);
+        assert_eq!(report.relinked.len(), 3, "{:?}", report.relinked);
+
+        let reader = db.begin_read()?;
+        let current = get_current_hist_node(&reader)?;
+        assert_eq!(current.label, "last");
+        assert_eq!(current.parent_id, Some(first));
+        assert_eq!(get_current_state(&reader)?.properties.name, "last");
+        assert_eq!(get_hist_node(&db, first)?.child_ids, [last]);
+        let fsck = check_map(&reader, &registry)?;
+        assert!(fsck.is_ok(), "{:?}", fsck.issues);
+        Ok(())
+    }
+
+    fn get_hist_node(db: &Db, id: Id) -> Result<HistNode> {
+        Ok(db
+            .begin_read()?
+            .open_table(TBL_HIST_NODES)?
+            .get(id)?
+            .ok_or(NotFound)?
+            .value())
+    }
+}
@@ -0,0 +1,301 @@
+use std::path::{Path, PathBuf};
+
+use bevy::{input::common_conditions::input_just_pressed, prelude::*};
+
+use crate::{
+    app_data::AppDataPath,
+    core::{
+        binds::{Binding, InputBindingSystem},
+        db::Db,
+        map::{
+            changes::PendingChanges,
+            db_is_initialized,
+            elements::{ElementId, ElementRoleRegistry},
+            gc::{collect_garbage, GcSettings},
+            get_current_hist_node, get_current_meta, get_current_state,
+            history::new_timestamp,
+            init_db,
+            migrations::{migrate_legacy_states, migrate_schema, OnBroken},
+            properties::MapProperties,
+            states::{restore_state_in_world, MapState},
+            ElementLookup,
+        },
+        view::TPCameraTo,
+        AppState,
+    },
+    editor::update_editor_context,
+    id::IdGen,
+};
+
+pub const MAP_FILE_EXT: &str = "mmap";
+
+/// The map file currently being edited. Only exists while a map is open.
+#[derive(Resource, Debug, Clone)]
+pub struct MapSession {
+    pub path: PathBuf,
+}
+
+/// Map file to open on startup, e.g. from the command line.
+/// When not set, `map.mmap` in the app data directory is used.
+#[derive(Resource, Debug, Clone, Default)]
+pub struct StartupMap(pub Option<PathBuf>);
+
+/// Closes the current map (if any) and opens the map file at the given path.
+/// The file is created if it doesn't exist.
+#[derive(Event)]
+pub struct OpenMap {
+    pub path: PathBuf,
+}
+
+/// Closes the current map (if any) and creates a new, empty map file at the given path.
+#[derive(Event)]
+pub struct NewMap {
+    pub path: PathBuf,
+}
+
+/// Copies the current map file to the given path and continues editing the copy.
+#[derive(Event)]
+pub struct SaveMapAs {
+    pub path: PathBuf,
+}
+
+#[derive(Event)]
+pub struct CloseMap;
+
+/// Fired after a map has been closed and all of its elements are gone.
+#[derive(Event)]
+pub struct MapClosed;
+
+pub fn map_name_from_path(path: &Path) -> String {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "untitled".to_string())
+}
+
+/// Opens (or creates) a map database and restores its current state into the world.
+/// Expects no other map to be open.
+pub fn load_map(world: &mut World, path: &Path) -> Result {
+    let db = Db::new(path)?;
+
+    let reader = db.begin_read()?;
+    let is_initialized = db_is_initialized(&reader);
+    drop(reader);
+
+    if is_initialized {
+        migrate_legacy_states(&db, &mut OnBroken::Fail)?;
+        let report = migrate_schema(
+            &db,
+            world.resource::<ElementRoleRegistry>(),
+            &mut OnBroken::Fail,
+        )?;
+        if !report.is_empty() {
+            info!("Upgraded map {:?}: {}", path, report);
+        }
+    } else {
+        init_db(
+            &db,
+            map_name_from_path(path),
+            &mut world.resource_mut::<IdGen>(),
+        )?;
+    }
+
+    let reader = db.begin_read()?;
+    let meta = get_current_meta(&reader)?;
+    let hist_node = get_current_hist_node(&reader)?;
+    let properties = get_current_state(&reader)?.properties;
+    drop(reader);
+
+    let name = match properties.name.as_str() {
+        "" => map_name_from_path(path),
+        name => name.to_string(),
+    };
+    info!("Opening map '{}' from {:?}", name, path);
+
+    // Edits queued while no map was open don't belong to this one.
+    world.resource_mut::<PendingChanges>().clear();
+
+    // Db has to exist when state is restored.
+    world.insert_resource(db);
+    world.insert_resource(MapSession {
+        path: path.to_path_buf(),
+    });
+
+    if world
+        .get_resource::<State<AppState>>()
+        .is_some_and(|state| state.get() == &AppState::InEditor)
+    {
+        world.send_event(TPCameraTo(meta.editor_context.camera_pos));
+    }
+    world.insert_resource(meta.editor_context.cursor.clone());
+    world.insert_resource(meta.editor_context);
+
+    restore_state_in_world(world, hist_node.state_id, true)
+}
+
+/// Like `load_map`, but doesn't leave a half opened map behind when loading fails.
+fn load_map_or_close(world: &mut World, path: &Path) {
+    if let Err(err) = load_map(world, path) {
+        error!(
+            "Failed to open map {:?}: {}. If the file is damaged, try `{} repair {}`",
+            path,
+            err,
+            env!("CARGO_PKG_NAME"),
+            path.display()
+        );
+        close_current_map(world);
+    }
+}
+
+/// Tears down the currently open map, leaving the world without any map elements.
+pub fn close_current_map(world: &mut World) {
+    let Some(session) = world.remove_resource::<MapSession>() else {
+        return;
+    };
+
+    // Store the editor context while the db is still around.
+    if world
+        .get_resource::<State<AppState>>()
+        .is_some_and(|state| state.get() == &AppState::InEditor)
+    {
+        match world.run_system_cached(update_editor_context) {
+            Ok(Ok(())) => (),
+            Ok(Err(err)) => warn!("Failed to store editor context: {}", err),
+            Err(err) => warn!("Failed to store editor context: {}", err),
+        }
+    }
+
+    let elem_entities: Vec<Entity> = world
+        .query_filtered::<Entity, With<ElementId>>()
+        .iter(world)
+        .collect();
+    for entity in elem_entities {
+        world.despawn(entity);
+    }
+
+    world.resource_mut::<ElementLookup>().clear();
+    world.resource_mut::<PendingChanges>().clear();
+    world.remove_resource::<MapState>();
+    world
+        .resource_mut::<MapProperties>()
+        .set_if_neq(MapProperties::default());
+
+    if let Some(mut db) = world.remove_resource::<Db>() {
+        if let Some(settings) = world.get_resource::<GcSettings>().filter(|gc| gc.on_close) {
+            match collect_garbage(&db, &settings.policy).and_then(|_| db.compact()) {
+                Ok(_) => info!("Collected garbage in {:?}", session.path),
+                Err(err) => warn!("Failed to collect garbage: {}", err),
+            }
+        }
+        // Dropping the db closes the file.
+        drop(db);
+    }
+
+    world.trigger(MapClosed);
+    info!("Closed map {:?}", session.path);
+}
+
+pub fn open_startup_map(world: &mut World) {
+    let path = match world
+        .get_resource::<StartupMap>()
+        .and_then(|startup| startup.0.clone())
+    {
+        Some(path) => path,
+        None => PathBuf::from(world.resource::<AppDataPath>().get())
+            .join(format!("map.{}", MAP_FILE_EXT)),
+    };
+    // The editor keeps running without a map, so another one can be opened.
+    load_map_or_close(world, &path);
+}
+
+fn open_map(trigger: Trigger<OpenMap>, world: &mut World) {
+    let path = trigger.path.clone();
+    close_current_map(world);
+    load_map_or_close(world, &path);
+}
+
+fn new_map(trigger: Trigger<NewMap>, world: &mut World) {
+    let path = trigger.path.clone();
+    if path.exists() {
+        error!("Can't create new map, {:?} already exists", path);
+        return;
+    }
+    close_current_map(world);
+    load_map_or_close(world, &path);
+}
+
+fn save_map_as(trigger: Trigger<SaveMapAs>, world: &mut World) {
+    let path = trigger.path.clone();
+    let Some(cur_path) = world
+        .get_resource::<MapSession>()
+        .map(|session| session.path.clone())
+    else {
+        error!("Can't save map as {:?}, no map is open", path);
+        return;
+    };
+    if path.exists() {
+        error!("Can't save map as {:?}, file already exists", path);
+        return;
+    }
+
+    // Close first so the copied file is in a clean state.
+    close_current_map(world);
+    let mut copy_and_load = || -> Result {
+        std::fs::copy(&cur_path, &path)?;
+        load_map(world, &path)
+    };
+
+    if let Err(err) = copy_and_load() {
+        error!("Failed to save map as {:?}: {}", path, err);
+        close_current_map(world);
+        load_map_or_close(world, &cur_path);
+    } else {
+        info!("Saved map as {:?}", path);
+    }
+}
+
+fn close_map(_: Trigger<CloseMap>, world: &mut World) {
+    close_current_map(world);
+}
+
+fn new_map_in_data_dir(app_data_path: Res<AppDataPath>, mut commands: Commands) {
+    let path = PathBuf::from(app_data_path.get()).join(format!(
+        "untitled-{}.{}",
+        new_timestamp(),
+        MAP_FILE_EXT
+    ));
+    commands.trigger(NewMap { path });
+}
+
+fn save_map_copy(session: Res<MapSession>, mut commands: Commands) {
+    let path = session.path.with_file_name(format!(
+        "{}-{}.{}",
+        map_name_from_path(&session.path),
+        new_timestamp(),
+        MAP_FILE_EXT
+    ));
+    commands.trigger(SaveMapAs { path });
+}
+
+fn trigger_close_map(mut commands: Commands) {
+    commands.trigger(CloseMap);
+}
+
+pub fn plugin(app: &mut App) {
+    app.init_resource::<StartupMap>();
+    app.add_observer(open_map);
+    app.add_observer(new_map);
+    app.add_observer(save_map_as);
+    app.add_observer(close_map);
+    app.add_systems(
+        PreUpdate,
+        (
+            new_map_in_data_dir.run_if(input_just_pressed(Binding::NewMap)),
+            save_map_copy
+                .run_if(resource_exists::<MapSession>.and(input_just_pressed(Binding::SaveAs))),
+            trigger_close_map
+                .run_if(resource_exists::<MapSession>.and(input_just_pressed(Binding::CloseMap))),
+        )
+            .after(InputBindingSystem)
+            .run_if(in_state(AppState::InEditor)),
+    );
+}
@@ -1,189 +1,409 @@
-use bevy::{ecs::schedule::ScheduleLabel, platform::collections::HashMap, prelude::*};
-use color_eyre::eyre::eyre;
-use redb::TableDefinition;
+use bevy::{
+    ecs::schedule::ScheduleLabel,
+    platform::collections::{HashMap, HashSet},
+    prelude::*,
+    utils::Parallel,
+};
+use redb::{ReadableTable, Table, TableDefinition};
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
 use crate::{
     core::{
-        db::{Checksum, Db, EnsureExists, Object, Typed, TBL_META, TBL_OBJECTS},
+        db::{Checksum, Db, DbError, NotFound, Object, Typed, Versioned, TBL_META, TBL_OBJECTS},
         map::{
             changes::{Change, CreateId, UpdateElemInfo},
-            elements::{ElementId, ElementRoleRegistry, Info, Role},
+            elements::{ChangeBuilder, ElementId, ElementRoleRegistry, Info, Role},
             get_current_hist_node, get_current_state,
             history::TBL_HIST_NODES,
+            properties::MapProperties,
             ElementLookup,
         },
     },
     id::Id,
 };
 
-pub const TBL_STATES: TableDefinition<Id, Typed<MapState>> = TableDefinition::new("states");
+pub const TBL_STATES: TableDefinition<Id, Typed<StoredState>> =
+    TableDefinition::new("state_records");
+
+/// Maximum number of deltas between two keyframes.
+const KEYFRAME_INTERVAL: u32 = 32;
 
-#[derive(Serialize, Deserialize, Debug, Resource, Default)]
+#[derive(Serialize, Deserialize, Debug, Resource, Default, Clone, PartialEq)]
 pub struct MapState {
     pub elements: HashMap<Id, ElementState>,
+    pub properties: MapProperties,
     // snapshot should also store all Media used in the map, to be able to undo/redo media
     // add/delete/rename.. BUT not the media contents nor the element contents. those should be
     // stored separately and only deleted when all referring history snapshots are gone.. i guess..
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl MapState {
+    fn apply_delta(&mut self, delta: &StateDelta) {
+        for (id, elem) in delta.added.iter().chain(delta.changed.iter()) {
+            self.elements.insert(*id, elem.clone());
+        }
+        for id in delta.removed.iter() {
+            self.elements.remove(id);
+        }
+        if let Some(properties) = &delta.properties {
+            self.properties = properties.clone();
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct ElementState {
-    // TODO: role shoudl probably not be optional, forced by the decoupling here.
     /// used to insert/update the correct kind of element when restoring a state
-    pub role: Option<u64>,
+    pub role: u64,
     pub info: Checksum,
     pub params: Checksum,
 }
 
-// Multiple typed statechange variants to allow parallel insertion
-// (Generalize by element role i guess)
-#[derive(Event, Debug)]
-pub enum StateChange {
-    SetInfo { id: Id, info: Checksum },
-    SetParams { id: Id, role: u64, params: Checksum },
-    // Removed id
+/// How a map state is stored in the db.
+/// Most states are stored as a delta to their parent state, with a full keyframe every now and then
+/// to keep resolving fast.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum StoredState {
+    Keyframe(MapState),
+    Delta(StateDelta),
 }
 
-fn sync_elems(
-    db: Res<Db>,
-    state: Res<MapState>,
-    q_elems: Query<(&ElementId, &Info)>,
-    mut changes: EventWriter<StateChange>,
-) -> Result {
-    for (id, info) in q_elems.iter() {
-        let (new_checksum, new_info) = Object::new_typed(info);
+impl Versioned for StoredState {
+    const VERSION: u16 = 2;
+}
+
+impl StoredState {
+    /// Number of deltas that have to be applied on top of the closest keyframe.
+    pub fn depth(&self) -> u32 {
+        match self {
+            StoredState::Keyframe(_) => 0,
+            StoredState::Delta(delta) => delta.depth,
+        }
+    }
+
+    pub fn parent_id(&self) -> Option<Id> {
+        match self {
+            StoredState::Keyframe(_) => None,
+            StoredState::Delta(delta) => Some(delta.parent_id),
+        }
+    }
+
+    /// All element states mentioned by this record, with their element ids.
+    pub fn entries(&self) -> impl Iterator<Item = (&Id, &ElementState)> {
+        let (full, added, changed) = match self {
+            StoredState::Keyframe(state) => (Some(&state.elements), None, None),
+            StoredState::Delta(delta) => (None, Some(&delta.added), Some(&delta.changed)),
+        };
+        full.into_iter()
+            .chain(added)
+            .chain(changed)
+            .flat_map(|elements| elements.iter())
+    }
+
+    /// All element states mentioned by this record.
+    pub fn element_states(&self) -> impl Iterator<Item = &ElementState> {
+        self.entries().map(|(_, elem)| elem)
+    }
+
+    pub fn element_states_mut(&mut self) -> impl Iterator<Item = &mut ElementState> {
+        let (full, added, changed) = match self {
+            StoredState::Keyframe(state) => (Some(&mut state.elements), None, None),
+            StoredState::Delta(delta) => (None, Some(&mut delta.added), Some(&mut delta.changed)),
+        };
+        full.into_iter()
+            .chain(added)
+            .chain(changed)
+            .flat_map(|elements| elements.values_mut())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StateDelta {
+    pub parent_id: Id,
+    pub depth: u32,
+    pub added: HashMap<Id, ElementState>,
+    pub changed: HashMap<Id, ElementState>,
+    pub removed: Vec<Id>,
+    /// Only stored when the properties changed.
+    pub properties: Option<MapProperties>,
+}
 
-        if state
+impl StateDelta {
+    pub fn between(parent_id: Id, depth: u32, old: &MapState, new: &MapState) -> Self {
+        let mut added = HashMap::new();
+        let mut changed = HashMap::new();
+        for (id, elem) in new.elements.iter() {
+            match old.elements.get(id) {
+                None => {
+                    added.insert(*id, elem.clone());
+                }
+                Some(old_elem) if old_elem != elem => {
+                    changed.insert(*id, elem.clone());
+                }
+                _ => (),
+            }
+        }
+        let removed = old
             .elements
-            .get(id.id_ref())
-            .is_none_or(|existing| new_checksum != existing.info)
-        {
-            let writer = db.begin_write()?;
-            writer
-                .open_table(TBL_OBJECTS)?
-                .insert(&new_checksum, &new_info)?;
-            writer.commit()?;
-            changes.write(StateChange::SetInfo {
-                id: **id,
-                info: new_checksum,
-            });
+            .keys()
+            .filter(|id| !new.elements.contains_key(*id))
+            .copied()
+            .collect();
+        let properties = (new.properties != old.properties).then(|| new.properties.clone());
+
+        Self {
+            parent_id,
+            depth,
+            added,
+            changed,
+            removed,
+            properties,
+        }
+    }
+}
+
+/// Reads a fully resolved map state, applying deltas on top of the closest keyframe.
+pub fn read_state(
+    tbl_states: &impl ReadableTable<Id, Typed<StoredState>>,
+    id: Id,
+) -> Result<MapState> {
+    let mut deltas: Vec<StateDelta> = Vec::new();
+    let mut cur_id = id;
+    let mut state = loop {
+        match tbl_states.get(cur_id)?.ok_or(NotFound)?.value() {
+            StoredState::Keyframe(state) => break state,
+            StoredState::Delta(delta) => {
+                cur_id = delta.parent_id;
+                deltas.push(delta);
+            }
         }
+    };
+    for delta in deltas.iter().rev() {
+        state.apply_delta(delta);
     }
+    Ok(state)
+}
+
+/// Stores a map state, as a delta to its parent when possible.
+pub fn write_state(
+    tbl_states: &mut Table<Id, Typed<StoredState>>,
+    id: Id,
+    parent: Option<(Id, &MapState)>,
+    state: MapState,
+) -> Result {
+    let record = match parent {
+        Some((parent_id, parent_state)) => {
+            let depth = tbl_states.get(parent_id)?.ok_or(NotFound)?.value().depth() + 1;
+            if depth < KEYFRAME_INTERVAL {
+                StoredState::Delta(StateDelta::between(parent_id, depth, parent_state, &state))
+            } else {
+                StoredState::Keyframe(state)
+            }
+        }
+        None => StoredState::Keyframe(state),
+    };
+    tbl_states.insert(id, record)?;
     Ok(())
 }
 
-pub fn sync_params<R>(
-    db: Res<Db>,
+// (Generalize by element role i guess)
+#[derive(Event, Debug)]
+pub enum StateChange {
+    /// Info, role and params of an element, captured together.
+    SetElement {
+        id: Id,
+        elem: ElementState,
+    },
+    /// The element no longer exists in the world.
+    Remove {
+        id: Id,
+    },
+    SetProperties(MapProperties),
+}
+
+/// Ids of the elements seen by the role sync systems during a snapshot.
+/// Elements missing from this set have no role component and can't be stored.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct SyncedElements(HashSet<Id>);
+
+/// Objects created during a snapshot. They are written in the same transaction as the new state
+/// and its history node, so a failed snapshot leaves nothing behind.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct SnapshotObjects(HashMap<Checksum, Object>);
+
+/// Makes the next snapshot hash every element instead of only the changed ones.
+/// Needed after a failed snapshot, as it already consumed the change ticks.
+#[derive(Resource)]
+pub struct FullSnapshot;
+
+#[derive(Error, Debug)]
+#[error("Elements without a role component can't be stored: {0:?}")]
+pub struct MissingRoleError(pub Vec<Id>);
+
+/// Hashes the elements of a role that changed since the last snapshot, on the compute task pool.
+#[allow(clippy::type_complexity)]
+pub fn sync_elements<R>(
     state: Res<MapState>,
-    q_elem_params: Query<(&ElementId, &R)>,
+    full_snapshot: Option<Res<FullSnapshot>>,
+    q_elems: Query<(&ElementId, Ref<Info>, Ref<R>)>,
+    mut synced: ResMut<SyncedElements>,
+    mut objects: ResMut<SnapshotObjects>,
     mut changes: EventWriter<StateChange>,
-) -> Result
-where
+    mut hashed: Local<Parallel<Vec<(Id, ElementState, Object, Object)>>>,
+) where
     R: Role,
 {
-    for (id, params) in q_elem_params.iter() {
-        let (new_checksum, new_params_obj) = Object::new_typed(params);
+    synced.extend(q_elems.iter().map(|(id, _, _)| **id));
 
-        if state
-            .elements
-            .get(id.id_ref())
-            .is_none_or(|existing| new_checksum != existing.params)
-        {
-            let writer = db.begin_write()?;
-            writer
-                .open_table(TBL_OBJECTS)?
-                .insert(&new_checksum, &new_params_obj)?;
-            writer.commit()?;
-            changes.write(StateChange::SetParams {
-                id: **id,
-                role: R::id_hash(),
-                params: new_checksum,
-            });
+    let full_snapshot = full_snapshot.is_some();
+    q_elems.par_iter().for_each(|(id, info, params)| {
+        let stored = state.elements.get(id.id_ref());
+        if !full_snapshot && stored.is_some() && !info.is_changed() && !params.is_changed() {
+            return;
+        }
+
+        let (info_checksum, info_obj) = Object::new_typed(&*info);
+        let (params_checksum, params_obj) = Object::new_typed(&*params);
+        let elem = ElementState {
+            role: R::id_hash(),
+            info: info_checksum,
+            params: params_checksum,
+        };
+
+        if stored != Some(&elem) {
+            hashed
+                .borrow_local_mut()
+                .push((**id, elem, info_obj, params_obj));
+        }
+    });
+
+    for (id, elem, info_obj, params_obj) in hashed.drain() {
+        objects.insert(elem.info.clone(), info_obj);
+        objects.insert(elem.params.clone(), params_obj);
+        changes.write(StateChange::SetElement { id, elem });
+    }
+}
+
+/// Finds elements of the state that were despawned since it was taken.
+fn sync_removed(
+    state: Res<MapState>,
+    q_elems: Query<&ElementId>,
+    mut changes: EventWriter<StateChange>,
+) {
+    let in_world: HashSet<Id> = q_elems.iter().map(|id| **id).collect();
+    for id in state.elements.keys() {
+        if !in_world.contains(id) {
+            changes.write(StateChange::Remove { id: *id });
         }
     }
-    Ok(())
 }
 
 fn apply_state_changes(mut changes: EventReader<StateChange>, mut state: ResMut<MapState>) {
     for change in changes.read() {
         match change {
-            StateChange::SetInfo { id, info: meta } => {
-                state
-                    .elements
-                    .entry(*id)
-                    .and_modify(|elem| elem.info = meta.clone())
-                    .or_insert(ElementState {
-                        role: None,
-                        info: meta.clone(),
-                        params: Checksum::nil(),
-                    });
+            StateChange::SetElement { id, elem } => {
+                state.elements.insert(*id, elem.clone());
+            }
+            StateChange::Remove { id } => {
+                state.elements.remove(id);
             }
-            StateChange::SetParams { id, role, params } => {
-                state
-                    .elements
-                    .entry(*id)
-                    .and_modify(|elem| {
-                        elem.role = Some(*role);
-                        elem.params = params.clone();
-                    })
-                    .or_insert(ElementState {
-                        role: Some(*role),
-                        info: Checksum::nil(),
-                        params: params.clone(),
-                    });
+            StateChange::SetProperties(properties) => {
+                state.properties = properties.clone();
             }
         }
     }
 }
 
+/// Everything needed to put a stored element into the world.
+pub struct LoadedElement<'r> {
+    pub info: Info,
+    pub params: Object,
+    pub builder: &'r dyn ChangeBuilder,
+}
+
+/// Fetches the objects of an element and resolves its role.
+pub fn load_element<'r>(
+    objs: &impl ReadableTable<Checksum, Object>,
+    registry: &'r ElementRoleRegistry,
+    elem_id: Id,
+    elem: &ElementState,
+) -> Result<LoadedElement<'r>, DbError> {
+    let builder = registry.roles.get(&elem.role).ok_or(DbError::UnknownRole {
+        elem_id,
+        role: elem.role,
+    })?;
+    let info = objs
+        .get(&elem.info)?
+        .ok_or_else(|| DbError::MissingObject(elem.info.clone()))?
+        .value()
+        .cast::<Info>()?;
+    let params = objs
+        .get(&elem.params)?
+        .ok_or_else(|| DbError::MissingObject(elem.params.clone()))?
+        .value();
+    Ok(LoadedElement {
+        info,
+        params,
+        builder: builder.as_ref(),
+    })
+}
+
 #[derive(Event)]
 pub struct RestoreState {
     pub id: Id,
     pub fresh_map: bool,
 }
 
-fn restore_state(trigger: Trigger<RestoreState>, world: &mut World) -> Result {
+/// Makes the world match a stored state.
+/// All elements are loaded before the world is touched, so a broken state leaves the world as is.
+pub fn restore_state_in_world(world: &mut World, id: Id, fresh_map: bool) -> Result {
     let reader = world.resource::<Db>().begin_read()?;
 
     // Need to grab current state from db for easier comparisons..
     let cur_hist_node = get_current_hist_node(&reader)?;
     let states = reader.open_table(TBL_STATES)?;
-    let cur_state = states.get(cur_hist_node.state_id)?.unwrap().value();
-    let state_to_restore = states.get(trigger.id)?.ensure_exists()?.value();
+    let cur_state = read_state(&states, cur_hist_node.state_id)?;
+    let state_to_restore = read_state(&states, id)?;
 
     let objs = reader.open_table(TBL_OBJECTS)?;
+    let registry = world.resource::<ElementRoleRegistry>();
+    let mut changes: Vec<Box<dyn Change>> = Vec::new();
     for (elem_id, elem) in state_to_restore.elements.iter() {
-        let info = objs.get(&elem.info)?.unwrap().value().cast::<Info>();
-        let params = objs.get(&elem.params)?.unwrap().value();
-
         // TODO: shared code for handling a single element?
         // quirk: here it checks for checksum changes before changing, but in the "checkout" thing it should always run either update or create. perhaps a "force" bool?
         // also, db stuff should be handled separately for each call.. because getting state for
         // ever elem would be stupid slow
-        world.resource_scope(|world: &mut World, registry: Mut<ElementRoleRegistry>| {
-            let builder = registry.roles.get(&elem.role.unwrap()).unwrap();
-            if let Some(cur_elem) = (!trigger.fresh_map)
-                .then(|| cur_state.elements.get(elem_id))
-                .flatten()
-            {
+        match (!fresh_map)
+            .then(|| cur_state.elements.get(elem_id))
+            .flatten()
+        {
+            Some(cur_elem) if cur_elem == elem => (),
+            Some(cur_elem) => {
+                let loaded = load_element(&objs, registry, *elem_id, elem)?;
                 if elem.info != cur_elem.info {
-                    UpdateElemInfo {
+                    changes.push(Box::new(UpdateElemInfo {
                         elem_id: *elem_id,
-                        new_info: info,
-                    }
-                    .apply_to_world(world);
+                        new_info: loaded.info,
+                    }));
                 }
                 if elem.params != cur_elem.params {
-                    builder.build_update(*elem_id, params).apply_to_world(world);
+                    changes.push(loaded.builder.build_update(*elem_id, loaded.params)?);
                 }
-            } else {
-                // Create
-                builder
-                    .build_create(CreateId::Loaded(*elem_id), info, params)
-                    .apply_to_world(world);
             }
-        });
+            None => {
+                let loaded = load_element(&objs, registry, *elem_id, elem)?;
+                changes.push(loaded.builder.build_create(
+                    CreateId::Loaded(*elem_id),
+                    loaded.info,
+                    loaded.params,
+                )?);
+            }
+        }
+    }
+
+    for change in changes {
+        change.apply_to_world(world)?;
     }
 
     // Remove elems not in the state
@@ -198,54 +418,101 @@ fn restore_state(trigger: Trigger<RestoreState>, world: &mut World) -> Result {
         world.despawn(entity);
     }
 
+    world
+        .resource_mut::<MapProperties>()
+        .set_if_neq(state_to_restore.properties);
+
     Ok(())
 }
 
+fn restore_state(trigger: Trigger<RestoreState>, world: &mut World) {
+    if let Err(err) = restore_state_in_world(world, trigger.id, trigger.fresh_map) {
+        error!("Failed to restore state {}: {}", trigger.id, err);
+    }
+}
+
 #[derive(Event)]
 /// Like a Git file checkout, updates an element in the world to match its current state in the db.
 pub struct CheckoutElement {
     pub id: Id,
 }
 
-fn checkout_element(trigger: Trigger<CheckoutElement>, world: &mut World) -> Result {
+/// Makes some elements in the world match the current state in the db.
+/// Elements that aren't in the state are despawned, elements missing from the world are spawned.
+pub fn checkout_elements_in_world(world: &mut World, ids: impl IntoIterator<Item = Id>) -> Result {
     let reader = world.resource::<Db>().begin_read()?;
     let state = get_current_state(&reader)?;
     let objs = reader.open_table(TBL_OBJECTS)?;
 
-    let elem = state
-        .elements
-        .get(&trigger.id)
-        .ok_or(eyre!("Missing element!!"))?;
-    let info = objs.get(&elem.info)?.unwrap().value().cast::<Info>();
-    let params = objs.get(&elem.params)?.unwrap().value();
-
-    world.resource_scope(|world: &mut World, registry: Mut<ElementRoleRegistry>| {
-        let builder = registry.roles.get(&elem.role.unwrap()).unwrap();
-        UpdateElemInfo {
-            elem_id: trigger.id,
-            new_info: info,
-        }
-        .apply_to_world(world);
-        builder
-            .build_update(trigger.id, params)
-            .apply_to_world(world);
-    });
+    let registry = world.resource::<ElementRoleRegistry>();
+    let lookup = world.resource::<ElementLookup>();
+    let mut changes: Vec<Box<dyn Change>> = Vec::new();
+    let mut to_despawn = Vec::new();
+    for id in ids {
+        // The lookup is only cleaned up once per frame, so it can still hold despawned entities.
+        let entity = lookup
+            .find(&id)
+            .ok()
+            .filter(|entity| world.get_entity(*entity).is_ok());
+        match (state.elements.get(&id), entity) {
+            (Some(elem), Some(_)) => {
+                let loaded = load_element(&objs, registry, id, elem)?;
+                changes.push(Box::new(UpdateElemInfo {
+                    elem_id: id,
+                    new_info: loaded.info,
+                }));
+                changes.push(loaded.builder.build_update(id, loaded.params)?);
+            }
+            (Some(elem), None) => {
+                let loaded = load_element(&objs, registry, id, elem)?;
+                changes.push(loaded.builder.build_create(
+                    CreateId::Loaded(id),
+                    loaded.info,
+                    loaded.params,
+                )?);
+            }
+            (None, Some(entity)) => to_despawn.push(entity),
+            (None, None) => (),
+        }
+    }
+
+    for change in changes {
+        change.apply_to_world(world)?;
+    }
+    for entity in to_despawn {
+        world.despawn(entity);
+    }
+
+    Ok(())
+}
 
+/// Makes the map properties in the world match the current state in the db.
+pub fn checkout_properties_in_world(world: &mut World) -> Result {
+    let reader = world.resource::<Db>().begin_read()?;
+    let state = get_current_state(&reader)?;
+    world
+        .resource_mut::<MapProperties>()
+        .set_if_neq(state.properties);
     Ok(())
 }
 
+fn checkout_element(trigger: Trigger<CheckoutElement>, world: &mut World) -> Result {
+    checkout_elements_in_world(world, [trigger.id])
+}
+
 #[derive(ScheduleLabel, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct StateSnapshot;
 
 pub fn plugin(app: &mut App) {
     app.add_schedule(Schedule::new(StateSnapshot));
     app.add_event::<StateChange>();
+    app.init_resource::<SyncedElements>();
     app.add_observer(restore_state);
     app.add_observer(checkout_element);
     app.add_systems(
         StateSnapshot,
         (
-            sync_elems.in_set(SyncState),
+            sync_removed.in_set(SyncState),
             apply_state_changes.after(SyncState),
         ),
     );
@@ -253,3 +520,81 @@ pub fn plugin(app: &mut App) {
 
 #[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct SyncState;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::id::IdGen;
+
+    fn elem(seed: u32) -> ElementState {
+        ElementState {
+            role: 0,
+            info: Object::checksum(&seed.to_le_bytes()),
+            params: Object::checksum(&(seed + 1).to_le_bytes()),
+        }
+    }
+
+    #[test]
+    fn delta_chains_resolve_across_keyframes() -> Result {
+        let db = Db::in_memory()?;
+        let writer = db.begin_write()?;
+        let mut tbl_states = writer.open_table(TBL_STATES)?;
+        let mut id_gen = IdGen::default();
+        let elem_ids: Vec<Id> = (0..4).map(|_| id_gen.generate()).collect();
+
+        // Every step adds, changes or removes an element, now and then the properties change too.
+        let mut written: Vec<(Id, MapState)> = Vec::new();
+        let mut state = MapState::default();
+        for step in 0..KEYFRAME_INTERVAL * 2 + 5 {
+            let elem_id = elem_ids[step as usize % elem_ids.len()];
+            if state.elements.remove(&elem_id).is_none() || step % 3 == 0 {
+                state.elements.insert(elem_id, elem(step));
+            }
+            if step % 10 == 0 {
+                state.properties.name = format!("step {}", step);
+            }
+            let id = id_gen.generate();
+            let parent = written.last().map(|(id, state)| (*id, state));
+            write_state(&mut tbl_states, id, parent, state.clone())?;
+            written.push((id, state.clone()));
+        }
+
+        for (step, (id, expected)) in written.iter().enumerate() {
+            let record = tbl_states.get(id)?.ok_or(NotFound)?.value();
+            assert_eq!(record.depth(), step as u32 % KEYFRAME_INTERVAL);
+            assert_eq!(&read_state(&tbl_states, *id)?, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn deltas_only_hold_differences() {
+        let ids: Vec<Id> = {
+            let mut id_gen = IdGen::default();
+            (0..4).map(|_| id_gen.generate()).collect()
+        };
+        let old = MapState {
+            elements: [(ids[0], elem(0)), (ids[1], elem(1)), (ids[2], elem(2))]
+                .into_iter()
+                .collect(),
+            ..default()
+        };
+        let mut new = MapState {
+            elements: [(ids[0], elem(0)), (ids[1], elem(10)), (ids[3], elem(3))]
+                .into_iter()
+                .collect(),
+            ..default()
+        };
+        new.properties.name = "renamed".to_string();
+
+        let delta = StateDelta::between(ids[0], 1, &old, &new);
+        assert_eq!(delta.added.keys().collect::<Vec<_>>(), [&ids[3]]);
+        assert_eq!(delta.changed.keys().collect::<Vec<_>>(), [&ids[1]]);
+        assert_eq!(delta.removed, [ids[2]]);
+        assert_eq!(delta.properties, Some(new.properties.clone()));
+
+        let mut resolved = old.clone();
+        resolved.apply_delta(&delta);
+        assert_eq!(resolved, new);
+    }
+}
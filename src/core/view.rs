@@ -12,7 +12,7 @@ use crate::{
 
 /// For gimbal-locked rotation.
 /// Pitch=X, Yaw=Y, Roll=Z
-#[derive(Component, Debug, Default, Copy, Clone, Serialize, Deserialize)]
+#[derive(Component, Debug, Default, Copy, Clone, PartialEq, Serialize, Deserialize)]
 #[require(Transform)]
 pub struct Gimbal {
     pub pitch_yaw: Vec2,
@@ -34,7 +34,7 @@ impl FromPitchYawRoll for Gimbal {
     }
 }
 
-#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub struct GimbalPos {
     pub pos: Vec3,
     pub rot: Gimbal,
@@ -1,6 +1,7 @@
 pub mod actions;
 pub mod cursor;
 pub mod freelook;
+pub mod history_panel;
 pub mod selection;
 pub mod tools;
 pub mod ui;
@@ -102,6 +103,7 @@ pub fn plugin(app: &mut App) {
     app.add_plugins((
         freelook::plugin,
         cursor::plugin,
+        history_panel::plugin,
         selection::plugin,
         tools::plugin,
         actions::plugin,
@@ -1,3 +1,4 @@
+use avian3d::prelude::*;
 use bevy::{
     color::palettes::css,
     input::common_conditions::{input_just_pressed, input_just_released},
@@ -9,23 +10,28 @@ use crate::{
         binds::{Binding, InputBindingSystem},
         map::{
             changes::{
-                Change, CreateElem, CreateId, PendingChanges, UntrackedChange, RemoveElement,
-                UpdateElemParams,
+                BeginPreview, Change, ChangeSet, CommitPreview, CreateElem, CreateId,
+                PendingChanges, RemoveElement, RollbackPreview, UntrackedChange, UpdateElemParams,
             },
             elements::{
-                brush::{Brush, BrushBounds},
+                brush::{Brush, BrushBounds, SideUv, UvAlign},
                 light::{Light, LightType},
-                ElementEntity, Info,
+                ElementEntity, ElementId, Info,
             },
-            states::CheckoutElement,
+            generators::{Generate, Generator, GeneratorPresets},
+            session::MapClosed,
         },
     },
     editor::{
-        selection::{SelTargetBrushSide, SelectedPos, SelectionChanged, SelectionTargets},
+        selection::{
+            SelTargetBrushCorners, SelTargetBrushSide, SelectedPos, SelectionChanged,
+            SelectionTargets,
+        },
+        tools::{BrushShape, CurrentTool, SelectedSurface},
         EditorSystems,
     },
     id::Id,
-    util::Facing3d,
+    util::enter_state,
 };
 
 #[derive(States, Default, Debug, Clone, PartialEq, Eq, Hash)]
@@ -34,6 +40,7 @@ pub enum EditorAction {
     None,
     BuildBrush,
     ResizeBrush,
+    DragCorners,
 }
 
 #[derive(Resource)]
@@ -44,9 +51,21 @@ pub struct BuildBrushProcess {
 #[derive(Resource)]
 pub struct ResizeBrushProcess {
     pub target: ElementEntity,
-    pub side: Facing3d,
+    pub side: usize,
+}
+
+#[derive(Resource)]
+pub struct DragCornersProcess {
+    pub target: ElementEntity,
+    /// The brush before dragging, the corners are moved relative to it.
+    pub original: Brush,
+    pub corners: Vec<Vec3>,
+    pub start: Vec3,
 }
 
+/// Degrees brushes are turned by at a time.
+const BRUSH_ROTATION_STEP: f32 = 15.0;
+
 #[derive(Default, Reflect, GizmoConfigGroup)]
 struct ActionGizmos {}
 
@@ -68,6 +87,7 @@ fn start_building_brush_here(
 fn end_building_brush_here(
     process: Res<BuildBrushProcess>,
     sel_pos: Res<SelectedPos>,
+    shape: Res<BrushShape>,
     mut next_editor_action: ResMut<NextState<EditorAction>>,
     mut map_changes: ResMut<PendingChanges>,
 ) {
@@ -81,7 +101,7 @@ fn end_building_brush_here(
             info: Info {
                 name: "a brush".to_string(),
             },
-            params: Brush { bounds },
+            params: shape.build(&bounds),
         });
         next_editor_action.set(EditorAction::None);
     }
@@ -90,23 +110,32 @@ fn end_building_brush_here(
 fn build_brush_draw_gizmos(
     process: Res<BuildBrushProcess>,
     sel_pos: Res<SelectedPos>,
+    shape: Res<BrushShape>,
     mut gizmos: Gizmos<ActionGizmos>,
 ) {
     let start = process.start;
     let end = **sel_pos;
     let bounds = BrushBounds::new(start, end);
 
-    let transform = Transform::IDENTITY
-        .with_translation(bounds.center())
-        .with_scale(bounds.size());
+    if !bounds.is_valid() {
+        let transform = Transform::IDENTITY
+            .with_translation(bounds.center())
+            .with_scale(bounds.size());
+        gizmos.cuboid(transform, css::DARK_RED);
+        return;
+    }
 
-    let color = if bounds.is_valid() {
-        css::SPRING_GREEN
-    } else {
-        css::DARK_RED
-    };
+    for polygon in shape.build(&bounds).polygons() {
+        gizmos.linestrip(
+            polygon.iter().chain(polygon.first()).copied(),
+            css::SPRING_GREEN,
+        );
+    }
+}
 
-    gizmos.cuboid(transform, color);
+fn cycle_brush_shape(mut shape: ResMut<BrushShape>) {
+    *shape = shape.next();
+    info!("Selected brush shape: {:?}", *shape);
 }
 
 fn build_brush_cleanup(mut commands: Commands) {
@@ -126,6 +155,7 @@ fn start_resizing_brush(
         target: sel_target.focused,
         side: sel_target_brush_side.0,
     });
+    commands.trigger(BeginPreview);
 }
 
 fn live_brush_resize(
@@ -136,9 +166,7 @@ fn live_brush_resize(
     // mut deploy_events: EventWriter<DeployMapNode>,
 ) {
     let brush = q_brushes.get(process.target.entity).unwrap();
-    let resized_brush = Brush {
-        bounds: brush.bounds.resized(process.side, **sel_pos),
-    };
+    let resized_brush = brush.moved_face(process.side, **sel_pos);
     commands.trigger(UntrackedChange::new(UpdateElemParams {
         elem_id: process.target.element_id,
         params: resized_brush,
@@ -154,18 +182,15 @@ fn end_resizing_brush_here(
     process: Res<ResizeBrushProcess>,
     q_brushes: Query<&Brush>,
     // map: Res<Map>,
-    mut map_changes: ResMut<PendingChanges>,
     mut next_editor_action: ResMut<NextState<EditorAction>>,
     mut commands: Commands,
 ) {
-    let mut brush = q_brushes.get(process.target.entity).unwrap().clone();
-    let resized_bounds = brush.bounds.resized(process.side, **sel_pos);
-
-    brush.bounds = resized_bounds;
-    map_changes.push_single(UpdateElemParams {
+    let brush = q_brushes.get(process.target.entity).unwrap();
+    commands.trigger(UntrackedChange::new(UpdateElemParams {
         elem_id: process.target.element_id,
-        params: brush,
-    });
+        params: brush.moved_face(process.side, **sel_pos),
+    }));
+    commands.trigger(CommitPreview);
     commands.remove_resource::<ResizeBrushProcess>();
     next_editor_action.set(EditorAction::None);
 }
@@ -173,19 +198,211 @@ fn end_resizing_brush_here(
 fn resize_brush_cleanup(process: Option<Res<ResizeBrushProcess>>, mut commands: Commands) {
     if let Some(process) = process {
         warn!("resetting a brush {}", process.target.element_id);
-        commands.trigger(CheckoutElement {
-            id: process.target.element_id,
-        });
+        commands.trigger(RollbackPreview);
         commands.remove_resource::<ResizeBrushProcess>();
     }
 }
 
+// Action: Dragging brush corners and edges
+
+fn start_dragging_corners(
+    sel_pos: Res<SelectedPos>,
+    sel_target: Res<SelectionTargets>,
+    sel_target_corners: Res<SelTargetBrushCorners>,
+    q_brushes: Query<&Brush>,
+    mut next_editor_action: ResMut<NextState<EditorAction>>,
+    mut commands: Commands,
+) {
+    let Ok(brush) = q_brushes.get(sel_target.focused.entity) else {
+        return;
+    };
+    next_editor_action.set(EditorAction::DragCorners);
+    commands.insert_resource(DragCornersProcess {
+        target: sel_target.focused,
+        original: brush.clone(),
+        corners: sel_target_corners.0.clone(),
+        start: **sel_pos,
+    });
+    commands.trigger(BeginPreview);
+}
+
+/// Drags along with the cursor. Positions where the brush wouldn't be convex are skipped, leaving
+/// the last valid one in place.
+fn live_corner_drag(
+    sel_pos: Res<SelectedPos>,
+    process: Res<DragCornersProcess>,
+    mut commands: Commands,
+) {
+    let Some(brush) = process
+        .original
+        .with_moved_corners(&process.corners, **sel_pos - process.start)
+    else {
+        return;
+    };
+    commands.trigger(UntrackedChange::new(UpdateElemParams {
+        elem_id: process.target.element_id,
+        params: brush,
+    }));
+}
+
+fn end_dragging_corners(
+    mut next_editor_action: ResMut<NextState<EditorAction>>,
+    mut commands: Commands,
+) {
+    commands.trigger(CommitPreview);
+    commands.remove_resource::<DragCornersProcess>();
+    next_editor_action.set(EditorAction::None);
+}
+
+fn drag_corners_cleanup(process: Option<Res<DragCornersProcess>>, mut commands: Commands) {
+    if process.is_some() {
+        commands.trigger(RollbackPreview);
+        commands.remove_resource::<DragCornersProcess>();
+    }
+}
+
+/// Abandon any action in progress, the elements it was working on are gone.
+fn cancel_action_on_map_close(
+    _: Trigger<MapClosed>,
+    mut next_editor_action: ResMut<NextState<EditorAction>>,
+    mut commands: Commands,
+) {
+    commands.remove_resource::<ResizeBrushProcess>();
+    commands.remove_resource::<DragCornersProcess>();
+    commands.remove_resource::<SelectionTargets>();
+    commands.remove_resource::<SelTargetBrushSide>();
+    commands.remove_resource::<SelTargetBrushCorners>();
+    next_editor_action.set(EditorAction::None);
+}
+
+// Tool: Setting the surface of a brush side
+
+fn set_surface_on_side(
+    sel_target: Res<SelectionTargets>,
+    sel_target_brush_side: Res<SelTargetBrushSide>,
+    selected_surface: Res<SelectedSurface>,
+    q_brushes: Query<&Brush>,
+    mut map_changes: ResMut<PendingChanges>,
+) {
+    let Ok(brush) = q_brushes.get(sel_target.focused.entity) else {
+        return;
+    };
+    let side = **sel_target_brush_side;
+    if brush
+        .faces
+        .get(side)
+        .is_none_or(|face| face.surface == Some(**selected_surface))
+    {
+        return;
+    }
+    let mut brush = brush.clone();
+    brush.faces[side].surface = Some(**selected_surface);
+    map_changes.push_single(UpdateElemParams {
+        elem_id: sel_target.focused.element_id,
+        params: brush,
+    });
+}
+
+/// Edits the texture mapping of the targeted brush side, which gets the size of the side too.
+fn edit_side_uv(
+    edit: impl Fn(&mut SideUv, Vec2) + Send + Sync + 'static,
+) -> impl Fn(Res<SelectionTargets>, Res<SelTargetBrushSide>, Query<&Brush>, ResMut<PendingChanges>)
+{
+    move |sel_target, sel_target_brush_side, q_brushes, mut map_changes| {
+        let Ok(brush) = q_brushes.get(sel_target.focused.entity) else {
+            return;
+        };
+        let side = **sel_target_brush_side;
+        if side >= brush.faces.len() {
+            return;
+        }
+        let size = brush.face_size(side);
+        let mut brush = brush.clone();
+        edit(&mut brush.faces[side].uv, size);
+        map_changes.push_single(UpdateElemParams {
+            elem_id: sel_target.focused.element_id,
+            params: brush,
+        });
+    }
+}
+
+/// Turns the focused brush around the vertical axis.
+fn rotate_brush(
+    degrees: f32,
+) -> impl Fn(Res<SelectionTargets>, Query<&Brush>, ResMut<PendingChanges>) {
+    move |sel_target, q_brushes, mut map_changes| {
+        let Ok(brush) = q_brushes.get(sel_target.focused.entity) else {
+            return;
+        };
+        map_changes.push_single(UpdateElemParams {
+            elem_id: sel_target.focused.element_id,
+            params: brush.rotated(Quat::from_rotation_y(degrees.to_radians())),
+        });
+    }
+}
+
+/// Subtracts the focused brush from every brush it overlaps. Those are replaced by the pieces
+/// left of them, all in one change set so it undoes as one.
+fn carve_with_focused(
+    sel_target: Res<SelectionTargets>,
+    spatial_query: SpatialQuery,
+    q_cutters: Query<(&Brush, &Collider, &Transform)>,
+    q_brushes: Query<(&Brush, &ElementId, &Info)>,
+    mut map_changes: ResMut<PendingChanges>,
+) {
+    let cutter_entity = sel_target.focused.entity;
+    let Ok((cutter, collider, transform)) = q_cutters.get(cutter_entity) else {
+        return;
+    };
+
+    let mut changes: Vec<Box<dyn Change>> = Vec::new();
+    for entity in spatial_query.shape_intersections(
+        collider,
+        transform.translation,
+        transform.rotation,
+        &SpatialQueryFilter::default().with_excluded_entities([cutter_entity]),
+    ) {
+        let Ok((brush, elem_id, info)) = q_brushes.get(entity) else {
+            continue;
+        };
+        let Some(pieces) = brush.carved(cutter) else {
+            continue;
+        };
+        changes.push(Box::new(RemoveElement { elem_id: **elem_id }));
+        for piece in pieces {
+            changes.push(Box::new(CreateElem {
+                id_mode: CreateId::Generated,
+                info: info.clone(),
+                params: piece,
+            }));
+        }
+    }
+
+    if changes.is_empty() {
+        info!("Nothing to carve");
+        return;
+    }
+    map_changes.push_set(ChangeSet { changes });
+}
+
 fn remove_node(sel_target: Res<SelectionTargets>, mut map_changes: ResMut<PendingChanges>) {
     map_changes.push_single(RemoveElement {
         elem_id: sel_target.focused.element_id,
     });
 }
 
+fn generate_here(sel_pos: Res<SelectedPos>, mut commands: Commands) {
+    commands.trigger(Generate {
+        origin: **sel_pos,
+        seed: rand::random(),
+    });
+}
+
+fn cycle_generator(mut presets: ResMut<GeneratorPresets>) {
+    presets.select_next();
+    info!("Selected generator: {}", presets.selected().name());
+}
+
 fn add_light(sel_pos: Res<SelectedPos>, mut map_changes: ResMut<PendingChanges>) {
     let light = Light {
         position: **sel_pos,
@@ -205,6 +422,7 @@ fn add_light(sel_pos: Res<SelectedPos>, mut map_changes: ResMut<PendingChanges>)
 
 pub fn plugin(app: &mut App) {
     app.init_state::<EditorAction>()
+        .add_observer(cancel_action_on_map_close)
         .insert_gizmo_config(
             ActionGizmos {},
             GizmoConfig {
@@ -221,15 +439,48 @@ pub fn plugin(app: &mut App) {
             PreUpdate,
             (
                 (
-                    start_building_brush_here.run_if(
-                        resource_exists::<SelectedPos>
-                            .and(not(resource_exists::<SelTargetBrushSide>))
-                            .and(input_just_pressed(Binding::Primary)),
-                    ),
-                    start_resizing_brush.run_if(
-                        resource_exists::<SelectionTargets>
-                            .and(resource_exists::<SelTargetBrushSide>)
-                            .and(input_just_pressed(Binding::Primary)),
+                    (
+                        start_building_brush_here.run_if(
+                            resource_exists::<SelectedPos>
+                                .and(not(resource_exists::<SelTargetBrushSide>))
+                                .and(input_just_pressed(Binding::Primary)),
+                        ),
+                        start_resizing_brush.run_if(
+                            resource_exists::<SelectionTargets>
+                                .and(resource_exists::<SelTargetBrushSide>)
+                                .and(input_just_pressed(Binding::Primary)),
+                        ),
+                    )
+                        .run_if(
+                            not(in_state(CurrentTool::SetSurface))
+                                .and(not(in_state(CurrentTool::EditVertices))),
+                        ),
+                    (
+                        set_surface_on_side.run_if(
+                            resource_exists::<SelectionTargets>
+                                .and(resource_exists::<SelTargetBrushSide>)
+                                .and(resource_exists::<SelectedSurface>)
+                                .and(input_just_pressed(Binding::Primary)),
+                        ),
+                        enter_state(CurrentTool::Select)
+                            .run_if(input_just_pressed(KeyCode::Escape)),
+                    )
+                        .run_if(in_state(CurrentTool::SetSurface)),
+                    (
+                        start_dragging_corners.run_if(
+                            resource_exists::<SelectionTargets>
+                                .and(resource_exists::<SelTargetBrushCorners>)
+                                .and(input_just_pressed(Binding::Primary)),
+                        ),
+                        enter_state(CurrentTool::Select).run_if(
+                            input_just_pressed(KeyCode::Escape)
+                                .or(input_just_pressed(Binding::EditVertices)),
+                        ),
+                    )
+                        .run_if(in_state(CurrentTool::EditVertices)),
+                    enter_state(CurrentTool::EditVertices).run_if(
+                        not(in_state(CurrentTool::EditVertices))
+                            .and(input_just_pressed(Binding::EditVertices)),
                     ),
                     remove_node.run_if(
                         input_just_pressed(KeyCode::Delete)
@@ -238,6 +489,38 @@ pub fn plugin(app: &mut App) {
                     add_light.run_if(
                         resource_exists::<SelectedPos>.and(input_just_pressed(KeyCode::KeyI)),
                     ),
+                    edit_side_uv(|uv, size| *uv = SideUv::fit(size)).run_if(
+                        resource_exists::<SelectionTargets>
+                            .and(resource_exists::<SelTargetBrushSide>)
+                            .and(input_just_pressed(Binding::FitSurface)),
+                    ),
+                    edit_side_uv(|uv, _| {
+                        uv.align = match uv.align {
+                            UvAlign::World => UvAlign::Face,
+                            UvAlign::Face => UvAlign::World,
+                        }
+                    })
+                    .run_if(
+                        resource_exists::<SelectionTargets>
+                            .and(resource_exists::<SelTargetBrushSide>)
+                            .and(input_just_pressed(Binding::ToggleUvAlign)),
+                    ),
+                    rotate_brush(BRUSH_ROTATION_STEP).run_if(
+                        resource_exists::<SelectionTargets>
+                            .and(input_just_pressed(Binding::RotateBrush)),
+                    ),
+                    rotate_brush(-BRUSH_ROTATION_STEP).run_if(
+                        resource_exists::<SelectionTargets>
+                            .and(input_just_pressed(Binding::RotateBrushBack)),
+                    ),
+                    cycle_brush_shape.run_if(input_just_pressed(Binding::CycleBrushShape)),
+                    carve_with_focused.run_if(
+                        resource_exists::<SelectionTargets>.and(input_just_pressed(Binding::Carve)),
+                    ),
+                    generate_here.run_if(
+                        resource_exists::<SelectedPos>.and(input_just_pressed(Binding::Generate)),
+                    ),
+                    cycle_generator.run_if(input_just_pressed(Binding::CycleGenerator)),
                 )
                     .run_if(in_state(EditorAction::None)),
                 (
@@ -255,6 +538,12 @@ pub fn plugin(app: &mut App) {
                     ),
                 )
                     .run_if(in_state(EditorAction::ResizeBrush)),
+                (
+                    live_corner_drag
+                        .run_if(resource_exists::<SelectedPos>.and(on_event::<SelectionChanged>)),
+                    end_dragging_corners.run_if(input_just_released(Binding::Primary)),
+                )
+                    .run_if(in_state(EditorAction::DragCorners)),
                 cancel_action.run_if(
                     not(in_state(EditorAction::None)).and(input_just_pressed(KeyCode::Escape)),
                 ),
@@ -263,5 +552,6 @@ pub fn plugin(app: &mut App) {
                 .in_set(EditorSystems),
         )
         .add_systems(OnExit(EditorAction::BuildBrush), build_brush_cleanup)
-        .add_systems(OnExit(EditorAction::ResizeBrush), resize_brush_cleanup);
+        .add_systems(OnExit(EditorAction::ResizeBrush), resize_brush_cleanup)
+        .add_systems(OnExit(EditorAction::DragCorners), drag_corners_cleanup);
 }
@@ -0,0 +1,232 @@
+use bevy::{
+    color::palettes::css,
+    platform::collections::{HashMap, HashSet},
+    prelude::*,
+};
+use redb::ReadableTable;
+
+use crate::{
+    core::{
+        db::{Db, NotFound, Typed},
+        map::{
+            get_current_meta,
+            history::{
+                ancestors, format_timestamp, read_all_hist_nodes, HistNode, JumpToHistoryNode,
+                UpdateCurrentHistNode, TBL_HIST_NODES,
+            },
+            session::{MapClosed, MapSession},
+            states::{read_state, StateDelta, StoredState, TBL_STATES},
+        },
+        AppState,
+    },
+    editor::{ui::PreventClicks, EditorSystems},
+    id::Id,
+};
+
+/// Lists the branching map history and lets the user jump to any node in it.
+#[derive(Component)]
+pub struct HistoryPanel;
+
+#[derive(Component)]
+struct HistNodeButton(Id);
+
+/// Exists when the history panel needs to be rebuilt.
+#[derive(Resource)]
+struct HistoryPanelDirty;
+
+/// Change summaries by history node id. A node never changes what it stores, so a summary only has
+/// to be worked out once, which matters for keyframes that are diffed against their parent.
+#[derive(Resource, Default, Deref, DerefMut)]
+struct ChangeSummaries(HashMap<Id, String>);
+
+struct HistRow {
+    id: Id,
+    column: usize,
+    is_current: bool,
+    label: String,
+}
+
+fn change_summary(
+    tbl_states: &impl ReadableTable<Id, Typed<StoredState>>,
+    node: &HistNode,
+    parent: Option<&HistNode>,
+) -> Result<String> {
+    let Some(parent) = parent else {
+        return Ok("initial state".to_string());
+    };
+    let delta = match tbl_states.get(node.state_id)?.ok_or(NotFound)?.value() {
+        StoredState::Delta(delta) => delta,
+        StoredState::Keyframe(state) => StateDelta::between(
+            parent.state_id,
+            0,
+            &read_state(tbl_states, parent.state_id)?,
+            &state,
+        ),
+    };
+    Ok(format!(
+        "+{} ~{} -{}",
+        delta.added.len(),
+        delta.changed.len(),
+        delta.removed.len()
+    ))
+}
+
+/// Flattens the history tree into rows, newest first.
+/// The first child of a node continues in the same column, later children branch off.
+fn history_rows(db: &Db, summaries: &mut ChangeSummaries) -> Result<Vec<HistRow>> {
+    let reader = db.begin_read()?;
+    let meta = get_current_meta(&reader)?;
+    let nodes = read_all_hist_nodes(&reader.open_table(TBL_HIST_NODES)?)?;
+    let tbl_states = reader.open_table(TBL_STATES)?;
+
+    let root = *ancestors(&nodes, meta.hist_node_id).last().unwrap();
+
+    let mut rows = Vec::new();
+    let mut visited = HashSet::new();
+    let mut stack = vec![(root, 0)];
+    while let Some((id, column)) = stack.pop() {
+        let Some(node) = nodes.get(&id).filter(|_| visited.insert(id)) else {
+            continue;
+        };
+        let summary = match summaries.get(&id) {
+            Some(summary) => summary.clone(),
+            None => {
+                let parent = node.parent_id.and_then(|parent_id| nodes.get(&parent_id));
+                let summary = change_summary(&tbl_states, node, parent)?;
+                summaries.insert(id, summary.clone());
+                summary
+            }
+        };
+        rows.push(HistRow {
+            id,
+            column,
+            is_current: id == meta.hist_node_id,
+            label: format!(
+                "{}  {}  ({})",
+                format_timestamp(node.timestamp),
+                node.label,
+                summary
+            ),
+        });
+        for (idx, child_id) in node.child_ids.iter().enumerate().rev() {
+            stack.push((*child_id, column + idx));
+        }
+    }
+    rows.reverse();
+    Ok(rows)
+}
+
+fn init_history_panel(mut commands: Commands) {
+    commands.spawn((
+        PreventClicks,
+        StateScoped(AppState::InEditor),
+        Interaction::default(),
+        Node {
+            position_type: PositionType::Absolute,
+            right: Val::Px(0.0),
+            display: Display::Flex,
+            flex_direction: FlexDirection::Column,
+            width: Val::Px(320.0),
+            height: Val::Percent(100.0),
+            padding: UiRect::all(Val::Px(16.)),
+            align_items: AlignItems::Start,
+            justify_content: JustifyContent::Start,
+            overflow: Overflow::clip_y(),
+            ..default()
+        },
+        BackgroundColor(Color::Srgba(css::BLACK)),
+        HistoryPanel,
+    ));
+    commands.insert_resource(HistoryPanelDirty);
+}
+
+fn mark_history_dirty(mut commands: Commands) {
+    commands.insert_resource(HistoryPanelDirty);
+}
+
+fn on_hist_node_changed(_: Trigger<UpdateCurrentHistNode>, mut commands: Commands) {
+    commands.insert_resource(HistoryPanelDirty);
+}
+
+fn on_map_closed(
+    _: Trigger<MapClosed>,
+    mut summaries: ResMut<ChangeSummaries>,
+    mut commands: Commands,
+) {
+    summaries.clear();
+    commands.insert_resource(HistoryPanelDirty);
+}
+
+fn rebuild_history_panel(
+    db: Option<Res<Db>>,
+    mut summaries: ResMut<ChangeSummaries>,
+    q_panels: Query<Entity, With<HistoryPanel>>,
+    mut commands: Commands,
+) -> Result {
+    commands.remove_resource::<HistoryPanelDirty>();
+    let rows = match db {
+        Some(db) => history_rows(&db, &mut summaries)?,
+        None => Vec::new(),
+    };
+
+    for panel_entity in q_panels.iter() {
+        let mut entity_cmds = commands.entity(panel_entity);
+        entity_cmds.despawn_related::<Children>();
+        entity_cmds.with_children(|builder| {
+            for row in rows.iter() {
+                builder
+                    .spawn((
+                        Node {
+                            margin: UiRect::left(Val::Px(row.column as f32 * 12.0)),
+                            padding: UiRect::all(Val::Px(4.)),
+                            ..default()
+                        },
+                        Button,
+                        PreventClicks,
+                        HistNodeButton(row.id),
+                        BackgroundColor(if row.is_current {
+                            Color::Srgba(css::DARK_GOLDENROD)
+                        } else {
+                            Color::NONE
+                        }),
+                    ))
+                    .with_child((
+                        Text::new(row.label.clone()),
+                        TextFont {
+                            font_size: 12.0,
+                            ..default()
+                        },
+                    ));
+            }
+        });
+    }
+    Ok(())
+}
+
+fn click_hist_node(
+    q_buttons: Query<(&Interaction, &HistNodeButton), Changed<Interaction>>,
+    mut commands: Commands,
+) {
+    for (interaction, button) in q_buttons.iter() {
+        if interaction == &Interaction::Pressed {
+            commands.trigger(JumpToHistoryNode { id: button.0 });
+        }
+    }
+}
+
+pub fn plugin(app: &mut App) {
+    app.init_resource::<ChangeSummaries>();
+    app.add_observer(on_hist_node_changed);
+    app.add_observer(on_map_closed);
+    app.add_systems(OnEnter(AppState::InEditor), init_history_panel);
+    app.add_systems(
+        Update,
+        (
+            mark_history_dirty.run_if(resource_exists_and_changed::<MapSession>),
+            click_hist_node.run_if(resource_exists::<Db>),
+            rebuild_history_panel.run_if(resource_exists::<HistoryPanelDirty>),
+        )
+            .chain()
+            .in_set(EditorSystems),
+    );
+}
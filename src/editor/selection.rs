@@ -10,9 +10,9 @@ use crate::{
     editor::{
         cursor::{CursorMode, SpatialAxis, SpatialCursor},
         freelook::FreelookState,
+        tools::CurrentTool,
         EditorSystems,
     },
-    util::Facing3d,
 };
 
 /// Exists when a position is selected via the spatial cursor.
@@ -111,8 +111,9 @@ fn scroll_intersecting(num: i32) -> impl Fn(ResMut<SelectionTargets>) {
     }
 }
 
+/// Index of the brush face closest to the selected position, on the focused brush.
 #[derive(Resource, Deref)]
-pub struct SelTargetBrushSide(pub Facing3d);
+pub struct SelTargetBrushSide(pub usize);
 
 fn sel_brush_test(
     sel_pos: Res<SelectedPos>,
@@ -124,28 +125,27 @@ fn sel_brush_test(
 ) {
     if let Some(sel_targets) = sel_targets {
         if let Ok(brush) = brushes.get(sel_targets.focused.entity) {
-            let closest_side = brush
-                .bounds
-                .sides_world()
-                .sorted_by(|side_a, side_b| {
-                    sel_pos
-                        .distance(side_a.pos)
-                        .total_cmp(&sel_pos.distance(side_b.pos))
+            let Some((closest_side, polygon)) = brush
+                .polygons()
+                .into_iter()
+                .enumerate()
+                .filter(|(_, polygon)| !polygon.is_empty())
+                .min_by(|(side_a, _), (side_b, _)| {
+                    let dist =
+                        |side: &usize| brush.faces[*side].plane.signed_distance(**sel_pos).abs();
+                    dist(side_a).total_cmp(&dist(side_b))
                 })
-                .next()
-                .unwrap();
+            else {
+                return;
+            };
 
-            gizmos.rect(
-                Isometry3d::new(
-                    closest_side.pos,
-                    Quat::from_rotation_arc(Vec3::NEG_Z, *closest_side.facing.as_dir()),
-                ),
-                closest_side.size,
+            gizmos.linestrip(
+                polygon.iter().chain(polygon.first()).copied(),
                 css::INDIAN_RED,
             );
 
-            if sel_brush_target_side.is_none_or(|side| side.0 != closest_side.facing) {
-                commands.insert_resource(SelTargetBrushSide(closest_side.facing));
+            if sel_brush_target_side.is_none_or(|side| side.0 != closest_side) {
+                commands.insert_resource(SelTargetBrushSide(closest_side));
             }
         }
     } else if sel_brush_target_side.is_some() {
@@ -153,6 +153,63 @@ fn sel_brush_test(
     }
 }
 
+/// Corners of the focused brush closest to the selected position, one for a vertex or two for an
+/// edge.
+#[derive(Resource, Deref)]
+pub struct SelTargetBrushCorners(pub Vec<Vec3>);
+
+fn sel_brush_corner_test(
+    sel_pos: Res<SelectedPos>,
+    sel_targets: Option<Res<SelectionTargets>>,
+    sel_target_corners: Option<Res<SelTargetBrushCorners>>,
+    brushes: Query<&Brush>,
+    mut gizmos: Gizmos<SelHighlightGizmos>,
+    mut commands: Commands,
+) {
+    let Some(brush) = sel_targets.and_then(|targets| brushes.get(targets.focused.entity).ok())
+    else {
+        if sel_target_corners.is_some() {
+            commands.remove_resource::<SelTargetBrushCorners>();
+        }
+        return;
+    };
+
+    let segment_distance = |(start, end): (Vec3, Vec3)| {
+        let t =
+            ((**sel_pos - start).dot(end - start) / start.distance_squared(end)).clamp(0.0, 1.0);
+        sel_pos.distance(start.lerp(end, t))
+    };
+    // Vertices come first, so they win from the edges ending in them.
+    let Some((_, closest)) = brush
+        .corners()
+        .into_iter()
+        .map(|corner| (sel_pos.distance(corner), vec![corner]))
+        .chain(
+            brush
+                .edges()
+                .into_iter()
+                .map(|edge| (segment_distance(edge), vec![edge.0, edge.1])),
+        )
+        .min_by(|(dist_a, _), (dist_b, _)| dist_a.total_cmp(dist_b))
+    else {
+        return;
+    };
+
+    match closest.as_slice() {
+        [corner] => {
+            gizmos.sphere(Isometry3d::from_translation(*corner), 0.1, css::INDIAN_RED);
+        }
+        [start, end] => {
+            gizmos.line(*start, *end, css::INDIAN_RED);
+        }
+        _ => {}
+    }
+
+    if sel_target_corners.is_none_or(|corners| corners.0 != closest) {
+        commands.insert_resource(SelTargetBrushCorners(closest));
+    }
+}
+
 #[derive(Default, Reflect, GizmoConfigGroup)]
 pub struct SelAxisGizmos {}
 
@@ -298,7 +355,11 @@ pub fn plugin(app: &mut App) {
             Update,
             (
                 find_targets_at_selection.run_if(on_event::<SelectionChanged>),
-                sel_brush_test.run_if(resource_exists::<SelectedPos>),
+                (
+                    sel_brush_test.run_if(not(in_state(CurrentTool::EditVertices))),
+                    sel_brush_corner_test.run_if(in_state(CurrentTool::EditVertices)),
+                )
+                    .run_if(resource_exists::<SelectedPos>),
             )
                 .chain()
                 .in_set(EditorSystems),
@@ -1,5 +1,10 @@
 use bevy::prelude::*;
 
+use crate::{
+    core::map::elements::brush::{Brush, BrushBounds},
+    id::Id,
+};
+
 #[derive(States, Default, Debug, Clone, PartialEq, Eq, Hash)]
 pub enum CurrentTool {
     #[default]
@@ -8,8 +13,42 @@ pub enum CurrentTool {
     ResizeBrush,
     SetSurface,
     AddLight,
+    /// Dragging corners and edges of brushes.
+    EditVertices,
+}
+
+/// Surface picked from the surface list, applied to brush sides by `CurrentTool::SetSurface`.
+#[derive(Resource, Deref, Clone, Copy)]
+pub struct SelectedSurface(pub Id);
+
+/// Shape of new brushes, fitted into the box dragged out when building.
+#[derive(Resource, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BrushShape {
+    #[default]
+    Box,
+    Ramp,
+    Pillar,
+}
+
+impl BrushShape {
+    pub fn next(self) -> Self {
+        match self {
+            BrushShape::Box => BrushShape::Ramp,
+            BrushShape::Ramp => BrushShape::Pillar,
+            BrushShape::Pillar => BrushShape::Box,
+        }
+    }
+
+    pub fn build(self, bounds: &BrushBounds) -> Brush {
+        match self {
+            BrushShape::Box => Brush::cuboid(bounds),
+            BrushShape::Ramp => Brush::ramp(bounds),
+            BrushShape::Pillar => Brush::pillar(bounds),
+        }
+    }
 }
 
 pub fn plugin(app: &mut App) {
     app.init_state::<CurrentTool>();
+    app.init_resource::<BrushShape>();
 }
@@ -1,8 +1,13 @@
 use bevy::{color::palettes::css, platform::collections::HashSet, prelude::*, ui::BackgroundColor};
 
-use crate::core::{
-    media::{surface::Surface, MediaCollection},
-    AppState,
+use crate::{
+    core::{
+        map::changes::ChangeRejected,
+        media::{surface::Surface, MediaCollection},
+        AppState,
+    },
+    editor::tools::{CurrentTool, SelectedSurface},
+    id::Id,
 };
 
 #[derive(Component)]
@@ -22,6 +27,7 @@ impl ClickBlocker {
 #[allow(clippy::type_complexity)]
 fn clicktest(
     q_changed_inter: Query<(Entity, &Interaction), (With<PreventClicks>, Changed<Interaction>)>,
+    mut q_removed: RemovedComponents<PreventClicks>,
     mut click_blocker: ResMut<ClickBlocker>,
 ) {
     for (entity, inter) in &q_changed_inter {
@@ -30,11 +36,22 @@ fn clicktest(
             Interaction::None => click_blocker.remove(&entity),
         };
     }
+
+    // Entities can be despawned while hovered, e.g. when a list is rebuilt.
+    for entity in q_removed.read() {
+        click_blocker.remove(&entity);
+    }
 }
 
 #[derive(Component)]
 pub struct SurfaceList;
 
+#[derive(Component, Deref)]
+struct SurfaceButton(Id);
+
+const SURFACE_BUTTON_COLOR: Srgba = css::BLACK;
+const SELECTED_SURFACE_BUTTON_COLOR: Srgba = css::DARK_SLATE_GRAY;
+
 fn init_ui(mut commands: Commands) {
     commands.spawn((
         PreventClicks,
@@ -69,9 +86,10 @@ fn update_surf_list(
         let mut entity_cmds = commands.entity(list_entity);
         entity_cmds.despawn_related::<Children>();
         entity_cmds.with_children(|builder| {
-            for (_id, surface) in surfaces.iter() {
+            for (id, surface) in surfaces.iter() {
                 builder
                     .spawn((
+                        SurfaceButton(*id),
                         Node {
                             padding: UiRect {
                                 left: Val::Px(8.),
@@ -82,6 +100,7 @@ fn update_surf_list(
                             ..default()
                         },
                         Button,
+                        BackgroundColor(Color::Srgba(SURFACE_BUTTON_COLOR)),
                     ))
                     .with_child(Text::new(surface.meta.path.to_string_lossy()));
             }
@@ -89,14 +108,89 @@ fn update_surf_list(
     }
 }
 
+/// Picking a surface switches to the tool that applies it.
+fn select_surface(
+    q_buttons: Query<(&Interaction, &SurfaceButton), Changed<Interaction>>,
+    mut next_tool: ResMut<NextState<CurrentTool>>,
+    mut commands: Commands,
+) {
+    for (interaction, button) in q_buttons.iter() {
+        if interaction == &Interaction::Pressed {
+            commands.insert_resource(SelectedSurface(**button));
+            next_tool.set(CurrentTool::SetSurface);
+        }
+    }
+}
+
+fn highlight_selected_surface(
+    selected: Option<Res<SelectedSurface>>,
+    tool: Res<State<CurrentTool>>,
+    mut q_buttons: Query<(&SurfaceButton, &mut BackgroundColor)>,
+) {
+    let selected = selected
+        .filter(|_| tool.get() == &CurrentTool::SetSurface)
+        .map(|selected| **selected);
+    for (button, mut color) in q_buttons.iter_mut() {
+        let new_color = if Some(**button) == selected {
+            SELECTED_SURFACE_BUTTON_COLOR
+        } else {
+            SURFACE_BUTTON_COLOR
+        };
+        color.set_if_neq(BackgroundColor(Color::Srgba(new_color)));
+    }
+}
+
+/// Message at the bottom of the screen, removed after a few seconds.
+#[derive(Component, Deref, DerefMut)]
+struct Notice(Timer);
+
+const NOTICE_SECONDS: f32 = 4.0;
+
+fn notify_change_rejected(trigger: Trigger<ChangeRejected>, mut commands: Commands) {
+    commands.spawn((
+        StateScoped(AppState::InEditor),
+        Notice(Timer::from_seconds(NOTICE_SECONDS, TimerMode::Once)),
+        Node {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(16.0),
+            left: Val::Px(316.0),
+            padding: UiRect::all(Val::Px(8.)),
+            ..default()
+        },
+        BackgroundColor(Color::Srgba(css::DARK_RED)),
+        children![(
+            Text::new(format!("{}: {}", trigger.label, trigger.reason)),
+            TextFont {
+                font_size: 14.0,
+                ..default()
+            },
+        )],
+    ));
+}
+
+fn expire_notices(
+    time: Res<Time>,
+    mut q_notices: Query<(Entity, &mut Notice)>,
+    mut commands: Commands,
+) {
+    for (entity, mut notice) in q_notices.iter_mut() {
+        if notice.tick(time.delta()).finished() {
+            commands.entity(entity).despawn();
+        }
+    }
+}
+
 pub fn plugin(app: &mut App) {
     app.init_resource::<ClickBlocker>();
+    app.add_observer(notify_change_rejected);
     app.add_systems(OnEnter(AppState::InEditor), init_ui);
     app.add_systems(
         Update,
         (
             clicktest,
+            expire_notices,
             update_surf_list.run_if(resource_exists_and_changed::<MediaCollection<Surface>>),
+            (select_surface, highlight_selected_surface).chain(),
         ),
     );
 }
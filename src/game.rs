@@ -6,33 +6,36 @@ use bevy::prelude::*;
 
 use crate::{
     core::{
+        map::properties::MapProperties,
         view::{GimbalPos, GimbalRotatesParent},
         AppState,
     },
     util::{grab_mouse, release_mouse},
 };
 
-#[derive(Resource)]
+#[derive(Resource, Clone)]
 pub struct GameRules {
     pub spawn: GimbalPos,
+    pub gravity: Vec3,
 }
 
-impl Default for GameRules {
-    fn default() -> Self {
+impl GameRules {
+    pub fn from_properties(properties: &MapProperties) -> Self {
         Self {
-            spawn: GimbalPos {
-                pos: Vec3::Y * 5.0,
-                rot: default(),
-            },
+            spawn: properties.spawn,
+            gravity: properties.gravity,
         }
     }
 }
 
-fn init_game(mut commands: Commands, init_conf: Option<Res<GameRules>>) {
-    let default_conf = GameRules::default();
+fn init_game(
+    mut commands: Commands,
+    init_conf: Option<Res<GameRules>>,
+    properties: Res<MapProperties>,
+) {
     let conf = init_conf
-        .map(|res| res.into_inner())
-        .unwrap_or(&default_conf);
+        .map(|res| res.clone())
+        .unwrap_or_else(|| GameRules::from_properties(&properties));
 
     let player_head_height = 1.0;
     let player_coll = Collider::capsule(0.4, 1.0);
@@ -61,6 +64,7 @@ fn init_game(mut commands: Commands, init_conf: Option<Res<GameRules>>) {
             GimbalRotatesParent,
         )],
     ));
+    commands.insert_resource(conf);
 }
 
 fn teardown_game(_: Commands) {
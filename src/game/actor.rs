@@ -3,7 +3,7 @@ use bevy::{math::vec2, prelude::*};
 
 use crate::{
     core::binds::{Binding, BindingAxis, BindingAxisFns, InputBindingSystem},
-    game::GameSystems,
+    game::{GameRules, GameSystems},
 };
 
 #[derive(Component)]
@@ -55,11 +55,14 @@ fn update_grounded(
 const ACCEL: f32 = 60.0;
 const AIR_ACCEL: f32 = 14.0;
 const TOP_SPEED: f32 = 6.0;
-const GRAV: f32 = 9.81 * 2.0;
 const JUMP: f32 = 8.0;
 
-fn apply_gravity(time: Res<Time>, mut q_actors: Query<&mut LinearVelocity, With<PlayerActor>>) {
-    let gravity = Vec3::NEG_Y * GRAV;
+fn apply_gravity(
+    time: Res<Time>,
+    rules: Res<GameRules>,
+    mut q_actors: Query<&mut LinearVelocity, With<PlayerActor>>,
+) {
+    let gravity = rules.gravity;
     for mut linear_velocity in &mut q_actors.iter_mut() {
         linear_velocity.0 += gravity * time.delta_secs();
     }
@@ -14,6 +14,14 @@ impl std::fmt::Display for Id {
     }
 }
 
+impl std::str::FromStr for Id {
+    type Err = ulid::DecodeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Id(Ulid::from_string(s)?))
+    }
+}
+
 #[derive(Resource)]
 pub struct IdGen(ulid::Generator);
 
@@ -0,0 +1,373 @@
+//! Headless inspection, import, export, checking, repair and editing of map files, without
+//! starting the app.
+
+use std::path::Path;
+
+use bevy::{platform::collections::HashSet, prelude::*};
+use clap::Subcommand;
+use itertools::Itertools;
+use redb::{ReadTransaction, ReadableTable, ReadableTableMetadata};
+
+use crate::{
+    core::{
+        db::{Db, DbError, TBL_OBJECTS},
+        map::{
+            db_is_initialized,
+            document::{export_state, import_document, read_document},
+            edits::EditScript,
+            elements::{ElementRoleRegistry, Info},
+            fsck::check_map,
+            get_current_hist_node, get_current_meta, get_current_state,
+            history::{ancestors, format_timestamp, read_all_hist_nodes, TBL_HIST_NODES},
+            init_db,
+            migrations::{
+                migrate_legacy_states, migrate_schema, pending_migrations, verify_records, OnBroken,
+            },
+            repair::repair_map,
+            session::map_name_from_path,
+            states::{read_state, TBL_STATES},
+        },
+    },
+    id::{Id, IdGen},
+};
+
+#[derive(Subcommand)]
+pub enum InspectCommand {
+    /// Print the map's meta record.
+    Meta,
+    /// Print the history tree. The current node is marked with `*`.
+    History,
+    /// List the elements of a map state.
+    Elements {
+        /// State to list. Defaults to the state of the current history node.
+        #[arg(long)]
+        state: Option<Id>,
+        /// Also print the params of each element.
+        #[arg(long)]
+        params: bool,
+    },
+    /// List stored objects and their sizes.
+    Objects,
+}
+
+fn open_initialized_map_file(path: &Path) -> Result<Db> {
+    let db = Db::open(path)?;
+    if !db_is_initialized(&db.begin_read()?) {
+        return Err(format!("{:?} is not an initialized map file", path).into());
+    }
+    Ok(db)
+}
+
+/// Opens a map file for reading, it's never written to. Maps written by older editors can't be
+/// read until they are upgraded with `migrate`.
+fn open_current_map_file(path: &Path) -> Result<Db> {
+    let db = open_initialized_map_file(path)?;
+    let pending = pending_migrations(
+        &db.begin_read()?,
+        &ElementRoleRegistry::with_builtin_roles(),
+    )?;
+    if !pending.is_empty() {
+        return Err(format!(
+            "{:?} was written by an older editor, {} need upgrading. Run `{} migrate {}` first",
+            path,
+            pending,
+            env!("CARGO_PKG_NAME"),
+            path.display()
+        )
+        .into());
+    }
+    Ok(db)
+}
+
+/// Like `open_current_map_file`, but also fails on damaged records, which would crash whatever
+/// reads them later.
+fn open_map_file(path: &Path) -> Result<Db> {
+    let db = open_current_map_file(path)?;
+    if let Err(err) = verify_records(&db.begin_read()?) {
+        return Err(format!(
+            "{:?} is damaged, {}. Run `{} repair {}` to drop what can't be read",
+            path,
+            err,
+            env!("CARGO_PKG_NAME"),
+            path.display()
+        )
+        .into());
+    }
+    Ok(db)
+}
+
+/// Copies the map file next to itself, before it's changed.
+fn backup_map_file(path: &Path) -> Result {
+    let backup_path = path.with_extension(format!(
+        "{}.bak",
+        path.extension()
+            .map(|ext| ext.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    ));
+    std::fs::copy(path, &backup_path).map_err(DbError::Io)?;
+    println!("Backed up {:?} to {:?}", path, backup_path);
+    Ok(())
+}
+
+/// Opens the map file and prints what was asked for.
+pub fn run(path: &Path, command: &InspectCommand) -> Result {
+    let db = open_map_file(path)?;
+    let reader = db.begin_read()?;
+
+    match command {
+        InspectCommand::Meta => print_meta(&reader),
+        InspectCommand::History => print_history(&reader),
+        InspectCommand::Elements { state, params } => print_elements(&reader, *state, *params),
+        InspectCommand::Objects => print_objects(&reader),
+    }
+}
+
+/// Writes a map state to a text document.
+pub fn export(path: &Path, out: &Path, state_id: Option<Id>) -> Result {
+    let db = open_map_file(path)?;
+    let reader = db.begin_read()?;
+    let state_id = match state_id {
+        Some(id) => id,
+        None => get_current_hist_node(&reader)?.state_id,
+    };
+    let document = export_state(
+        &reader,
+        &ElementRoleRegistry::with_builtin_roles(),
+        state_id,
+    )?;
+    std::fs::write(out, document.to_ron()?)?;
+    println!(
+        "Exported {} elements of state {} to {:?}",
+        document.elements.len(),
+        state_id,
+        out
+    );
+    Ok(())
+}
+
+/// Creates a new map file from a text document.
+pub fn import(document_path: &Path, path: &Path) -> Result {
+    let document = read_document(document_path)?;
+    let count = document.elements.len();
+    import_document(
+        path,
+        document,
+        &ElementRoleRegistry::with_builtin_roles(),
+        &mut IdGen::default(),
+    )?;
+    println!(
+        "Imported {} elements from {:?} into {:?}",
+        count, document_path, path
+    );
+    Ok(())
+}
+
+/// Checks the integrity of the map and prints every issue found.
+/// Fails if there are any issues, so it can be used in scripts.
+pub fn check(path: &Path) -> Result {
+    // Damaged records are reported by the check itself.
+    let db = open_current_map_file(path)?;
+    let report = check_map(
+        &db.begin_read()?,
+        &ElementRoleRegistry::with_builtin_roles(),
+    )?;
+    for issue in report.issues.iter() {
+        println!("{}", issue);
+    }
+    println!("{}", report);
+    if !report.is_ok() {
+        return Err(format!("{:?} has {} issues", path, report.issues.len()).into());
+    }
+    Ok(())
+}
+
+/// Upgrades a map written by an older editor to the current format.
+/// A backup of the original file is written next to it first.
+pub fn migrate(path: &Path) -> Result {
+    let db = open_initialized_map_file(path)?;
+    let registry = ElementRoleRegistry::with_builtin_roles();
+    if pending_migrations(&db.begin_read()?, &registry)?.is_empty() {
+        println!("{:?} is up to date", path);
+        return Ok(());
+    }
+    drop(db);
+
+    backup_map_file(path)?;
+    let db = open_initialized_map_file(path)?;
+    migrate_legacy_states(&db, &mut OnBroken::Fail)?;
+    let report = migrate_schema(&db, &registry, &mut OnBroken::Fail)?;
+    println!("Upgraded {}", report);
+    Ok(())
+}
+
+/// Upgrades the map, dropping whatever can't be upgraded, then drops elements that can't be loaded
+/// from every state of the map. A backup of the original file is written next to it first.
+pub fn repair(path: &Path) -> Result {
+    backup_map_file(path)?;
+
+    let db = open_initialized_map_file(path)?;
+    let report = repair_map(&db, &ElementRoleRegistry::with_builtin_roles())?;
+    for record in report.records.iter() {
+        println!(
+            "{} {}: dropped: {}",
+            record.table, record.key, record.reason
+        );
+    }
+    for relinked in report.relinked.iter() {
+        println!("{}", relinked);
+    }
+    for dropped in report.dropped.iter() {
+        println!(
+            "state {}: dropped element {}: {}",
+            dropped.state_id, dropped.elem_id, dropped.reason
+        );
+    }
+    println!("{}", report);
+    Ok(())
+}
+
+/// Applies an edit script to a map as a single history node. The map is created if it doesn't
+/// exist yet, and removed again if the edits fail.
+pub fn edit(path: &Path, script: &EditScript) -> Result {
+    let registry = ElementRoleRegistry::with_builtin_roles();
+    let mut id_gen = IdGen::default();
+    let created = !path.exists();
+    let db = if created {
+        let db = Db::new(path)?;
+        init_db(&db, map_name_from_path(path), &mut id_gen)?;
+        db
+    } else {
+        open_map_file(path)?
+    };
+
+    match script.apply_to_map(&db, &registry, &mut id_gen, "Edit script") {
+        Ok(hist_id) => {
+            println!(
+                "Applied {} edits to {:?}, history node {}",
+                script.0.len(),
+                path,
+                hist_id
+            );
+            Ok(())
+        }
+        Err(err) => {
+            if created {
+                drop(db);
+                std::fs::remove_file(path).map_err(DbError::Io)?;
+            }
+            Err(err)
+        }
+    }
+}
+
+fn print_meta(reader: &ReadTransaction) -> Result {
+    let meta = get_current_meta(reader)?;
+    let properties = get_current_state(reader)?.properties;
+    println!("name:         {}", properties.name);
+    println!("author:       {}", properties.author);
+    println!("history node: {}", meta.hist_node_id);
+    println!("editor:       {:#?}", meta.editor_context);
+    println!();
+    println!(
+        "{} history nodes, {} states, {} objects",
+        reader.open_table(TBL_HIST_NODES)?.len()?,
+        reader.open_table(TBL_STATES)?.len()?,
+        reader.open_table(TBL_OBJECTS)?.len()?
+    );
+    Ok(())
+}
+
+fn print_history(reader: &ReadTransaction) -> Result {
+    let meta = get_current_meta(reader)?;
+    let nodes = read_all_hist_nodes(&reader.open_table(TBL_HIST_NODES)?)?;
+    let root = *ancestors(&nodes, meta.hist_node_id).last().unwrap();
+
+    // Same layout as the history panel: the first child continues at the same depth.
+    let mut visited = HashSet::new();
+    let mut stack = vec![(root, 0)];
+    while let Some((id, depth)) = stack.pop() {
+        let Some(node) = nodes.get(&id).filter(|_| visited.insert(id)) else {
+            continue;
+        };
+        let marker = if id == meta.hist_node_id { '*' } else { ' ' };
+        println!(
+            "{} {}{}  {}  {}  (state {})",
+            marker,
+            "  ".repeat(depth),
+            id,
+            format_timestamp(node.timestamp),
+            node.label,
+            node.state_id
+        );
+        for (idx, child_id) in node.child_ids.iter().enumerate().rev() {
+            stack.push((*child_id, depth + idx));
+        }
+    }
+
+    let unreachable = nodes.len() - visited.len();
+    if unreachable > 0 {
+        println!(
+            "({} history nodes not reachable from the root)",
+            unreachable
+        );
+    }
+    Ok(())
+}
+
+fn print_elements(reader: &ReadTransaction, state_id: Option<Id>, params: bool) -> Result {
+    let state_id = match state_id {
+        Some(id) => id,
+        None => get_current_hist_node(reader)?.state_id,
+    };
+    let state = read_state(&reader.open_table(TBL_STATES)?, state_id)?;
+    let tbl_objects = reader.open_table(TBL_OBJECTS)?;
+    let registry = ElementRoleRegistry::with_builtin_roles();
+
+    println!("state {}: {} elements", state_id, state.elements.len());
+    for (id, elem) in state.elements.iter().sorted_by_key(|(id, _)| **id) {
+        // Broken elements are printed too, this is where people look when a map won't open.
+        let name = match tbl_objects.get(&elem.info)? {
+            Some(object) => match object.value().cast::<Info>() {
+                Ok(info) => info.name,
+                Err(err) => format!("<{}>", err),
+            },
+            None => format!("<missing info object {}>", elem.info),
+        };
+        let role_name = registry
+            .role_id(elem.role)
+            .map(String::from)
+            .unwrap_or_else(|| format!("unknown role {:x}", elem.role));
+        println!("{}  {:<8} {}", id, role_name, name);
+
+        if params {
+            let Some(raw_params) = tbl_objects.get(&elem.params)? else {
+                println!("    <missing params object {}>", elem.params);
+                continue;
+            };
+            let raw_params = raw_params.value();
+            match registry.roles.get(&elem.role) {
+                Some(builder) => match builder.debug_params(&raw_params) {
+                    Ok(params) => println!("    {}", params),
+                    Err(err) => println!("    <{}>", err),
+                },
+                None => println!("    {} bytes", raw_params.data.len()),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_objects(reader: &ReadTransaction) -> Result {
+    let tbl_objects = reader.open_table(TBL_OBJECTS)?;
+    let mut count = 0;
+    let mut total = 0;
+    for entry in tbl_objects.iter()? {
+        let (checksum, object) = entry?;
+        let size = object.value().data.len();
+        println!("{}  {:>8} bytes", checksum.value().to_hex(), size);
+        count += 1;
+        total += size;
+    }
+    println!("{} objects, {} bytes total", count, total);
+    Ok(())
+}
@@ -4,17 +4,34 @@ mod editor;
 mod experimental;
 mod game;
 mod id;
+mod inspect;
 mod util;
 
+use std::path::PathBuf;
+
 use avian3d::{prelude::PhysicsInterpolationPlugin, PhysicsPlugins};
 use bevy::prelude::*;
 use clap::{Parser, Subcommand};
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{eyre, Result};
+
+use crate::{
+    core::map::{
+        document::{ImportMap, DOCUMENT_FILE_EXT},
+        edits::EditScript,
+        session::{OpenMap, StartupMap, MAP_FILE_EXT},
+    },
+    id::Id,
+    inspect::InspectCommand,
+};
 
 pub const APP_NAME: &str = "Mallet";
 
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Cli {
+    /// Map file to open in the editor. Created if it doesn't exist.
+    map: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
@@ -22,6 +39,57 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     Experiment,
+    /// Print the contents of a map file without opening the editor.
+    Inspect {
+        /// Map file to inspect.
+        path: PathBuf,
+
+        #[command(subcommand)]
+        what: InspectCommand,
+    },
+    /// Write a map state to a RON text document.
+    Export {
+        /// Map file to export from.
+        path: PathBuf,
+        /// Document to write.
+        out: PathBuf,
+        /// State to export. Defaults to the state of the current history node.
+        #[arg(long)]
+        state: Option<Id>,
+    },
+    /// Check a map file for broken links between history, states and objects.
+    Check {
+        /// Map file to check.
+        path: PathBuf,
+    },
+    /// Upgrade a map file written by an older editor. Keeps a backup of the file.
+    Migrate {
+        /// Map file to upgrade.
+        path: PathBuf,
+    },
+    /// Drop elements that can't be loaded from a damaged map file. Keeps a backup of the file.
+    Repair {
+        /// Map file to repair.
+        path: PathBuf,
+    },
+    /// Create a new map file from a RON text document and open it in the editor.
+    Import {
+        /// Document to import.
+        document: PathBuf,
+        /// Map file to create.
+        map: PathBuf,
+    },
+    /// Apply a RON edit script to a map as a single history entry and open it in the editor.
+    /// Nothing is applied if any of the edits fails.
+    Edit {
+        /// Map file to edit. Created if it doesn't exist.
+        map: PathBuf,
+        /// Edit script to apply, "-" reads it from stdin.
+        script: PathBuf,
+        /// Don't open the editor once the edits are applied.
+        #[arg(long)]
+        exit: bool,
+    },
 }
 
 fn main() -> Result<()> {
@@ -30,30 +98,59 @@ fn main() -> Result<()> {
     let cli = Cli::parse();
 
     match &cli.command {
-        None => {
-            App::new()
-                .add_plugins((
-                    app_data::plugin,
-                    DefaultPlugins,
-                    //PhysicsPlugins::default().set(PhysicsInterpolationPlugin::interpolate_all()),
-                    PhysicsPlugins::default(),
-                    core::plugin,
-                    editor::plugin,
-                    game::plugin,
-                ))
-                // Only update when there is user input. Should be disabled when in-game
-                //.insert_resource(WinitSettings::desktop_app())
-                .add_systems(PreUpdate, file_drop)
-                .run();
-        }
+        None => run_editor(cli.map.clone()),
         Some(Commands::Experiment) => {
             experimental::run_playground();
         }
+        Some(Commands::Inspect { path, what }) => {
+            inspect::run(path, what).map_err(|err| eyre!("{}", err))?;
+        }
+        Some(Commands::Export { path, out, state }) => {
+            inspect::export(path, out, *state).map_err(|err| eyre!("{}", err))?;
+        }
+        Some(Commands::Check { path }) => {
+            inspect::check(path).map_err(|err| eyre!("{}", err))?;
+        }
+        Some(Commands::Migrate { path }) => {
+            inspect::migrate(path).map_err(|err| eyre!("{}", err))?;
+        }
+        Some(Commands::Repair { path }) => {
+            inspect::repair(path).map_err(|err| eyre!("{}", err))?;
+        }
+        Some(Commands::Import { document, map }) => {
+            inspect::import(document, map).map_err(|err| eyre!("{}", err))?;
+            run_editor(Some(map.clone()));
+        }
+        Some(Commands::Edit { map, script, exit }) => {
+            let script = EditScript::read(script).map_err(|err| eyre!("{}", err))?;
+            inspect::edit(map, &script).map_err(|err| eyre!("{}", err))?;
+            if !exit {
+                run_editor(Some(map.clone()));
+            }
+        }
     }
     Ok(())
 }
 
-fn file_drop(mut evr_dnd: EventReader<FileDragAndDrop>) {
+fn run_editor(startup_map: Option<PathBuf>) {
+    App::new()
+        .add_plugins((
+            app_data::plugin,
+            DefaultPlugins,
+            //PhysicsPlugins::default().set(PhysicsInterpolationPlugin::interpolate_all()),
+            PhysicsPlugins::default(),
+            core::plugin,
+            editor::plugin,
+            game::plugin,
+        ))
+        .insert_resource(StartupMap(startup_map))
+        // Only update when there is user input. Should be disabled when in-game
+        //.insert_resource(WinitSettings::desktop_app())
+        .add_systems(PreUpdate, file_drop)
+        .run();
+}
+
+fn file_drop(mut evr_dnd: EventReader<FileDragAndDrop>, mut commands: Commands) {
     for ev in evr_dnd.read() {
         info!("dnd event: {:?}", ev);
         if let FileDragAndDrop::DroppedFile { window, path_buf } = ev {
@@ -61,6 +158,20 @@ fn file_drop(mut evr_dnd: EventReader<FileDragAndDrop>) {
                 "Dropped file with path: {:?}, in window id: {:?}",
                 path_buf, window
             );
+            if path_buf.extension().is_some_and(|ext| ext == MAP_FILE_EXT) {
+                commands.trigger(OpenMap {
+                    path: path_buf.clone(),
+                });
+            } else if path_buf
+                .extension()
+                .is_some_and(|ext| ext == DOCUMENT_FILE_EXT)
+            {
+                // Imported maps are created next to the document.
+                commands.trigger(ImportMap {
+                    document: path_buf.clone(),
+                    path: path_buf.with_extension(MAP_FILE_EXT),
+                });
+            }
         }
     }
 }